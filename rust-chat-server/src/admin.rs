@@ -0,0 +1,199 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::audit::AuditEntry;
+use crate::client_ip;
+use crate::request_id::RequestId;
+use crate::upstream::{self, LlmEndpoint};
+use crate::AppState;
+
+// Admin routes require `Authorization: Bearer $ADMIN_TOKEN`. Without a
+// configured token they are unavailable.
+pub fn authorize(req: &HttpRequest, app_state: &AppState) -> Result<(), HttpResponse> {
+    let Some(expected) = app_state.admin_token.as_deref() else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+    let provided = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if tokens_match(token, expected) => Ok(()),
+        _ => Err(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Admin token required"
+        }))),
+    }
+}
+
+// Compares every byte whatever the first difference, so response times don't
+// reveal how much of a guess was right. Only the length can leak.
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[get("/admin/budget")]
+async fn token_budget(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &app_state) {
+        return Ok(response);
+    }
+    match &app_state.token_budget {
+        Some(budget) => Ok(HttpResponse::Ok().json(budget.snapshot())),
+        None => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "limit": null,
+            "message": "No daily token budget configured"
+        }))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlushQuery {
+    prefix: Option<String>,
+}
+
+// Clears the response cache, or only keys starting with `prefix` (for
+// example `<template>/` after editing that template).
+#[post("/admin/cache/flush")]
+async fn flush_cache(
+    req: HttpRequest,
+    query: web::Query<FlushQuery>,
+    request_id: web::ReqData<RequestId>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &app_state) {
+        return Ok(response);
+    }
+    let Some(cache) = &app_state.response_cache else {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({
+            "evicted": 0,
+            "message": "No response cache configured"
+        })));
+    };
+    let prefix = query.prefix.as_deref().filter(|prefix| !prefix.is_empty());
+    let evicted = cache.flush(prefix);
+    log::info!(target: "audit", "Flushed {} response cache entries (prefix {:?})", evicted, prefix);
+    app_state.audit(AuditEntry {
+        event: "cache.flushed",
+        request_id: Some(request_id.0.clone()),
+        subject: None,
+        client_ip: client_ip::client_ip(&app_state, &req),
+        details: serde_json::json!({
+            "prefix": prefix,
+            "evicted": evicted,
+        }),
+    });
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "evicted": evicted })))
+}
+
+// Builds the primary endpoint from SERVING_ENDPOINT_NAME and DATABRICKS_HOST,
+// taking them from .env when it sets them and from the process
+// environment otherwise.
+fn endpoint_from_config() -> Result<LlmEndpoint, String> {
+    // The non-deprecated loaders never override variables that are already
+    // set, which is exactly what a reload has to do
+    #[allow(deprecated)]
+    let vars = dotenv::dotenv_iter();
+    let file: HashMap<String, String> = match vars {
+        Ok(vars) => vars
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("can't read .env: {}", e))?,
+        Err(dotenv::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(format!("can't read .env: {}", e)),
+    };
+    let setting = |key: &str| {
+        file.get(key)
+            .cloned()
+            .or_else(|| std::env::var(key).ok())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| format!("{} must be set", key))
+    };
+    let host = setting("DATABRICKS_HOST")?;
+    let name = setting("SERVING_ENDPOINT_NAME")?;
+    if !LlmEndpoint::valid_name(&name) {
+        return Err(format!("invalid SERVING_ENDPOINT_NAME {:?}", name));
+    }
+    if host.contains("://") || host.contains('/') || host.contains(char::is_whitespace) {
+        return Err(format!("DATABRICKS_HOST must be a bare host name, got {:?}", host));
+    }
+    let endpoint = LlmEndpoint::new(&host, &name);
+    reqwest::Url::parse(&endpoint.url).map_err(|e| format!("invalid endpoint URL {}: {}", endpoint.url, e))?;
+    Ok(endpoint)
+}
+
+// Swaps the primary endpoint for the one currently configured. Requests
+// already running finish on the endpoint they started with. Nothing changes
+// if the settings are invalid or, with STARTUP_SELFTEST=true, if the new
+// endpoint fails the self-test.
+#[post("/admin/reload")]
+async fn reload(
+    req: HttpRequest,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&req, &app_state) {
+        return Ok(response);
+    }
+    if app_state.regions.is_some() {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "error": "REGION_HOSTS is set; the region pool is not reloaded"
+        })));
+    }
+    let endpoint = match endpoint_from_config() {
+        Ok(endpoint) => endpoint,
+        Err(message) => {
+            log::warn!("Rejected endpoint reload: {}", message);
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": message })));
+        }
+    };
+    if std::env::var("STARTUP_SELFTEST").map(|v| v == "true").unwrap_or(false) {
+        if let Err(e) = upstream::self_test(&client, &endpoint, &app_state.api_key).await {
+            log::warn!("Rejected endpoint reload, self-test against {} failed: {}", endpoint.url, e);
+            return Ok(HttpResponse::BadGateway().json(serde_json::json!({
+                "error": format!("self-test against the new endpoint failed: {}", e)
+            })));
+        }
+    }
+
+    let previous = std::mem::replace(&mut *app_state.llm_endpoint.write().unwrap(), endpoint.clone());
+    log::info!(target: "audit", "Primary endpoint reloaded: {} -> {}", previous.name, endpoint.name);
+    app_state.audit(AuditEntry {
+        event: "endpoint.reloaded",
+        request_id: Some(request_id.0.clone()),
+        subject: None,
+        client_ip: client_ip::client_ip(&app_state, &req),
+        details: serde_json::json!({
+            "previous": previous.name,
+            "endpoint": endpoint.name,
+        }),
+    });
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "endpoint": endpoint.name,
+        "url": endpoint.url,
+        "previous": previous.name,
+        "changed": previous.url != endpoint.url,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tokens_match;
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secret2", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+}
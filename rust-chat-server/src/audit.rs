@@ -0,0 +1,118 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use crate::conversations::now_ms;
+
+// Append-only, hash-chained audit trail. Every line carries the hash of the
+// previous line, so edits or deletions break the chain. Records are written
+// by a dedicated thread in the order they were submitted and flushed to disk
+// before the next one.
+pub struct AuditLog {
+    sender: mpsc::Sender<AuditEntry>,
+    pub record_prompts: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub event: &'static str,
+    pub request_id: Option<String>,
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    #[serde(flatten)]
+    pub details: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct ChainedRecord<'a> {
+    seq: u64,
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    entry: &'a AuditEntry,
+    prev_hash: &'a str,
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    Sha1::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Recovers the sequence number and hash of the last record in an existing log.
+fn chain_tail(path: &Path) -> (u64, String) {
+    let Ok(file) = File::open(path) else {
+        return (0, String::new());
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+        .last()
+        .map(|last| {
+            (
+                last["seq"].as_u64().unwrap_or_default(),
+                last["hash"].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .unwrap_or_default()
+}
+
+impl AuditLog {
+    pub fn from_env() -> std::io::Result<Option<Self>> {
+        let Some(path) = std::env::var("AUDIT_LOG_PATH").ok().filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+        let record_prompts = std::env::var("AUDIT_LOG_PROMPTS")
+            .map(|v| v == "true")
+            .unwrap_or(false);
+
+        let (mut seq, mut prev_hash) = chain_tail(&path);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        log::info!("Audit log enabled at {:?}, resuming after record {}", path, seq);
+
+        let (sender, receiver) = mpsc::channel::<AuditEntry>();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for entry in receiver {
+                    seq += 1;
+                    let record = ChainedRecord {
+                        seq,
+                        timestamp_ms: now_ms(),
+                        entry: &entry,
+                        prev_hash: &prev_hash,
+                    };
+                    let body = serde_json::to_string(&record).unwrap_or_default();
+                    let hash = sha1_hex(body.as_bytes());
+                    // Append the hash as the final field of the same object
+                    let line = format!("{},\"hash\":\"{}\"}}\n", &body[..body.len() - 1], hash);
+                    let written = file
+                        .write_all(line.as_bytes())
+                        .and_then(|_| file.flush())
+                        .and_then(|_| file.sync_data());
+                    if let Err(e) = written {
+                        log::error!("Failed to write audit record {}: {}", seq, e);
+                    }
+                    prev_hash = hash;
+                }
+            })?;
+
+        Ok(Some(Self {
+            sender,
+            record_prompts,
+        }))
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if self.sender.send(entry).is_err() {
+            log::error!("Audit log writer has stopped; record dropped");
+        }
+    }
+}
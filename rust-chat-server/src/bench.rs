@@ -0,0 +1,198 @@
+use actix_web::web::Bytes;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+use crate::upstream::LlmEndpoint;
+use crate::AppState;
+
+// `llm-chat-server bench [--runs N] [--tokens N] [--gap-ms N]`
+//
+// Starts a mock serving endpoint that emits `tokens` tokens `gap-ms` apart,
+// points an in-process server at it and sends the same prompt through
+// /api/chat and /api/chat/stream, then prints time-to-first-token and total
+// time for each.
+
+const PROMPT: &str = "Summarise the benefits of streaming responses.";
+
+#[derive(Debug, Clone, Copy)]
+struct BenchOptions {
+    runs: usize,
+    tokens: usize,
+    gap: Duration,
+}
+
+impl BenchOptions {
+    fn parse(args: &[String]) -> std::io::Result<Self> {
+        let mut options = Self {
+            runs: 5,
+            tokens: 50,
+            gap: Duration::from_millis(40),
+        };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| invalid_input(format!("{} needs a numeric value", flag)))?;
+            match flag.as_str() {
+                "--runs" => options.runs = value.max(1) as usize,
+                "--tokens" => options.tokens = value.max(1) as usize,
+                "--gap-ms" => options.gap = Duration::from_millis(value),
+                _ => return Err(invalid_input(format!("unknown bench option {}", flag))),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+pub async fn run(args: &[String]) -> std::io::Result<()> {
+    let options = BenchOptions::parse(args)?;
+
+    let mock_listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let mock_addr = mock_listener.local_addr()?;
+    let mock = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(options))
+            .route("/invocations", web::post().to(mock_invocations))
+    })
+    .workers(1)
+    .listen(mock_listener)?
+    .run();
+    actix_web::rt::spawn(mock);
+
+    let llm_endpoint = LlmEndpoint {
+        name: "mock".to_string(),
+        url: format!("http://{}/invocations", mock_addr),
+    };
+    let mut app_state = AppState::from_env(llm_endpoint, "bench".to_string(), "localhost")?;
+    // Identical prompts would otherwise be answered from the cache
+    app_state.response_cache = None;
+    let client = reqwest::Client::new();
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let server = crate::serve(web::Data::new(app_state), client.clone(), listener)?;
+    actix_web::rt::spawn(server);
+
+    println!(
+        "Benchmarking {} runs of {} tokens, {} ms apart\n",
+        options.runs,
+        options.tokens,
+        options.gap.as_millis()
+    );
+    let mut chat = Vec::new();
+    let mut stream = Vec::new();
+    for _ in 0..options.runs {
+        chat.push(time_chat(&client, &base_url).await?);
+        stream.push(time_stream(&client, &base_url).await?);
+    }
+
+    println!(
+        "{:<18} {:>10} {:>10} {:>10} {:>10}",
+        "endpoint", "ttft p50", "ttft mean", "total p50", "total mean"
+    );
+    for (name, samples) in [("/api/chat", &chat), ("/api/chat/stream", &stream)] {
+        let ttft: Vec<Duration> = samples.iter().map(|s| s.first_token).collect();
+        let total: Vec<Duration> = samples.iter().map(|s| s.total).collect();
+        println!(
+            "{:<18} {:>7} ms {:>7} ms {:>7} ms {:>7} ms",
+            name,
+            median(&ttft).as_millis(),
+            mean(&ttft).as_millis(),
+            median(&total).as_millis(),
+            mean(&total).as_millis()
+        );
+    }
+    Ok(())
+}
+
+struct Sample {
+    first_token: Duration,
+    total: Duration,
+}
+
+async fn time_chat(client: &reqwest::Client, base_url: &str) -> std::io::Result<Sample> {
+    let started = Instant::now();
+    let response = client
+        .post(format!("{}/api/chat", base_url))
+        .json(&serde_json::json!({ "message": PROMPT }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(std::io::Error::other)?;
+    response.bytes().await.map_err(std::io::Error::other)?;
+    // Nothing is shown until the whole reply arrives
+    let total = started.elapsed();
+    Ok(Sample {
+        first_token: total,
+        total,
+    })
+}
+
+async fn time_stream(client: &reqwest::Client, base_url: &str) -> std::io::Result<Sample> {
+    let started = Instant::now();
+    let mut response = client
+        .post(format!("{}/api/chat/stream", base_url))
+        .json(&serde_json::json!({ "message": PROMPT }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(std::io::Error::other)?;
+    let mut first_token = None;
+    while let Some(chunk) = response.chunk().await.map_err(std::io::Error::other)? {
+        if first_token.is_none() && chunk.windows(7).any(|w| w == b"\"delta\"") {
+            first_token = Some(started.elapsed());
+        }
+    }
+    let total = started.elapsed();
+    Ok(Sample {
+        first_token: first_token.unwrap_or(total),
+        total,
+    })
+}
+
+fn median(samples: &[Duration]) -> Duration {
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    sorted[sorted.len() / 2]
+}
+
+fn mean(samples: &[Duration]) -> Duration {
+    samples.iter().sum::<Duration>() / samples.len() as u32
+}
+
+// Mimics a serving endpoint: token `i` is produced `gap * i` after the
+// request, whether or not the reply is streamed.
+async fn mock_invocations(
+    body: web::Json<serde_json::Value>,
+    options: web::Data<BenchOptions>,
+) -> HttpResponse {
+    let options = **options;
+    if body["stream"] != serde_json::Value::Bool(true) {
+        tokio::time::sleep(options.gap * options.tokens as u32).await;
+        let content = (0..options.tokens).map(|i| format!("tok{} ", i)).collect::<String>();
+        return HttpResponse::Ok().json(serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": content } }],
+            "usage": { "completion_tokens": options.tokens }
+        }));
+    }
+
+    let events = futures::stream::unfold(0, move |i| async move {
+        let event = if i < options.tokens {
+            tokio::time::sleep(options.gap).await;
+            let chunk = serde_json::json!({ "choices": [{ "delta": { "content": format!("tok{} ", i) } }] });
+            format!("data: {}\n\n", chunk)
+        } else if i == options.tokens {
+            let chunk = serde_json::json!({ "choices": [{ "delta": {}, "finish_reason": "stop" }] });
+            format!("data: {}\n\ndata: [DONE]\n\n", chunk)
+        } else {
+            return None;
+        };
+        Some((Ok::<_, actix_web::Error>(Bytes::from(event)), i + 1))
+    });
+    HttpResponse::Ok().content_type("text/event-stream").streaming(events)
+}
@@ -0,0 +1,155 @@
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+use crate::AppState;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+// Shortest gap between writes of the state file; updates in between go out
+// together in the next write.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct BudgetState {
+    // Days since the Unix epoch, i.e. the current UTC day
+    day: u64,
+    used: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BudgetSnapshot {
+    pub limit: u64,
+    pub used: u64,
+    pub remaining: u64,
+    pub resets_in_secs: u64,
+}
+
+// Global cap on tokens spent per UTC day. The running total is written to
+// disk by a background task shortly after each update, and again on
+// shutdown, so a restart doesn't hand out a fresh budget.
+pub struct TokenBudget {
+    limit: u64,
+    path: PathBuf,
+    state: Mutex<BudgetState>,
+    changed: Notify,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl TokenBudget {
+    pub fn from_env() -> Option<Self> {
+        let limit = std::env::var("DAILY_TOKEN_BUDGET")
+            .ok()?
+            .parse::<u64>()
+            .expect("DAILY_TOKEN_BUDGET must be a positive integer");
+        let path = std::env::var("TOKEN_BUDGET_STATE_PATH")
+            .unwrap_or_else(|_| "token_budget.json".to_string())
+            .into();
+        Some(Self::new(limit, path))
+    }
+
+    // Picks up today's total from `path` when it's there.
+    pub fn new(limit: u64, path: PathBuf) -> Self {
+        let state = match std::fs::read_to_string(&path) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                log::warn!("Ignoring unreadable token budget state {:?}: {}", path, e);
+                BudgetState::default()
+            }),
+            Err(_) => BudgetState::default(),
+        };
+        log::info!("Daily token budget set to {} (state in {:?})", limit, path);
+        Self {
+            limit,
+            path,
+            state: Mutex::new(state),
+            changed: Notify::new(),
+        }
+    }
+
+    // Rolls the counter over when the UTC day has changed.
+    fn roll_over(state: &mut BudgetState) {
+        let today = now_secs() / SECS_PER_DAY;
+        if state.day != today {
+            *state = BudgetState { day: today, used: 0 };
+        }
+    }
+
+    pub fn snapshot(&self) -> BudgetSnapshot {
+        let mut state = self.state.lock().unwrap();
+        Self::roll_over(&mut state);
+        BudgetSnapshot {
+            limit: self.limit,
+            used: state.used,
+            remaining: self.limit.saturating_sub(state.used),
+            resets_in_secs: ((state.day + 1) * SECS_PER_DAY).saturating_sub(now_secs()),
+        }
+    }
+
+    pub fn record(&self, tokens: u64) {
+        let mut state = self.state.lock().unwrap();
+        Self::roll_over(&mut state);
+        state.used = state.used.saturating_add(tokens);
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    fn serialized(&self) -> String {
+        serde_json::to_string(&*self.state.lock().unwrap()).unwrap_or_default()
+    }
+
+    // Writes the state file after each change, at most once per
+    // PERSIST_INTERVAL. Only this task writes it during serving, so the file
+    // never goes backwards.
+    pub async fn persist_changes(&self) {
+        loop {
+            self.changed.notified().await;
+            if let Err(e) = tokio::fs::write(&self.path, self.serialized()).await {
+                log::error!("Failed to persist token budget to {:?}: {}", self.path, e);
+            }
+            tokio::time::sleep(PERSIST_INTERVAL).await;
+        }
+    }
+
+    // For shutdown, once the background writer has stopped.
+    pub fn persist_now(&self) {
+        if let Err(e) = std::fs::write(&self.path, self.serialized()) {
+            log::error!("Failed to persist token budget to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+pub async fn run_persistence(app_state: web::Data<AppState>) {
+    if let Some(budget) = &app_state.token_budget {
+        budget.persist_changes().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBudget;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn spend_is_written_in_the_background() {
+        let path = std::env::temp_dir().join(format!("budget-persist-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let budget = TokenBudget::new(100, path.clone());
+        budget.record(3);
+        budget.record(4);
+        assert!(!path.exists());
+        let persisted = tokio::time::timeout(Duration::from_millis(200), budget.persist_changes()).await;
+        assert!(persisted.is_err());
+        let state: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(state["used"], 7);
+        assert_eq!(TokenBudget::new(100, path.clone()).snapshot().used, 7);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::audit;
+
+// In-memory cache of chat replies keyed by the serving endpoint and the exact
+// upstream payload, so canary and primary replies are never mixed up.
+// Entries expire after RESPONSE_CACHE_TTL_SECS; once RESPONSE_CACHE_MAX_ENTRIES
+// is reached the oldest entry is evicted. Keys of templated requests start
+// with `<template>/`, so a template's replies can be flushed together.
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    state: Mutex<CacheState>,
+}
+
+// A stored reply and the serving endpoint that produced it.
+#[derive(Debug, Clone)]
+pub struct CachedReply {
+    pub content: String,
+    pub endpoint: String,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, (Instant, CachedReply)>,
+    // Keys in insertion order, for eviction
+    order: VecDeque<String>,
+}
+
+impl ResponseCache {
+    pub fn from_env() -> Option<Self> {
+        let ttl_secs: u64 = std::env::var("RESPONSE_CACHE_TTL_SECS")
+            .ok()
+            .map(|v| v.parse().expect("RESPONSE_CACHE_TTL_SECS must be a whole number of seconds"))?;
+        let max_entries = std::env::var("RESPONSE_CACHE_MAX_ENTRIES")
+            .ok()
+            .map(|v| v.parse().expect("RESPONSE_CACHE_MAX_ENTRIES must be a positive integer"))
+            .unwrap_or(1000);
+        log::info!("Caching chat replies for {}s (up to {} entries)", ttl_secs, max_entries);
+        Some(Self {
+            ttl: Duration::from_secs(ttl_secs),
+            max_entries,
+            state: Mutex::new(CacheState::default()),
+        })
+    }
+
+    pub fn key(template: Option<&str>, endpoint_name: &str, payload: &serde_json::Value) -> String {
+        let hash = audit::sha1_hex(format!("{}\n{}", endpoint_name, payload).as_bytes());
+        match template {
+            Some(template) => format!("{}/{}", template, hash),
+            None => hash,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedReply> {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get(key) {
+            Some((stored_at, reply)) if stored_at.elapsed() < self.ttl => Some(reply.clone()),
+            Some(_) => {
+                state.entries.remove(key);
+                state.order.retain(|k| k != key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Drops every entry, or those whose key starts with `prefix`, returning
+    // how many were removed.
+    pub fn flush(&self, prefix: Option<&str>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let before = state.entries.len();
+        match prefix {
+            Some(prefix) => {
+                state.entries.retain(|key, _| !key.starts_with(prefix));
+                state.order.retain(|key| !key.starts_with(prefix));
+            }
+            None => *state = CacheState::default(),
+        }
+        before - state.entries.len()
+    }
+
+    pub fn insert(&self, key: String, reply: CachedReply) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.entries.insert(key.clone(), (Instant::now(), reply)).is_none() {
+            state.order.push_back(key);
+        }
+        while state.entries.len() > self.max_entries {
+            let Some(oldest) = state.order.pop_front() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResponseCache;
+
+    #[test]
+    fn keys_are_split_by_endpoint() {
+        let payload = serde_json::json!({ "messages": [{ "role": "user", "content": "Hi" }] });
+        let primary = ResponseCache::key(None, "chat", &payload);
+        assert_ne!(primary, ResponseCache::key(None, "chat-canary", &payload));
+        assert_eq!(primary, ResponseCache::key(None, "chat", &payload));
+        assert!(ResponseCache::key(Some("faq"), "chat", &payload).starts_with("faq/"));
+    }
+}
@@ -0,0 +1,133 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::AppState;
+
+// Streaming generations that POST /api/chat/{request_id}/cancel can stop,
+// keyed by request id, for UIs whose stop button can't drop the original
+// connection. Client-chosen request ids may repeat; the newest generation
+// under an id is the one cancelled. Request ids can be chosen or guessed,
+// so a cancel must also present the generation's X-Cancel-Token, which only
+// its own response carries.
+#[derive(Default)]
+pub struct Cancellations {
+    active: Mutex<HashMap<String, Active>>,
+    next_serial: AtomicU64,
+}
+
+struct Active {
+    serial: u64,
+    token: String,
+    notify: Arc<Notify>,
+}
+
+pub const TOKEN_HEADER: &str = "X-Cancel-Token";
+
+// Held for the life of a generation; dropping it unregisters the id.
+pub struct Registration {
+    app_state: web::Data<AppState>,
+    request_id: String,
+    serial: u64,
+    notify: Arc<Notify>,
+    // For the client that started the generation only
+    pub token: String,
+}
+
+impl Cancellations {
+    pub fn register(app_state: &web::Data<AppState>, request_id: &str) -> Registration {
+        let cancellations = &app_state.cancellations;
+        let serial = cancellations.next_serial.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+        let token = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let active = Active {
+            serial,
+            token: token.clone(),
+            notify: notify.clone(),
+        };
+        cancellations.active.lock().unwrap().insert(request_id.to_string(), active);
+        Registration {
+            app_state: app_state.clone(),
+            request_id: request_id.to_string(),
+            serial,
+            notify,
+            token,
+        }
+    }
+
+    // False when nothing is running under that id with that token.
+    fn cancel(&self, request_id: &str, token: &str) -> bool {
+        match self.active.lock().unwrap().get(request_id) {
+            Some(active) if active.token == token => {
+                // Stored as a permit if the generation isn't waiting right now
+                active.notify.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Registration {
+    // Resolves once the generation has been cancelled.
+    pub async fn cancelled(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut active = self.app_state.cancellations.active.lock().unwrap();
+        if active.get(&self.request_id).is_some_and(|active| active.serial == self.serial) {
+            active.remove(&self.request_id);
+        }
+    }
+}
+
+#[post("/api/chat/{request_id}/cancel")]
+async fn cancel_generation(
+    req: HttpRequest,
+    path: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let request_id = path.into_inner();
+    let Some(token) = req.headers().get(TOKEN_HEADER).and_then(|value| value.to_str().ok()) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "X-Cancel-Token header required"
+        }));
+    };
+    // A wrong token looks like no generation, so ids can't be probed
+    if app_state.cancellations.cancel(&request_id, token) {
+        log::info!("Cancelling generation for request {}", request_id);
+        HttpResponse::Accepted().json(serde_json::json!({ "request_id": request_id }))
+    } else {
+        HttpResponse::NotFound().json(serde_json::json!({
+            "error": "No generation in progress for that request id and token"
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Active, Cancellations};
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    #[test]
+    fn cancelling_needs_the_generations_token() {
+        let cancellations = Cancellations::default();
+        let active = Active {
+            serial: 0,
+            token: "right".to_string(),
+            notify: Arc::new(Notify::new()),
+        };
+        cancellations.active.lock().unwrap().insert("req-1".to_string(), active);
+        assert!(!cancellations.cancel("req-1", "wrong"));
+        assert!(!cancellations.cancel("req-1", ""));
+        assert!(!cancellations.cancel("req-2", "right"));
+        assert!(cancellations.cancel("req-1", "right"));
+    }
+}
@@ -0,0 +1,1438 @@
+use actix_web::http::header::{HeaderValue, VARY};
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::audit::{self, AuditEntry};
+use crate::cache::{CachedReply, ResponseCache};
+use crate::client_ip;
+use crate::conversations::StaleConversation;
+use crate::debug;
+use crate::events::CompletionEvent;
+use crate::idempotency::{self, Begin, StoredResponse};
+use crate::injection::InjectionPolicy;
+use crate::language;
+use crate::limiter::{OwnedPermit, Priority};
+use crate::request_id::RequestId;
+use crate::sanitize;
+use crate::schema;
+use crate::stream;
+use crate::trace::TraceHeaders;
+use crate::turns::ConversationTurns;
+use crate::upstream::{self, Completion, LlmEndpoint, UpstreamError};
+use crate::usage::Usage;
+use crate::AppState;
+
+// Unknown roles fail deserialization, which the JSON error handler turns
+// into a 400 naming the bad value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub message: String,
+    // Earlier turns, oldest first, sent ahead of the message
+    #[serde(default)]
+    pub history: Vec<ChatMessage>,
+    // When set, the turn is recorded in the conversation store
+    pub conversation_id: Option<String>,
+    // Name of a server-side prompt template to render the message into
+    pub template: Option<String>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    // Start of the assistant reply; sent as a trailing assistant message and
+    // prepended to the returned content
+    pub prefill: Option<String>,
+    // Forwarded as the endpoint's `seed`; only reproducible where the
+    // serving backend supports seeded sampling
+    pub seed: Option<u64>,
+    // JSON Schema the reply must satisfy; also sent as response_format
+    pub schema: Option<serde_json::Value>,
+    // Extra top-level fields for the upstream payload, for backend options
+    // not modelled here. Must be an object; `messages` and `stream` are
+    // managed by the server and skipped.
+    pub extra: Option<serde_json::Value>,
+    // Id of the newest message the client has of this conversation (0 for a
+    // new one). The turn is refused with 409 if others were added since.
+    pub last_message_id: Option<u64>,
+    // Serving endpoint to use instead of the configured ones, as `name` or
+    // `host/name`; only accepted with ALLOW_ENDPOINT_OVERRIDE=true
+    pub endpoint: Option<String>,
+    // Lane for the concurrency limiter: high, normal (default) or low
+    #[serde(default)]
+    pub priority: Priority,
+    // Adds the upstream exchange as `_debug`; only honoured with
+    // DEBUG_ENDPOINT_ENABLED=true
+    pub debug: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatResponse {
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversation_id: Option<String>,
+    // Id of the stored assistant message, for feedback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<u64>,
+    // As reported upstream, or `truncated_by_server` past MAX_RESPONSE_CHARS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+    // Serving endpoint that produced the reply, stored with cached ones; not
+    // known for fallback replies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(rename = "_debug", skip_serializing_if = "Option::is_none")]
+    debug: Option<serde_json::Value>,
+}
+
+// Appended where MAX_RESPONSE_CHARS cut a reply short
+pub const TRUNCATION_MARKER: &str = "…";
+pub const TRUNCATED_BY_SERVER: &str = "truncated_by_server";
+
+// Cuts `content` to `max_chars` characters plus the marker; false if it
+// already fit. Some backends ignore max_tokens, so this is enforced here.
+pub fn truncate_reply(content: &mut String, max_chars: usize) -> bool {
+    let Some((cut, _)) = content.char_indices().nth(max_chars) else {
+        return false;
+    };
+    content.truncate(cut);
+    content.push_str(TRUNCATION_MARKER);
+    true
+}
+
+// MAX_HISTORY_MESSAGES caps the history a request may carry; with
+// TRIM_HISTORY=true the oldest excess messages are dropped instead of
+// rejecting the request.
+pub struct HistoryLimit {
+    max_messages: usize,
+    trim: bool,
+}
+
+impl HistoryLimit {
+    pub fn from_env() -> Self {
+        Self {
+            max_messages: env::var("MAX_HISTORY_MESSAGES")
+                .ok()
+                .map(|v| v.parse().expect("MAX_HISTORY_MESSAGES must be a non-negative integer"))
+                .unwrap_or(50),
+            trim: env::var("TRIM_HISTORY").map(|v| v == "true").unwrap_or(false),
+        }
+    }
+}
+
+// DEFAULT_TEMPERATURE and DEFAULT_MAX_TOKENS go into every upstream payload
+// whose request doesn't set them through `extra`. Unset, the key is left
+// out and the endpoint's own default applies.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationDefaults {
+    temperature: Option<f64>,
+    max_tokens: Option<u64>,
+}
+
+impl GenerationDefaults {
+    pub fn from_env() -> Self {
+        let defaults = Self {
+            temperature: env::var("DEFAULT_TEMPERATURE").ok().filter(|v| !v.is_empty()).map(|v| {
+                match v.parse::<f64>() {
+                    Ok(t) if (0.0..=2.0).contains(&t) => t,
+                    _ => panic!("DEFAULT_TEMPERATURE must be a number between 0 and 2"),
+                }
+            }),
+            max_tokens: env::var("DEFAULT_MAX_TOKENS").ok().filter(|v| !v.is_empty()).map(|v| {
+                match v.parse::<u64>() {
+                    Ok(n) if n > 0 => n,
+                    _ => panic!("DEFAULT_MAX_TOKENS must be a positive integer"),
+                }
+            }),
+        };
+        if defaults.temperature.is_some() || defaults.max_tokens.is_some() {
+            log::info!("Default generation parameters: {:?}", defaults);
+        }
+        defaults
+    }
+}
+
+// With ENFORCE_SYSTEM_PROMPT=true, SYSTEM_PROMPT is added to every request.
+// Client system messages are kept; by default they follow the enforced one,
+// SYSTEM_PROMPT_ORDER=client_first puts the enforced prompt after them.
+pub struct SystemPrompt {
+    content: String,
+    client_first: bool,
+}
+
+impl SystemPrompt {
+    pub fn from_env() -> Option<Self> {
+        if env::var("ENFORCE_SYSTEM_PROMPT").map(|v| v != "true").unwrap_or(true) {
+            return None;
+        }
+        let content = env::var("SYSTEM_PROMPT")
+            .ok()
+            .filter(|c| !c.trim().is_empty())
+            .expect("SYSTEM_PROMPT must be set when ENFORCE_SYSTEM_PROMPT=true");
+        let client_first = match env::var("SYSTEM_PROMPT_ORDER").as_deref() {
+            Err(_) | Ok("enforced_first") => false,
+            Ok("client_first") => true,
+            Ok(_) => panic!("SYSTEM_PROMPT_ORDER must be enforced_first or client_first"),
+        };
+        Some(Self { content, client_first })
+    }
+
+    fn apply(&self, messages: &mut Vec<ChatMessage>) {
+        let position = if self.client_first {
+            messages.iter().take_while(|m| m.role == Role::System).count()
+        } else {
+            0
+        };
+        messages.insert(
+            position,
+            ChatMessage {
+                role: Role::System,
+                content: self.content.clone(),
+            },
+        );
+    }
+}
+
+// A validated chat request, ready to be sent upstream.
+pub struct PreparedChat {
+    pub req: ChatRequest,
+    // Rendered user turn (the message, or the template it was rendered into)
+    pub user_content: String,
+    pub messages: Vec<ChatMessage>,
+    pub history_trimmed: usize,
+    pub endpoint_override: Option<LlmEndpoint>,
+    // ISO 639-1 code of the message (DETECT_LANGUAGE or LANG_ROUTING)
+    pub language: Option<&'static str>,
+    // Prompt-injection score, when INJECTION_POLICY flagged the message
+    pub injection_score: Option<usize>,
+    pub generation_defaults: GenerationDefaults,
+}
+
+impl PreparedChat {
+    // Upstream request body. The response cache keys on it, so optional
+    // sampling parameters like `seed` only appear when set.
+    pub fn payload(&self) -> serde_json::Value {
+        let mut payload = serde_json::json!({
+            "messages": self.messages
+        });
+        // Overridden by the same keys in `extra`
+        let defaults = self.generation_defaults;
+        if let Some(temperature) = defaults.temperature {
+            payload["temperature"] = temperature.into();
+        }
+        if let Some(max_tokens) = defaults.max_tokens {
+            payload["max_tokens"] = max_tokens.into();
+        }
+        if let Some(serde_json::Value::Object(extra)) = &self.req.extra {
+            for (key, value) in extra {
+                if key != "messages" && key != "stream" {
+                    payload[key] = value.clone();
+                }
+            }
+        }
+        if let Some(seed) = self.req.seed {
+            payload["seed"] = seed.into();
+        }
+        if let Some(schema) = &self.req.schema {
+            payload["response_format"] = serde_json::json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema }
+            });
+        }
+        payload
+    }
+}
+
+// Collapses runs of identical messages (same role and content), as left by
+// clients that resend, returning how many were dropped. Runs after
+// sanitizing so messages differing only in normalization count as equal.
+fn dedupe_history(history: &mut Vec<ChatMessage>) -> usize {
+    let before = history.len();
+    history.dedup_by(|later, earlier| later.role == earlier.role && later.content == earlier.content);
+    before - history.len()
+}
+
+// Shared front half of the chat endpoints: cleans the input, enforces the
+// history and budget limits and renders any template. Err holds the
+// response to return as-is.
+pub fn prepare_chat(
+    mut req: ChatRequest,
+    app_state: &AppState,
+) -> Result<PreparedChat, HttpResponse> {
+    let normalize = app_state.normalize_unicode;
+    req.message = sanitize::prepare(&req.message, normalize);
+    for value in req.variables.values_mut() {
+        *value = sanitize::prepare(value, normalize);
+    }
+    for message in &mut req.history {
+        message.content = sanitize::prepare(&message.content, normalize);
+    }
+    let duplicates = dedupe_history(&mut req.history);
+    if duplicates > 0 {
+        log::info!("Dropped {} repeated history messages", duplicates);
+    }
+    req.prefill = req
+        .prefill
+        .map(|prefill| sanitize::prepare(&prefill, normalize))
+        .filter(|prefill| !prefill.is_empty());
+    let language = app_state
+        .language_detection
+        .as_ref()
+        .and_then(|_| language::detect(&req.message));
+    match language {
+        Some(language) => log::info!("Received message ({}): {}", language, req.message),
+        None => log::info!("Received message: {}", req.message),
+    }
+    let injection_score = app_state
+        .injection_guard
+        .as_ref()
+        .and_then(|guard| Some((guard.policy, guard.check(&req.message)?)));
+    if let Some((policy, score)) = injection_score {
+        log::warn!("Message looks like a prompt injection (score {}, {:?})", score, policy);
+        if policy == InjectionPolicy::Block {
+            return Err(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                "error": "The message was rejected as a likely prompt injection"
+            })));
+        }
+    }
+    if req.extra.as_ref().is_some_and(|extra| !extra.is_object()) {
+        return Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "extra must be a JSON object"
+        })));
+    }
+    // Only one reply is returned, and the prefill only starts that one
+    let choices = req.extra.as_ref().and_then(|extra| extra.get("n")).and_then(serde_json::Value::as_f64);
+    if req.prefill.is_some() && choices.is_some_and(|n| n > 1.0) {
+        return Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "prefill can't be combined with n greater than 1"
+        })));
+    }
+    if req.schema.as_ref().is_some_and(|s| !schema::is_schema(s)) {
+        return Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "schema must be a JSON Schema object"
+        })));
+    }
+    let endpoint_override = match (&req.endpoint, &app_state.endpoint_override) {
+        (None, _) => None,
+        (Some(_), None) => {
+            return Err(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "endpoint overrides are disabled on this server"
+            })));
+        }
+        (Some(requested), Some(overrides)) => match overrides.resolve(requested) {
+            Ok(endpoint) => Some(endpoint),
+            Err(message) => {
+                return Err(HttpResponse::BadRequest().json(serde_json::json!({ "error": message })));
+            }
+        },
+    };
+    // Checked again when the turn is stored; this just avoids a wasted call
+    if let (Some(conversation_id), Some(expected)) = (&req.conversation_id, req.last_message_id) {
+        let latest_message_id = app_state.conversations.latest_message_id(conversation_id);
+        if latest_message_id != expected {
+            return Err(stale_conversation(StaleConversation { latest_message_id }, None));
+        }
+    }
+
+    let mut history_trimmed = 0;
+    if req.history.len() > app_state.history_limit.max_messages {
+        let excess = req.history.len() - app_state.history_limit.max_messages;
+        if !app_state.history_limit.trim {
+            return Err(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!(
+                    "history has {} messages; at most {} are accepted",
+                    req.history.len(),
+                    app_state.history_limit.max_messages
+                )
+            })));
+        }
+        req.history.drain(..excess);
+        history_trimmed = excess;
+        // Don't let the cut leave an assistant reply at the front
+        while req.history.first().is_some_and(|m| m.role == Role::Assistant) {
+            req.history.remove(0);
+            history_trimmed += 1;
+        }
+        log::warn!("Trimmed {} oldest history messages", history_trimmed);
+    }
+
+    let first_turn = req.history.iter().find(|m| m.role != Role::System);
+    if first_turn.is_some_and(|m| m.role == Role::Assistant) {
+        return Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "history must not start with an assistant message"
+        })));
+    }
+
+    check_budget(app_state)?;
+    let user_content = render_user_turn(&req, app_state)?;
+    let messages = upstream_messages(&req, &user_content, app_state);
+    Ok(PreparedChat {
+        req,
+        user_content,
+        messages,
+        history_trimmed,
+        endpoint_override,
+        language,
+        injection_score: injection_score.map(|(_, score)| score),
+        generation_defaults: app_state.generation_defaults,
+    })
+}
+
+// prepare_chat for a turn rebuilt from a stored conversation, which the
+// server accepted when it was stored: the history limit, injection guard and
+// last_message_id checks are not run again, and nothing is re-sanitized.
+fn prepare_stored_turn(req: ChatRequest, app_state: &AppState) -> Result<PreparedChat, HttpResponse> {
+    check_budget(app_state)?;
+    let user_content = render_user_turn(&req, app_state)?;
+    let messages = upstream_messages(&req, &user_content, app_state);
+    let language = app_state
+        .language_detection
+        .as_ref()
+        .and_then(|_| language::detect(&req.message));
+    Ok(PreparedChat {
+        req,
+        user_content,
+        messages,
+        history_trimmed: 0,
+        endpoint_override: None,
+        language,
+        injection_score: None,
+        generation_defaults: app_state.generation_defaults,
+    })
+}
+
+fn check_budget(app_state: &AppState) -> Result<(), HttpResponse> {
+    let Some(budget) = &app_state.token_budget else {
+        return Ok(());
+    };
+    let snapshot = budget.snapshot();
+    if snapshot.remaining == 0 {
+        log::warn!("Daily token budget of {} exhausted, rejecting chat request", snapshot.limit);
+        return Err(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", snapshot.resets_in_secs.to_string()))
+            .json(serde_json::json!({
+                "error": "Daily token budget exhausted; try again after midnight UTC"
+            })));
+    }
+    Ok(())
+}
+
+// The message, or the template it names rendered around it.
+fn render_user_turn(req: &ChatRequest, app_state: &AppState) -> Result<String, HttpResponse> {
+    match &req.template {
+        Some(name) => app_state.templates.render(name, &req.message, &req.variables).map_err(|e| {
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }),
+        None => Ok(req.message.clone()),
+    }
+}
+
+// History, system prompt, the user turn and any prefill, in upstream order.
+fn upstream_messages(req: &ChatRequest, user_content: &str, app_state: &AppState) -> Vec<ChatMessage> {
+    let mut messages = req.history.clone();
+    if let Some(system_prompt) = &app_state.system_prompt {
+        system_prompt.apply(&mut messages);
+    }
+    messages.push(ChatMessage {
+        role: Role::User,
+        content: user_content.to_string(),
+    });
+    if let Some(prefill) = &req.prefill {
+        messages.push(ChatMessage {
+            role: Role::Assistant,
+            content: prefill.clone(),
+        });
+    }
+    messages
+}
+
+// Picks the serving endpoint for a request and counts it.
+// Turns of one conversation hash to the same canary decision and region,
+// so the model doesn't change mid-conversation. A pinned region that is
+// unhealthy is skipped for the fastest healthy one, or the next one in the
+// weighted rotation.
+// The primary is a copy, so a reload doesn't move a request mid-flight.
+// A request's own endpoint override wins over all of it.
+pub fn select_endpoint<'a>(
+    app_state: &'a AppState,
+    prepared: &PreparedChat,
+) -> (Cow<'a, LlmEndpoint>, &'static str) {
+    if let Some(endpoint) = &prepared.endpoint_override {
+        app_state.metrics.llm_requests.inc(&[&endpoint.name, "override"]);
+        return (Cow::Owned(endpoint.clone()), "override");
+    }
+    let routed = app_state
+        .language_detection
+        .as_ref()
+        .zip(prepared.language)
+        .and_then(|(detection, language)| detection.route(language));
+    if let Some(endpoint) = routed {
+        app_state.metrics.llm_requests.inc(&[&endpoint.name, "language"]);
+        return (Cow::Borrowed(endpoint), "language");
+    }
+    let hash = prepared.req.conversation_id.as_deref().map(conversation_hash);
+    let (endpoint, route) = match &app_state.canary {
+        Some(canary) if canary.selected_for(hash) => (Cow::Borrowed(&canary.endpoint), "canary"),
+        _ => match (&app_state.regions, hash) {
+            (Some(regions), Some(hash)) => (Cow::Borrowed(regions.sticky(hash)), "primary"),
+            (Some(regions), None) => (Cow::Borrowed(regions.pick()), "primary"),
+            (None, _) => (Cow::Owned(app_state.primary_endpoint()), "primary"),
+        },
+    };
+    app_state.metrics.llm_requests.inc(&[&endpoint.name, route]);
+    (endpoint, route)
+}
+
+// Stable across restarts and replicas, unlike std's hasher.
+fn conversation_hash(conversation_id: &str) -> u64 {
+    let digest = Sha1::digest(conversation_id.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// One upstream call, with retries, inside the concurrency limit.
+async fn call_llm(
+    app_state: &AppState,
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    payload: &serde_json::Value,
+    trace: &TraceHeaders,
+    priority: Priority,
+) -> Result<Completion, UpstreamError> {
+    app_state.cooldowns.check(endpoint)?;
+    let _permit = match &app_state.limiter {
+        Some(limiter) => Some(limiter.acquire(priority).await.map_err(UpstreamError::Shed)?),
+        None => None,
+    };
+    let started = Instant::now();
+    let result = upstream::complete_with_retries(
+        client,
+        endpoint,
+        &app_state.api_key,
+        payload,
+        Some(trace),
+        app_state.upstream_transport,
+        &app_state.retry_policy,
+        |reason| {
+            app_state.metrics.llm_retries.inc(&[reason]);
+            if reason == "429" {
+                rate_limited(app_state, endpoint);
+            }
+        },
+    )
+    .await;
+    app_state
+        .metrics
+        .llm_request_duration
+        .observe(&[&endpoint.name], started.elapsed().as_secs_f64());
+    if let Err(e) = &result {
+        note_rate_limit(app_state, endpoint, e);
+    }
+    result
+}
+
+// `call_llm` for the streamed routes: the permit is held by the caller
+// until the stream has been relayed.
+pub async fn open_llm_stream(
+    app_state: &AppState,
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    payload: &serde_json::Value,
+    trace: &TraceHeaders,
+    priority: Priority,
+) -> Result<(reqwest::Response, Option<OwnedPermit>), UpstreamError> {
+    app_state.cooldowns.check(endpoint)?;
+    let permit = match &app_state.limiter {
+        Some(limiter) => Some(limiter.acquire_owned(priority).await.map_err(UpstreamError::Shed)?),
+        None => None,
+    };
+    let result = upstream::open_stream_with_retries(
+        client,
+        endpoint,
+        &app_state.api_key,
+        payload,
+        Some(trace),
+        &app_state.retry_policy,
+        |reason| {
+            app_state.metrics.llm_retries.inc(&[reason]);
+            if reason == "429" {
+                rate_limited(app_state, endpoint);
+            }
+        },
+    )
+    .await;
+    if let Err(e) = &result {
+        note_rate_limit(app_state, endpoint, e);
+    }
+    result.map(|response| (response, permit))
+}
+
+fn rate_limited(app_state: &AppState, endpoint: &LlmEndpoint) {
+    app_state.metrics.llm_rate_limited.inc(&[&endpoint.name]);
+    if let Some(limiter) = &app_state.limiter {
+        limiter.back_off();
+    }
+}
+
+// X-Prompt-Injection-Score, set for flagged messages under warn-header.
+pub fn injection_header(app_state: &AppState, score: Option<usize>) -> Option<(&'static str, String)> {
+    let guard = app_state.injection_guard.as_ref()?;
+    let score = score.filter(|_| guard.policy == InjectionPolicy::WarnHeader)?;
+    Some(("X-Prompt-Injection-Score", score.to_string()))
+}
+
+// Charges a reply's tokens to the budget and its cost to the counter,
+// returning the cost.
+pub fn record_usage(app_state: &AppState, usage: Option<Usage>) -> Option<f64> {
+    let usage = usage?;
+    if let Some(budget) = &app_state.token_budget {
+        budget.record(usage.tokens());
+    }
+    let cost = app_state.pricing.cost_usd(&usage);
+    app_state.metrics.cost_usd.add(cost);
+    log::info!(
+        "Request cost ${:.6} ({} prompt + {} completion tokens)",
+        cost,
+        usage.prompt_tokens,
+        usage.completion_tokens
+    );
+    Some(cost)
+}
+
+// Counts a failed upstream call once in each error family.
+pub fn count_upstream_error(app_state: &AppState, endpoint_name: &str, error: &UpstreamError) {
+    app_state.metrics.llm_errors.inc(&[endpoint_name, error.kind()]);
+    app_state.metrics.llm_request_errors.inc(&[error.category()]);
+}
+
+// A final 429 backs off the concurrency limit and, with Retry-After, pauses
+// calls to the endpoint for that long.
+pub fn note_rate_limit(app_state: &AppState, endpoint: &LlmEndpoint, error: &UpstreamError) {
+    if !matches!(error, UpstreamError::Status(reqwest::StatusCode::TOO_MANY_REQUESTS, ..)) {
+        return;
+    }
+    rate_limited(app_state, endpoint);
+    if let Some(delay) = error.retry_after() {
+        app_state.cooldowns.start(endpoint, delay);
+    }
+}
+
+pub fn audit_request(
+    app_state: &AppState,
+    request_id: &RequestId,
+    client_ip: Option<IpAddr>,
+    endpoint: &LlmEndpoint,
+    prepared: &PreparedChat,
+) {
+    let Some(audit_log) = &app_state.audit_log else {
+        return;
+    };
+    let mut details = serde_json::json!({
+        "model": endpoint.name,
+        "conversation_id": prepared.req.conversation_id,
+        "prompt_sha1": audit::sha1_hex(prepared.user_content.as_bytes()),
+    });
+    if let Some(language) = prepared.language {
+        details["language"] = language.into();
+    }
+    if let Some(score) = prepared.injection_score {
+        details["injection_score"] = score.into();
+    }
+    if audit_log.record_prompts {
+        details["prompt"] = serde_json::Value::String(prepared.user_content.clone());
+    }
+    audit_log.record(AuditEntry {
+        event: "chat.request",
+        request_id: Some(request_id.0.clone()),
+        subject: None,
+        client_ip,
+        details,
+    });
+}
+
+// Stores both sides of a completed turn, returning the assistant message id.
+pub fn record_turn(
+    app_state: &AppState,
+    req: &ChatRequest,
+    content: &str,
+) -> Result<Option<u64>, StaleConversation> {
+    let Some(conversation_id) = &req.conversation_id else {
+        return Ok(None);
+    };
+    app_state
+        .conversations
+        .append_turn(conversation_id, &req.message, content, req.last_message_id)
+        .map(Some)
+}
+
+// 409 for a turn based on an outdated view of the conversation. A reply
+// that was generated but not stored is handed back so it isn't lost.
+pub fn stale_conversation(stale: StaleConversation, content: Option<&str>) -> HttpResponse {
+    log::warn!("Refusing turn on a stale conversation (latest message {})", stale.latest_message_id);
+    let mut body = serde_json::json!({
+        "error": "The conversation has new messages; reload it and try again",
+        "latest_message_id": stale.latest_message_id,
+    });
+    if let Some(content) = content {
+        body["content"] = serde_json::Value::String(content.to_string());
+    }
+    HttpResponse::Conflict().json(body)
+}
+
+// Whole seconds for a Retry-After header: at least 1, since 0 invites an
+// immediate retry, and at most MAX_RETRY_AFTER_SECS however long the
+// upstream asked for.
+const MAX_RETRY_AFTER_SECS: u64 = 300;
+
+fn retry_after_secs(delay: std::time::Duration) -> String {
+    let secs = delay.as_secs_f64().ceil() as u64;
+    secs.clamp(1, MAX_RETRY_AFTER_SECS).to_string()
+}
+
+// Logs an upstream failure and turns it into the client-facing error.
+pub fn upstream_failure(error: UpstreamError, request_id: &RequestId) -> actix_web::Result<HttpResponse> {
+    match error {
+        UpstreamError::Send(e) => {
+            log::error!("Failed to send request: {}", e);
+            Err(actix_web::error::ErrorInternalServerError("Failed to send request to LLM"))
+        }
+        UpstreamError::Status(status, error_body, retry_after, rate_limit)
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+        {
+            log::warn!("LLM endpoint rate-limited the request: {}", error_body);
+            let mut response = HttpResponse::TooManyRequests();
+            if let Some(retry_after) = retry_after {
+                response.insert_header(("Retry-After", retry_after_secs(retry_after)));
+            }
+            for header in rate_limit {
+                response.insert_header(header);
+            }
+            Ok(response.json(serde_json::json!({
+                "error": "LLM endpoint rate limit reached; retry later"
+            })))
+        }
+        UpstreamError::Status(status, error_body, ..) => {
+            log::error!(
+                "HTTP error occurred. Status: {}, Body: {}",
+                status,
+                error_body
+            );
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Error from LLM endpoint"
+            })))
+        }
+        UpstreamError::Decode(e) => {
+            log::error!("Failed to decode response: {}", e);
+            Err(actix_web::error::ErrorInternalServerError("Invalid response from LLM endpoint"))
+        }
+        UpstreamError::NoChoices => {
+            log::error!("Invalid response structure from LLM");
+            Err(actix_web::error::ErrorInternalServerError("Invalid response structure from LLM endpoint"))
+        }
+        UpstreamError::CoolingDown(remaining) => {
+            log::warn!("LLM endpoint is cooling down after a 429 for another {}s", remaining.as_secs());
+            Ok(HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after_secs(remaining)))
+                .json(serde_json::json!({
+                    "error": "LLM endpoint rate limit reached; retry later"
+                })))
+        }
+        UpstreamError::Shed(retry_after) => {
+            log::warn!("Shedding chat request: concurrency queue is full");
+            Ok(HttpResponse::ServiceUnavailable()
+                .insert_header(("Retry-After", retry_after_secs(retry_after)))
+                .json(serde_json::json!({
+                    "error": "The server is overloaded; retry later"
+                })))
+        }
+        UpstreamError::NotJson(..) => {
+            log::error!("LLM endpoint returned a non-JSON success response: {}", error);
+            Ok(HttpResponse::BadGateway().json(serde_json::json!({
+                "error": "upstream returned non-JSON response",
+                "request_id": request_id.0,
+            })))
+        }
+        UpstreamError::Stalled(idle) => {
+            log::error!("LLM stream stalled: no data for {}s", idle.as_secs());
+            Err(actix_web::error::ErrorInternalServerError("LLM endpoint stopped sending tokens"))
+        }
+        UpstreamError::TooLarge(limit) => {
+            log::error!("LLM response passed MAX_UPSTREAM_BYTES ({} bytes), abandoned", limit);
+            Ok(HttpResponse::BadGateway().json(serde_json::json!({
+                "error": format!("upstream response exceeded {} bytes", limit),
+                "request_id": request_id.0,
+            })))
+        }
+    }
+}
+
+// JSON by default; server-sent events, as from /api/chat/stream, when the
+// Accept header prefers text/event-stream.
+#[post("/api/chat")]
+async fn chat_with_llm(
+    http_req: HttpRequest,
+    req: web::Json<ChatRequest>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let (req, request_id) = (req.into_inner(), request_id.into_inner());
+    let response = if stream::wants_event_stream(http_req.headers()) {
+        stream::stream_chat(http_req, req, request_id, client, app_state).await
+    } else {
+        chat_json(http_req, req, request_id, client, app_state).await
+    };
+    response.map(|mut response| {
+        response.headers_mut().insert(VARY, HeaderValue::from_static("Accept"));
+        response
+    })
+}
+
+async fn chat_json(
+    http_req: HttpRequest,
+    req: ChatRequest,
+    request_id: RequestId,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let key = idempotency::key_from(http_req.headers());
+    let (Some(key), Some(store)) = (key, &app_state.idempotency) else {
+        return complete_chat(http_req, req, request_id, client, app_state.clone()).await;
+    };
+    let claim = match store.begin(&key) {
+        Begin::New(claim) => claim,
+        Begin::Replay(stored) => {
+            log::info!("Replaying stored response for idempotency key {}", key);
+            return Ok(stored.to_response(true));
+        }
+        Begin::InProgress => {
+            return Ok(HttpResponse::Conflict().json(serde_json::json!({
+                "error": "A request with this Idempotency-Key is still in progress"
+            })));
+        }
+    };
+    let response = match complete_chat(http_req, req, request_id, client, app_state.clone()).await {
+        Ok(response) => response,
+        Err(e) => e.error_response(),
+    };
+    let stored = StoredResponse::capture(response).await;
+    claim.finish(&stored);
+    Ok(stored.to_response(false))
+}
+
+#[derive(Debug, Serialize)]
+struct ValidatedChat {
+    // Exactly what /api/chat would send upstream
+    payload: serde_json::Value,
+    history_trimmed: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'static str>,
+}
+
+// Runs the /api/chat checks and returns the upstream payload without
+// calling the LLM. Budget and stale-conversation checks apply too, so the
+// errors match what /api/chat would return.
+#[post("/api/chat/validate")]
+async fn validate_chat(req: web::Json<ChatRequest>, app_state: web::Data<AppState>) -> HttpResponse {
+    match prepare_chat(req.into_inner(), &app_state) {
+        Ok(prepared) => HttpResponse::Ok().json(ValidatedChat {
+            payload: prepared.payload(),
+            history_trimmed: prepared.history_trimmed,
+            language: prepared.language,
+        }),
+        Err(response) => response,
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReplayRequest {
+    // Same forms as ChatRequest.endpoint; the primary endpoint by default
+    endpoint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayedTurn {
+    // Id of the stored user message
+    message_id: u64,
+    user: String,
+    // Stored reply; None when the conversation ends on a user message
+    original: Option<String>,
+    replay: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayResult {
+    conversation_id: String,
+    endpoint: String,
+    turns: Vec<ReplayedTurn>,
+}
+
+// Re-sends every user turn of a stored conversation, for comparing a model
+// or prompt change against the replies on record. Each turn sees the
+// original transcript before it, not earlier replayed replies, so turns are
+// compared like for like. Nothing is stored; calls run at low priority.
+// Admin only.
+#[post("/api/conversations/{id}/replay")]
+async fn replay_conversation(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: Option<web::Json<ReplayRequest>>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = crate::admin::authorize(&http_req, &app_state) {
+        return Ok(response);
+    }
+    let conversation_id = path.into_inner();
+    let requested = body.map(web::Json::into_inner).unwrap_or_default().endpoint;
+    let endpoint = match (requested, &app_state.endpoint_override) {
+        (None, _) => match &app_state.regions {
+            Some(regions) => regions.best().clone(),
+            None => app_state.primary_endpoint(),
+        },
+        (Some(_), None) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "endpoint overrides are disabled on this server"
+            })));
+        }
+        (Some(requested), Some(overrides)) => match overrides.resolve(&requested) {
+            Ok(endpoint) => endpoint,
+            Err(message) => {
+                return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": message })));
+            }
+        },
+    };
+    let Some(stored) = app_state.conversations.load_history(&conversation_id) else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Conversation not found"
+        })));
+    };
+
+    log::info!("Replaying conversation {} against {}", conversation_id, endpoint.name);
+    let trace = TraceHeaders::for_request(&http_req, &request_id, app_state.trace_context, &app_state.forward_headers);
+    let mut transcript: Vec<ChatMessage> = Vec::new();
+    let mut turns = Vec::new();
+    for (i, message) in stored.iter().enumerate() {
+        let role = match message.role.as_str() {
+            "user" => Role::User,
+            "assistant" => Role::Assistant,
+            "system" => Role::System,
+            _ => continue,
+        };
+        if role == Role::User {
+            // Built like the original turn's request, generation defaults and all
+            let req: ChatRequest = serde_json::from_value(serde_json::json!({
+                "message": message.content,
+                "history": transcript,
+            }))
+            .expect("a stored turn makes a valid chat request");
+            let payload = match prepare_stored_turn(req, &app_state) {
+                Ok(prepared) => prepared.payload(),
+                Err(response) => return Ok(response),
+            };
+            app_state.metrics.llm_requests.inc(&[&endpoint.name, "replay"]);
+            let result = call_llm(&app_state, &client, &endpoint, &payload, &trace, Priority::Low).await;
+            let (replay, error) = match result {
+                Ok(completion) => {
+                    record_usage(&app_state, completion.usage);
+                    (Some(completion.content), None)
+                }
+                Err(e) => {
+                    count_upstream_error(&app_state, &endpoint.name, &e);
+                    log::warn!("Replay of message {} failed: {}", message.id, e);
+                    (None, Some(e.to_string()))
+                }
+            };
+            let original = stored
+                .get(i + 1)
+                .filter(|next| next.role == "assistant")
+                .map(|next| next.content.clone());
+            turns.push(ReplayedTurn {
+                message_id: message.id,
+                user: message.content.clone(),
+                original,
+                replay,
+                error,
+            });
+        }
+        transcript.push(ChatMessage {
+            role,
+            content: message.content.clone(),
+        });
+    }
+
+    app_state.audit(AuditEntry {
+        event: "conversation.replayed",
+        request_id: Some(request_id.0.clone()),
+        subject: None,
+        client_ip: client_ip::client_ip(&app_state, &http_req),
+        details: serde_json::json!({
+            "conversation_id": conversation_id,
+            "endpoint": endpoint.name,
+            "turns": turns.len(),
+            "failed": turns.iter().filter(|turn| turn.error.is_some()).count(),
+        }),
+    });
+    Ok(HttpResponse::Ok().json(ReplayResult {
+        conversation_id,
+        endpoint: endpoint.name,
+        turns,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RegenerateRequest {
+    // Sampling changes for the new reply, e.g. a higher temperature
+    temperature: Option<f64>,
+    seed: Option<u64>,
+}
+
+// Swaps the reply that ends a stored conversation for a new one to the same
+// user message, sent with the transcript before it. The new reply gets a new
+// message id. Template turns are re-sent as the message that was stored.
+// The response cache is bypassed, since a repeat is the point.
+#[post("/api/conversations/{id}/regenerate")]
+async fn regenerate_reply(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    body: Option<web::Json<RegenerateRequest>>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let conversation_id = path.into_inner();
+    let options = body.map(web::Json::into_inner).unwrap_or_default();
+    // Held to the range DEFAULT_TEMPERATURE is
+    if options.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "temperature must be a number between 0 and 2"
+        })));
+    }
+    let _turn = match ConversationTurns::begin(&app_state, Some(&conversation_id)).await {
+        Ok(turn) => turn,
+        Err(response) => return Ok(response),
+    };
+    let Some(stored) = app_state.conversations.load_history(&conversation_id) else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Conversation not found"
+        })));
+    };
+    let (user, reply_id) = match stored.as_slice() {
+        [.., user, reply] if user.role == "user" && reply.role == "assistant" => (user, reply.id),
+        _ => {
+            return Ok(HttpResponse::Conflict().json(serde_json::json!({
+                "error": "The conversation does not end with a reply to regenerate"
+            })));
+        }
+    };
+    let history: Vec<ChatMessage> = stored[..stored.len() - 2]
+        .iter()
+        .filter_map(|message| {
+            let role = match message.role.as_str() {
+                "user" => Role::User,
+                "assistant" => Role::Assistant,
+                "system" => Role::System,
+                _ => return None,
+            };
+            Some(ChatMessage {
+                role,
+                content: message.content.clone(),
+            })
+        })
+        .collect();
+    let extra = options.temperature.map(|temperature| serde_json::json!({ "temperature": temperature }));
+    let req: ChatRequest = serde_json::from_value(serde_json::json!({
+        "message": user.content,
+        "history": history,
+        "conversation_id": conversation_id,
+        "seed": options.seed,
+        "extra": extra,
+    }))
+    .expect("a stored turn makes a valid chat request");
+    let prepared = match prepare_stored_turn(req, &app_state) {
+        Ok(prepared) => prepared,
+        Err(response) => return Ok(response),
+    };
+    let payload = prepared.payload();
+
+    let (selected, route) = select_endpoint(&app_state, &prepared);
+    let endpoint = &*selected;
+    let client_ip = client_ip::client_ip(&app_state, &http_req);
+    audit_request(&app_state, &request_id, client_ip, endpoint, &prepared);
+    log::info!("Regenerating the last reply of conversation {} on {}", conversation_id, endpoint.name);
+    let trace = TraceHeaders::for_request(&http_req, &request_id, app_state.trace_context, &app_state.forward_headers);
+    let started = Instant::now();
+    let completion = match call_llm(&app_state, &client, endpoint, &payload, &trace, prepared.req.priority).await {
+        Ok(completion) => completion,
+        Err(e) => {
+            count_upstream_error(&app_state, &endpoint.name, &e);
+            return upstream_failure(e, &request_id);
+        }
+    };
+
+    let mut content = completion.content;
+    let mut finish_reason = completion.finish_reason;
+    if let Some(filters) = &app_state.reply_filters {
+        content = filters.apply(&content);
+    }
+    if app_state
+        .max_response_chars
+        .is_some_and(|max| truncate_reply(&mut content, max))
+    {
+        finish_reason = Some(TRUNCATED_BY_SERVER.to_string());
+    }
+    record_usage(&app_state, completion.usage);
+    let message_id = match app_state.conversations.replace_last_reply(&conversation_id, reply_id, &content) {
+        Ok(message_id) => message_id,
+        Err(stale) => return Ok(stale_conversation(stale, Some(&content))),
+    };
+    app_state.completion_events.publish(CompletionEvent {
+        request_id: request_id.0.clone(),
+        conversation_id: Some(conversation_id.clone()),
+        model: endpoint.name.clone(),
+        route,
+        usage: completion.usage,
+        finish_reason: finish_reason.clone(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        client_ip,
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-LLM-Endpoint", endpoint.name.as_str()))
+        .insert_header(("X-LLM-Route", route))
+        .json(ChatResponse {
+            content,
+            conversation_id: Some(conversation_id),
+            message_id: Some(message_id),
+            finish_reason,
+            model: Some(endpoint.name.clone()),
+            debug: None,
+        }))
+}
+
+// The /api/chat pipeline, also run in the background for /api/chat/async.
+pub async fn complete_chat(
+    http_req: HttpRequest,
+    req: ChatRequest,
+    request_id: RequestId,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let started = Instant::now();
+    // Before prepare_chat, so its last_message_id check sees earlier turns
+    let _turn = match ConversationTurns::begin(&app_state, req.conversation_id.as_deref()).await {
+        Ok(turn) => turn,
+        Err(response) => return Ok(response),
+    };
+    let prepared = match prepare_chat(req, &app_state) {
+        Ok(prepared) => prepared,
+        Err(response) => return Ok(response),
+    };
+    let payload = prepared.payload();
+    // Before the cache, which is split by endpoint
+    let (selected, route) = select_endpoint(&app_state, &prepared);
+    let endpoint = &*selected;
+
+    // Overridden requests are experiments; their replies aren't the primary's
+    let cache_key = app_state
+        .response_cache
+        .as_ref()
+        .filter(|_| prepared.endpoint_override.is_none())
+        .map(|_| ResponseCache::key(prepared.req.template.as_deref(), &endpoint.name, &payload));
+    if let (Some(cache), Some(key)) = (&app_state.response_cache, &cache_key) {
+        if let Some(CachedReply { content, endpoint }) = cache.get(key) {
+            app_state.metrics.cache_hits.inc(&["response"]);
+            log::info!("Serving chat reply from the response cache ({})", endpoint);
+            let req = prepared.req;
+            let message_id = match record_turn(&app_state, &req, &content) {
+                Ok(message_id) => message_id,
+                Err(stale) => return Ok(stale_conversation(stale, Some(&content))),
+            };
+            return Ok(HttpResponse::Ok()
+                .insert_header(("X-Cache", "hit"))
+                .insert_header(("X-LLM-Endpoint", endpoint.as_str()))
+                .insert_header(("X-LLM-Route", route))
+                .json(ChatResponse {
+                    content,
+                    conversation_id: req.conversation_id,
+                    message_id,
+                    finish_reason: None,
+                    model: Some(endpoint),
+                    debug: None,
+                }));
+        }
+        app_state.metrics.cache_misses.inc(&["response"]);
+    }
+
+    let client_ip = client_ip::client_ip(&app_state, &http_req);
+    audit_request(&app_state, &request_id, client_ip, endpoint, &prepared);
+
+    log::info!("Sending request to LLM endpoint: {} ({})", endpoint.url, route);
+    let trace = TraceHeaders::for_request(&http_req, &request_id, app_state.trace_context, &app_state.forward_headers);
+
+    let shadow_tx = app_state
+        .shadow
+        .as_ref()
+        .map(|shadow| shadow.mirror(&client, &app_state.api_key, endpoint, &payload));
+
+    let call_started = Instant::now();
+    let result = call_llm(&app_state, &client, endpoint, &payload, &trace, prepared.req.priority).await;
+    // Wall time of upstream calls, including retries and limiter waits
+    let mut upstream_time = call_started.elapsed();
+    if let Some(regions) = &app_state.regions {
+        regions.record_call(endpoint, upstream_time, result.as_ref().err());
+    }
+    if let Some(shadow_tx) = shadow_tx {
+        let outcome = result
+            .as_ref()
+            .map(|completion| completion.content.clone())
+            .map_err(ToString::to_string);
+        let _ = shadow_tx.send(outcome);
+    }
+
+    let (result, endpoint, route) = match &app_state.filter_fallback {
+        Some(fallback) if upstream::content_filtered(&result) => {
+            log::warn!(
+                "Reply from {} was content-filtered, retrying on {}",
+                endpoint.name,
+                fallback.name
+            );
+            app_state.metrics.llm_requests.inc(&[&fallback.name, "filter_fallback"]);
+            let call_started = Instant::now();
+            let result = call_llm(&app_state, &client, fallback, &payload, &trace, prepared.req.priority).await;
+            upstream_time += call_started.elapsed();
+            (result, fallback, "filter_fallback")
+        }
+        _ => (result, endpoint, route),
+    };
+
+    let completion = match result {
+        Ok(completion) => completion,
+        Err(e) => {
+            count_upstream_error(&app_state, &endpoint.name, &e);
+            if let Some(fallback) = &app_state.fallback_response {
+                log::error!("LLM request failed, serving fallback response: {}", e);
+                return Ok(HttpResponse::Ok()
+                    .insert_header(("X-Fallback-Response", "true"))
+                    .json(ChatResponse {
+                        content: fallback.clone(),
+                        conversation_id: prepared.req.conversation_id,
+                        message_id: None,
+                        finish_reason: None,
+                        model: None,
+                        debug: None,
+                    }));
+            }
+            return upstream_failure(e, &request_id);
+        }
+    };
+
+    log::info!("Received response from LLM");
+
+    // The first exchange; a schema repair call isn't included
+    let debug = (prepared.req.debug == Some(true) && debug::enabled())
+        .then(|| debug::exchange(endpoint, app_state.upstream_transport, &payload, &trace, &completion));
+    let mut content = match &prepared.req.prefill {
+        Some(prefill) => format!("{}{}", prefill, completion.content),
+        None => completion.content,
+    };
+    let mut usage = completion.usage;
+    let mut finish_reason = completion.finish_reason;
+    let mut schema_repaired = false;
+    if let Some(schema) = &prepared.req.schema {
+        let mut errors = schema::validate_reply(schema, &content);
+        if !errors.is_empty() && app_state.schema_repair {
+            log::warn!("Reply failed schema validation, asking for a fix: {}", errors.join("; "));
+            app_state.metrics.llm_requests.inc(&[&endpoint.name, "schema_repair"]);
+            let mut repair = payload.clone();
+            if let Some(messages) = repair["messages"].as_array_mut() {
+                // The failed reply replaces the prefill turn, which it starts with
+                if prepared.req.prefill.is_some() {
+                    messages.pop();
+                }
+                messages.push(serde_json::json!({ "role": "assistant", "content": content }));
+                messages.push(serde_json::json!({ "role": "user", "content": schema::repair_prompt(&errors) }));
+            }
+            let call_started = Instant::now();
+            let repaired = call_llm(&app_state, &client, endpoint, &repair, &trace, prepared.req.priority).await;
+            upstream_time += call_started.elapsed();
+            match repaired {
+                Ok(repaired) => {
+                    usage = match (usage, repaired.usage) {
+                        (Some(first), Some(second)) => Some(first + second),
+                        (first, second) => first.or(second),
+                    };
+                    content = repaired.content;
+                    finish_reason = repaired.finish_reason;
+                    errors = schema::validate_reply(schema, &content);
+                    schema_repaired = true;
+                }
+                Err(e) => {
+                    count_upstream_error(&app_state, &endpoint.name, &e);
+                    log::error!("Schema repair request failed: {}", e);
+                }
+            }
+        }
+        if !errors.is_empty() {
+            log::error!("Reply does not match the request schema: {}", errors.join("; "));
+            return Ok(HttpResponse::BadGateway().json(serde_json::json!({
+                "error": "LLM reply did not match the requested schema",
+                "validation_errors": errors,
+                "content": content,
+            })));
+        }
+    }
+    if let Some(filters) = &app_state.reply_filters {
+        content = filters.apply(&content);
+    }
+    if app_state
+        .max_response_chars
+        .is_some_and(|max| truncate_reply(&mut content, max))
+    {
+        log::warn!("Reply from {} exceeded MAX_RESPONSE_CHARS, truncated", endpoint.name);
+        finish_reason = Some(TRUNCATED_BY_SERVER.to_string());
+    }
+    if let (Some(cache), Some(key)) = (&app_state.response_cache, cache_key) {
+        cache.insert(
+            key,
+            CachedReply {
+                content: content.clone(),
+                endpoint: endpoint.name.clone(),
+            },
+        );
+    }
+    let cost_usd = record_usage(&app_state, usage);
+
+    let req = prepared.req;
+    let message_id = match record_turn(&app_state, &req, &content) {
+        Ok(message_id) => message_id,
+        Err(stale) => return Ok(stale_conversation(stale, Some(&content))),
+    };
+
+    app_state.completion_events.publish(CompletionEvent {
+        request_id: request_id.0.clone(),
+        conversation_id: req.conversation_id.clone(),
+        model: endpoint.name.clone(),
+        route,
+        usage,
+        finish_reason: finish_reason.clone(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        client_ip,
+    });
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("X-LLM-Endpoint", endpoint.name.as_str()))
+        .insert_header(("X-LLM-Route", route));
+    if prepared.history_trimmed > 0 {
+        response.insert_header(("X-History-Trimmed", prepared.history_trimmed.to_string()));
+    }
+    if let Some(language) = prepared.language {
+        response.insert_header(("X-Detected-Language", language));
+    }
+    if let Some(header) = injection_header(&app_state, prepared.injection_score) {
+        response.insert_header(header);
+    }
+    if schema_repaired {
+        response.insert_header(("X-Schema-Repaired", "true"));
+    }
+    if let Some(cost) = cost_usd.filter(|_| app_state.pricing.is_configured()) {
+        response.insert_header(("X-Request-Cost-USD", format!("{:.6}", cost)));
+    }
+    if app_state.debug_headers {
+        response
+            .insert_header(("X-Upstream-Duration-Ms", upstream_time.as_millis().to_string()))
+            .insert_header(("X-Total-Duration-Ms", started.elapsed().as_millis().to_string()));
+    }
+    Ok(response.json(ChatResponse {
+        content,
+        conversation_id: req.conversation_id,
+        message_id,
+        finish_reason,
+        model: Some(endpoint.name.clone()),
+        debug,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dedupe_history, ChatMessage, HistoryLimit, Role};
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn dedupe_collapses_consecutive_repeats_only() {
+        let mut history = vec![
+            message(Role::User, "Hi"),
+            message(Role::User, "Hi"),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+            message(Role::User, "Hello!"),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+            message(Role::Assistant, "Hello!"),
+        ];
+        assert_eq!(dedupe_history(&mut history), 3);
+        let kept: Vec<(Role, &str)> = history.iter().map(|m| (m.role, m.content.as_str())).collect();
+        assert_eq!(
+            kept,
+            [
+                (Role::User, "Hi"),
+                (Role::Assistant, "Hello!"),
+                (Role::User, "Hello!"),
+                (Role::User, "Hi"),
+                (Role::Assistant, "Hello!"),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn regenerate_works_past_the_history_limit() {
+        let body = r#"{"choices":[{"message":{"role":"assistant","content":"Fresh"},"finish_reason":"stop"}]}"#;
+        let endpoint = crate::fixed_upstream("application/json", body).await;
+        let base_url = crate::test_server(endpoint, |app_state| {
+            app_state.history_limit = HistoryLimit {
+                max_messages: 2,
+                trim: false,
+            };
+            for turn in 0..3 {
+                let (user, reply) = (format!("Question {}", turn), format!("Answer {}", turn));
+                app_state.conversations.append_turn("long", &user, &reply, None).unwrap();
+            }
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let regenerate = format!("{}/api/conversations/long/regenerate", base_url);
+        let too_hot = client.post(&regenerate).json(&serde_json::json!({ "temperature": 2.5 })).send();
+        assert_eq!(too_hot.await.unwrap().status(), 400);
+        let reply = client.post(&regenerate).send().await.unwrap();
+        assert_eq!(reply.status(), 200);
+        let reply = reply.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(reply["content"], "Fresh");
+    }
+}
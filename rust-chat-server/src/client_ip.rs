@@ -0,0 +1,84 @@
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::AppState;
+
+// Behind a load balancer the peer address is the proxy's. With
+// TRUST_PROXY=true, a request whose peer is a trusted proxy is attributed to
+// the rightmost X-Forwarded-For entry that is not itself a trusted proxy;
+// anything further left was written by the client and can be forged.
+// TRUSTED_PROXIES lists the proxies as IPs or CIDRs and defaults to loopback
+// and private ranges. Without TRUST_PROXY the header is ignored.
+pub struct ProxyTrust {
+    proxies: Vec<IpNet>,
+}
+
+const DEFAULT_TRUSTED_PROXIES: &str = "127.0.0.0/8,::1/128,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,fc00::/7";
+
+impl ProxyTrust {
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("TRUST_PROXY").map(|v| v != "true").unwrap_or(true) {
+            return None;
+        }
+        let list = std::env::var("TRUSTED_PROXIES")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_TRUSTED_PROXIES.to_string());
+        let proxies = list
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .expect("TRUSTED_PROXIES must be a comma-separated list of IPs or CIDRs")
+            })
+            .collect();
+        log::info!("Taking client addresses from X-Forwarded-For behind {}", list);
+        Some(Self { proxies })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+// The address a request is attributed to in logs and the audit trail.
+pub fn client_ip(app_state: &AppState, req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip().to_canonical();
+    let Some(trust) = &app_state.proxy_trust else {
+        return Some(peer);
+    };
+    if !trust.trusts(peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut client = peer;
+    for entry in forwarded.iter().rev() {
+        let Some(ip) = parse_entry(entry) else {
+            break;
+        };
+        client = ip;
+        if !trust.trusts(ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+// Entries are usually bare addresses, but some proxies append the port.
+fn parse_entry(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpAddr>()
+        .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
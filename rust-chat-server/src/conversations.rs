@@ -0,0 +1,811 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{delete, get, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::admin;
+use crate::audit::AuditEntry;
+use crate::client_ip;
+use crate::request_id::RequestId;
+use crate::AppState;
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+const MAX_SEARCH_RESULTS: usize = 50;
+const SNIPPETS_PER_CONVERSATION: usize = 3;
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub conversation_id: String,
+    pub matches: usize,
+    pub snippets: Vec<String>,
+    // Id of the most recent matching message, used to rank hits
+    pub last_match_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub role: String,
+    pub content: String,
+    pub created_at_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Feedback {
+    pub message_id: u64,
+    pub rating: Rating,
+    pub comment: Option<String>,
+    pub created_at_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Conversation {
+    messages: Vec<Message>,
+    feedback: Vec<Feedback>,
+}
+
+#[derive(Debug)]
+struct Message {
+    id: u64,
+    role: String,
+    content: Content,
+    created_at_ms: u64,
+}
+
+impl Message {
+    fn stored(&self) -> StoredMessage {
+        StoredMessage {
+            id: self.id,
+            role: self.role.clone(),
+            content: self.content.text().into_owned(),
+            created_at_ms: self.created_at_ms,
+        }
+    }
+}
+
+// Shorter messages rarely shrink enough to pay for the frame
+const COMPRESS_MIN_BYTES: usize = 256;
+const COMPRESSION_LEVEL: i32 = 3;
+
+// Message text as held by MemoryStore. With COMPRESS_HISTORY=true longer
+// messages are kept zstd-compressed and inflated whenever they are read,
+// including by search.
+#[derive(Debug)]
+enum Content {
+    Plain(String),
+    Zstd(Vec<u8>),
+}
+
+impl Content {
+    fn new(text: &str, compress: bool) -> Self {
+        if compress && text.len() >= COMPRESS_MIN_BYTES {
+            match zstd::bulk::compress(text.as_bytes(), COMPRESSION_LEVEL) {
+                Ok(compressed) if compressed.len() < text.len() => return Content::Zstd(compressed),
+                Ok(_) => {}
+                Err(e) => log::warn!("Storing message uncompressed: {}", e),
+            }
+        }
+        Content::Plain(text.to_string())
+    }
+
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            Content::Plain(text) => Cow::Borrowed(text),
+            Content::Zstd(compressed) => match zstd::stream::decode_all(compressed.as_slice()) {
+                Ok(bytes) => Cow::Owned(String::from_utf8_lossy(&bytes).into_owned()),
+                Err(e) => {
+                    log::error!("Failed to decompress a stored message: {}", e);
+                    Cow::Borrowed("")
+                }
+            },
+        }
+    }
+}
+
+// The conversation gained messages after the one a client last saw.
+#[derive(Debug)]
+pub struct StaleConversation {
+    pub latest_message_id: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FeedbackCounts {
+    pub up: u64,
+    pub down: u64,
+}
+
+// Where conversations are persisted. Handlers only go through this, so the
+// backend is picked once at startup by `from_env`; MemoryStore is the only
+// implementation so far. Message ids must be
+// ordered across conversations; paging and search ranking rely on it.
+pub trait ConversationStore: Send + Sync {
+    // Every message of a conversation, oldest first; None if it is unknown.
+    fn load_history(&self, conversation_id: &str) -> Option<Vec<StoredMessage>>;
+
+    // Id of the newest message; 0 for an unknown or empty conversation.
+    fn latest_message_id(&self, conversation_id: &str) -> u64;
+
+    // Stores a user message and its reply as one step, returning the reply's
+    // id. With `expected_latest`, fails instead when the conversation has
+    // moved past that message, e.g. because a turn from another tab landed
+    // first. There is deliberately no single-message append: a half-stored
+    // turn would leave a user message without its reply.
+    fn append_turn(
+        &self,
+        conversation_id: &str,
+        user: &str,
+        assistant: &str,
+        expected_latest: Option<u64>,
+    ) -> Result<u64, StaleConversation>;
+
+    // Swaps the reply that ends the conversation, `expected_latest`, for
+    // `assistant`, returning the new reply's id. Fails when that reply is no
+    // longer the newest message.
+    fn replace_last_reply(
+        &self,
+        conversation_id: &str,
+        expected_latest: u64,
+        assistant: &str,
+    ) -> Result<u64, StaleConversation>;
+
+    // Records a rating against a message; returns false if the conversation
+    // or message does not exist.
+    fn add_feedback(&self, conversation_id: &str, feedback: Feedback) -> bool;
+
+    // Counts ratings submitted within [since_ms, until_ms).
+    fn feedback_counts(&self, since_ms: u64, until_ms: u64) -> FeedbackCounts;
+
+    // Returns up to `limit` messages older than `before`, newest first, along
+    // with the total number of messages in the conversation and whether older
+    // messages remain beyond this page. Backends that can page in the
+    // database should override this.
+    fn page(
+        &self,
+        conversation_id: &str,
+        limit: usize,
+        before: Option<u64>,
+    ) -> Option<(Vec<StoredMessage>, usize, bool)> {
+        let messages = self.load_history(conversation_id)?;
+        let total = messages.len();
+        let mut older = messages
+            .into_iter()
+            .rev()
+            .filter(|m| before.is_none_or(|cursor| m.id < cursor));
+        let page: Vec<StoredMessage> = older.by_ref().take(limit).collect();
+        let has_more = older.next().is_some();
+        Some((page, total, has_more))
+    }
+
+    // Removes a conversation, returning how many messages it held.
+    fn delete(&self, conversation_id: &str) -> Option<usize>;
+
+    // Removes every conversation whose latest message is older than the
+    // cutoff, returning the ids that were deleted.
+    fn delete_older_than(&self, cutoff_ms: u64) -> Vec<String>;
+
+    // Case-insensitive substring search over message content, most recently
+    // matched conversations first.
+    fn search(&self, pattern: &Regex, limit: usize) -> Vec<SearchHit>;
+}
+
+// Picks the backend from the DATABASE_URL scheme. Unset, or `memory:`,
+// keeps conversations in process, which is the only backend so far.
+pub fn from_env() -> Box<dyn ConversationStore> {
+    let url = std::env::var("DATABASE_URL").unwrap_or_default();
+    let scheme = url.split_once(':').map_or(url.as_str(), |(scheme, _)| scheme);
+    match scheme {
+        "" | "memory" => Box::new(MemoryStore::from_env()),
+        other => panic!("DATABASE_URL scheme {:?} is not supported; use memory: or leave it unset", other),
+    }
+}
+
+// In-process conversation store. Messages are kept oldest first; ids come
+// from a single counter so they are also ordered across conversations.
+#[derive(Default)]
+pub struct MemoryStore {
+    conversations: Mutex<HashMap<String, Conversation>>,
+    next_id: AtomicU64,
+    compress: bool,
+}
+
+impl MemoryStore {
+    pub fn from_env() -> Self {
+        let compress = std::env::var("COMPRESS_HISTORY").map(|v| v == "true").unwrap_or(false);
+        if compress {
+            log::info!("Compressing stored messages of {} bytes or more", COMPRESS_MIN_BYTES);
+        }
+        Self {
+            compress,
+            ..Self::default()
+        }
+    }
+
+    // Called with the store locked, so ids are handed out in the order
+    // messages land. Timestamps never go backwards within a conversation,
+    // even if the clock does.
+    fn push(&self, conversation: &mut Conversation, role: &str, content: &str) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let created_at_ms = conversation
+            .messages
+            .last()
+            .map_or(0, |latest| latest.created_at_ms)
+            .max(now_ms());
+        conversation.messages.push(Message {
+            id,
+            role: role.to_string(),
+            content: Content::new(content, self.compress),
+            created_at_ms,
+        });
+        id
+    }
+}
+
+impl ConversationStore for MemoryStore {
+    fn load_history(&self, conversation_id: &str) -> Option<Vec<StoredMessage>> {
+        let conversations = self.conversations.lock().unwrap();
+        Some(conversations.get(conversation_id)?.messages.iter().map(Message::stored).collect())
+    }
+
+    fn latest_message_id(&self, conversation_id: &str) -> u64 {
+        let conversations = self.conversations.lock().unwrap();
+        conversations
+            .get(conversation_id)
+            .and_then(|conversation| conversation.messages.last())
+            .map_or(0, |latest| latest.id)
+    }
+
+    fn append_turn(
+        &self,
+        conversation_id: &str,
+        user: &str,
+        assistant: &str,
+        expected_latest: Option<u64>,
+    ) -> Result<u64, StaleConversation> {
+        let mut conversations = self.conversations.lock().unwrap();
+        let latest_message_id = conversations
+            .get(conversation_id)
+            .and_then(|conversation| conversation.messages.last())
+            .map_or(0, |latest| latest.id);
+        if expected_latest.is_some_and(|expected| expected != latest_message_id) {
+            return Err(StaleConversation { latest_message_id });
+        }
+        let conversation = conversations.entry(conversation_id.to_string()).or_default();
+        self.push(conversation, "user", user);
+        Ok(self.push(conversation, "assistant", assistant))
+    }
+
+    fn replace_last_reply(
+        &self,
+        conversation_id: &str,
+        expected_latest: u64,
+        assistant: &str,
+    ) -> Result<u64, StaleConversation> {
+        let mut conversations = self.conversations.lock().unwrap();
+        let Some(conversation) = conversations.get_mut(conversation_id) else {
+            return Err(StaleConversation { latest_message_id: 0 });
+        };
+        match conversation.messages.last() {
+            Some(latest) if latest.id == expected_latest && latest.role == "assistant" => {}
+            latest => {
+                return Err(StaleConversation {
+                    latest_message_id: latest.map_or(0, |latest| latest.id),
+                })
+            }
+        }
+        conversation.messages.pop();
+        Ok(self.push(conversation, "assistant", assistant))
+    }
+
+    fn add_feedback(&self, conversation_id: &str, feedback: Feedback) -> bool {
+        let mut conversations = self.conversations.lock().unwrap();
+        let Some(conversation) = conversations.get_mut(conversation_id) else {
+            return false;
+        };
+        if !conversation.messages.iter().any(|m| m.id == feedback.message_id) {
+            return false;
+        }
+        conversation.feedback.push(feedback);
+        true
+    }
+
+    fn feedback_counts(&self, since_ms: u64, until_ms: u64) -> FeedbackCounts {
+        let conversations = self.conversations.lock().unwrap();
+        let mut counts = FeedbackCounts::default();
+        for feedback in conversations
+            .values()
+            .flat_map(|c| &c.feedback)
+            .filter(|f| f.created_at_ms >= since_ms && f.created_at_ms < until_ms)
+        {
+            match feedback.rating {
+                Rating::Up => counts.up += 1,
+                Rating::Down => counts.down += 1,
+            }
+        }
+        counts
+    }
+
+    fn delete(&self, conversation_id: &str) -> Option<usize> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .remove(conversation_id)
+            .map(|conversation| conversation.messages.len())
+    }
+
+    fn delete_older_than(&self, cutoff_ms: u64) -> Vec<String> {
+        let mut conversations = self.conversations.lock().unwrap();
+        let expired: Vec<String> = conversations
+            .iter()
+            .filter(|(_, conversation)| {
+                conversation
+                    .messages
+                    .last()
+                    .is_none_or(|latest| latest.created_at_ms < cutoff_ms)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            conversations.remove(id);
+        }
+        expired
+    }
+
+    fn search(&self, pattern: &Regex, limit: usize) -> Vec<SearchHit> {
+        let conversations = self.conversations.lock().unwrap();
+        let mut hits: Vec<SearchHit> = conversations
+            .iter()
+            .filter_map(|(conversation_id, conversation)| {
+                let matching: Vec<(u64, Cow<str>)> = conversation
+                    .messages
+                    .iter()
+                    .rev()
+                    .map(|m| (m.id, m.content.text()))
+                    .filter(|(_, text)| pattern.is_match(text))
+                    .collect();
+                let last_match_id = matching.first()?.0;
+                Some(SearchHit {
+                    conversation_id: conversation_id.clone(),
+                    matches: matching.len(),
+                    snippets: matching
+                        .iter()
+                        .take(SNIPPETS_PER_CONVERSATION)
+                        .filter_map(|(_, text)| snippet(pattern, text))
+                        .collect(),
+                    last_match_id,
+                })
+            })
+            .collect();
+        hits.sort_by_key(|hit| Reverse(hit.last_match_id));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+// Cuts a window of text around the first match, marking elided ends.
+fn snippet(pattern: &Regex, content: &str) -> Option<String> {
+    let found = pattern.find(content)?;
+    let start = content[..found.start()]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT_CHARS - 1)
+        .map_or(0, |(i, _)| i);
+    let end = content[found.end()..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT_CHARS)
+        .map_or(content.len(), |(i, _)| found.end() + i);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(content[start..end].trim());
+    if end < content.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Parses retention windows such as "90s", "30m", "12h" or "7d"; a bare
+// number is taken as seconds.
+fn parse_age(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (digits, unit_secs) = match value.char_indices().last()? {
+        (i, 's') => (&value[..i], 1),
+        (i, 'm') => (&value[..i], 60),
+        (i, 'h') => (&value[..i], 60 * 60),
+        (i, 'd') => (&value[..i], 24 * 60 * 60),
+        _ => (value, 1),
+    };
+    let amount: u64 = digits.parse().ok()?;
+    amount.checked_mul(unit_secs).map(Duration::from_secs)
+}
+
+#[derive(Debug, Deserialize)]
+struct RetentionQuery {
+    older_than: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesQuery {
+    limit: Option<usize>,
+    before: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResults {
+    query: String,
+    results: Vec<SearchHit>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConversationExport {
+    conversation_id: String,
+    messages: Vec<StoredMessage>,
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesPage {
+    conversation_id: String,
+    messages: Vec<StoredMessage>,
+    total: usize,
+    next_cursor: Option<u64>,
+}
+
+#[get("/api/conversations/{id}/messages")]
+async fn get_messages(
+    path: web::Path<String>,
+    query: web::Query<MessagesQuery>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let conversation_id = path.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let Some((messages, total, has_more)) = app_state
+        .conversations
+        .page(&conversation_id, limit, query.before)
+    else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Conversation not found"
+        })));
+    };
+
+    // Only hand out a cursor when there is something older left to fetch
+    let next_cursor = if has_more {
+        messages.last().map(|oldest| oldest.id)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(MessagesPage {
+        conversation_id,
+        messages,
+        total,
+        next_cursor,
+    }))
+}
+
+#[get("/api/conversations/{id}/export")]
+async fn export_conversation(
+    path: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let conversation_id = path.into_inner();
+    let markdown = match query.format.as_deref().unwrap_or("md") {
+        "md" | "markdown" => true,
+        "json" => false,
+        other => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("unknown format {:?}: use md or json", other)
+            })));
+        }
+    };
+    let Some(messages) = app_state.conversations.load_history(&conversation_id) else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Conversation not found"
+        })));
+    };
+
+    let extension = if markdown { "md" } else { "json" };
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!(
+            "conversation-{}.{}",
+            file_stem(&conversation_id),
+            extension
+        ))],
+    };
+    let mut response = HttpResponse::Ok();
+    response.insert_header(disposition);
+    if markdown {
+        Ok(response
+            .content_type("text/markdown; charset=utf-8")
+            .body(render_markdown(&conversation_id, &messages)))
+    } else {
+        Ok(response.json(ConversationExport {
+            conversation_id,
+            messages,
+        }))
+    }
+}
+
+// One `##` section per message, headed by the capitalized role.
+fn render_markdown(conversation_id: &str, messages: &[StoredMessage]) -> String {
+    let mut markdown = format!("# Conversation {}\n", conversation_id);
+    for message in messages {
+        let mut role = message.role.chars();
+        let heading: String = role
+            .next()
+            .map(|first| first.to_uppercase().chain(role).collect())
+            .unwrap_or_default();
+        markdown.push_str(&format!("\n## {}\n\n{}\n", heading, message.content.trim_end()));
+    }
+    markdown
+}
+
+// Conversation ids are client-chosen; keep only what is safe in a file name.
+fn file_stem(conversation_id: &str) -> String {
+    let stem: String = conversation_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .take(64)
+        .collect();
+    if stem.is_empty() {
+        "export".to_string()
+    } else {
+        stem
+    }
+}
+
+#[get("/api/conversations/search")]
+async fn search_conversations(
+    query: web::Query<SearchQuery>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Query parameter q must not be empty"
+        })));
+    }
+
+    let pattern = RegexBuilder::new(&regex::escape(q))
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| {
+            log::error!("Failed to build search pattern: {}", e);
+            actix_web::error::ErrorInternalServerError("Failed to search conversations")
+        })?;
+    let limit = query.limit.unwrap_or(MAX_SEARCH_RESULTS).clamp(1, MAX_SEARCH_RESULTS);
+    let results = app_state.conversations.search(&pattern, limit);
+
+    Ok(HttpResponse::Ok().json(SearchResults {
+        query: q.to_string(),
+        results,
+    }))
+}
+
+#[delete("/api/conversations/{id}")]
+async fn delete_conversation(
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    request_id: web::ReqData<RequestId>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let conversation_id = path.into_inner();
+    match app_state.conversations.delete(&conversation_id) {
+        Some(message_count) => {
+            log::info!(
+                target: "audit",
+                "Deleted conversation {} ({} messages)",
+                conversation_id,
+                message_count
+            );
+            app_state.audit(AuditEntry {
+                event: "conversation.deleted",
+                request_id: Some(request_id.0.clone()),
+                subject: None,
+                client_ip: client_ip::client_ip(&app_state, &http_req),
+                details: serde_json::json!({
+                    "conversation_id": conversation_id,
+                    "message_count": message_count,
+                }),
+            });
+            Ok(HttpResponse::NoContent().finish())
+        }
+        None => Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Conversation not found"
+        }))),
+    }
+}
+
+#[delete("/api/conversations")]
+async fn delete_old_conversations(
+    http_req: HttpRequest,
+    query: web::Query<RetentionQuery>,
+    request_id: web::ReqData<RequestId>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = admin::authorize(&http_req, &app_state) {
+        return Ok(response);
+    }
+    // A zero age would match every conversation
+    let Some(max_age) = parse_age(&query.older_than).filter(|age| !age.is_zero()) else {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "older_than must be a non-zero duration such as 3600, 30m, 12h or 7d"
+        })));
+    };
+
+    let cutoff_ms = now_ms().saturating_sub(max_age.as_millis() as u64);
+    let deleted = app_state.conversations.delete_older_than(cutoff_ms);
+    log::info!(
+        target: "audit",
+        "Retention sweep older than {} deleted {} conversations: {:?}",
+        query.older_than,
+        deleted.len(),
+        deleted
+    );
+    app_state.audit(AuditEntry {
+        event: "conversations.retention_sweep",
+        request_id: Some(request_id.0.clone()),
+        subject: None,
+        client_ip: client_ip::client_ip(&app_state, &http_req),
+        details: serde_json::json!({
+            "older_than": query.older_than,
+            "conversation_ids": deleted,
+        }),
+    });
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "deleted": deleted.len()
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConversationStore, MemoryStore};
+    use regex::RegexBuilder;
+
+    #[test]
+    fn memory_store_round_trips_history() {
+        let store = MemoryStore::default();
+        let reply_id = store.append_turn("a", "Hello there", "Hi, how can I help?", None).unwrap();
+        store.append_turn("b", "Unrelated", "Ok", None).unwrap();
+
+        let history = store.load_history("a").unwrap();
+        let turns: Vec<(&str, &str)> = history.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(turns, [("user", "Hello there"), ("assistant", "Hi, how can I help?")]);
+        assert!(history[0].id < history[1].id);
+        assert!(history[0].created_at_ms <= history[1].created_at_ms);
+        assert_eq!(store.latest_message_id("a"), reply_id);
+        assert!(store.load_history("missing").is_none());
+
+        let (page, total, has_more) = store.page("a", 1, None).unwrap();
+        assert_eq!((page[0].content.as_str(), total, has_more), ("Hi, how can I help?", 2, true));
+    }
+
+    #[test]
+    fn memory_store_rejects_turns_on_stale_state() {
+        let store = MemoryStore::default();
+        let first = store.append_turn("a", "One", "Reply one", Some(0)).unwrap();
+        // Two tabs both saw `first`; only the first to finish is stored
+        let second = store.append_turn("a", "Two", "Reply two", Some(first)).unwrap();
+        let stale = store.append_turn("a", "Other tab", "Lost", Some(first)).unwrap_err();
+        assert_eq!(stale.latest_message_id, second);
+        assert_eq!(store.load_history("a").unwrap().len(), 4);
+        assert!(store.append_turn("new", "Hi", "Hello", Some(5)).is_err());
+        assert!(store.load_history("new").is_none());
+    }
+
+    #[test]
+    fn memory_store_replaces_only_the_closing_reply() {
+        let store = MemoryStore::default();
+        let first = store.append_turn("a", "One", "Reply one", None).unwrap();
+        let replaced = store.replace_last_reply("a", first, "Another reply").unwrap();
+        assert!(replaced > first);
+        let history = store.load_history("a").unwrap();
+        let turns: Vec<(&str, &str)> = history.iter().map(|m| (m.role.as_str(), m.content.as_str())).collect();
+        assert_eq!(turns, [("user", "One"), ("assistant", "Another reply")]);
+        // The reply it was generated for is gone
+        assert_eq!(store.replace_last_reply("a", first, "Lost").unwrap_err().latest_message_id, replaced);
+        assert!(store.replace_last_reply("missing", 1, "Lost").is_err());
+    }
+
+    #[test]
+    fn compressed_store_reads_back_and_searches() {
+        let store = MemoryStore {
+            compress: true,
+            ..MemoryStore::default()
+        };
+        let long = format!("{} needle {}", "lorem ipsum ".repeat(40), "dolor sit ".repeat(40));
+        store.append_turn("a", &long, "short reply", None).unwrap();
+        {
+            let conversations = store.conversations.lock().unwrap();
+            let messages = &conversations["a"].messages;
+            assert!(matches!(messages[0].content, super::Content::Zstd(_)));
+            assert!(matches!(messages[1].content, super::Content::Plain(_)));
+        }
+        assert_eq!(store.load_history("a").unwrap()[0].content, long);
+
+        let pattern = RegexBuilder::new("NEEDLE").case_insensitive(true).build().unwrap();
+        let hits = store.search(&pattern, 10);
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].snippets[0].contains("needle"));
+    }
+
+    #[test]
+    fn memory_store_search_and_delete() {
+        let store = MemoryStore::default();
+        store.append_turn("a", "The quick brown fox", "Jumps", None).unwrap();
+        store.append_turn("b", "A QUICK reply", "Sure", None).unwrap();
+        store.append_turn("c", "Nothing here", "Indeed", None).unwrap();
+
+        let pattern = RegexBuilder::new("quick").case_insensitive(true).build().unwrap();
+        let hits: Vec<String> = store.search(&pattern, 10).into_iter().map(|hit| hit.conversation_id).collect();
+        assert_eq!(hits, ["b", "a"]);
+
+        assert_eq!(store.delete("a"), Some(2));
+        assert_eq!(store.delete("a"), None);
+        assert!(store.load_history("a").is_none());
+    }
+
+    #[actix_web::test]
+    async fn retention_sweep_needs_the_admin_token_and_an_age() {
+        let endpoint = crate::upstream::LlmEndpoint::new("127.0.0.1:9", "chat");
+        let base_url = crate::test_server(endpoint, |app_state| {
+            app_state.admin_token = Some("secret".to_string());
+            app_state.conversations.append_turn("a", "Hello", "Hi", None).unwrap();
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let sweep = |older_than: &str, token: Option<&str>| {
+            let request = client.delete(format!("{}/api/conversations?older_than={}", base_url, older_than));
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+            .send()
+        };
+        assert_eq!(sweep("1s", None).await.unwrap().status(), 401);
+        assert_eq!(sweep("1s", Some("wrong")).await.unwrap().status(), 401);
+        for zero in ["0", "0s", "0d"] {
+            assert_eq!(sweep(zero, Some("secret")).await.unwrap().status(), 400);
+        }
+        let kept = sweep("1d", Some("secret")).await.unwrap().json::<serde_json::Value>().await.unwrap();
+        assert_eq!(kept["deleted"], 0);
+    }
+}
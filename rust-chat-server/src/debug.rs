@@ -0,0 +1,110 @@
+use std::sync::OnceLock;
+
+use crate::trace::TraceHeaders;
+use crate::upstream::{Completion, LlmEndpoint, Transport};
+
+// With DEBUG_ENDPOINT_ENABLED=true, a chat request with `"debug": true`
+// gets a `_debug` field showing the upstream exchange: the payload and
+// headers sent and the raw reply (the body, or each SSE data payload of a
+// streamed one). The bearer token and any header or JSON field whose name
+// looks like a credential are redacted. Never enable it in production:
+// prompts, system prompts included, are echoed to the client.
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        let enabled = std::env::var("DEBUG_ENDPOINT_ENABLED").map(|v| v == "true").unwrap_or(false);
+        if enabled {
+            log::warn!("DEBUG_ENDPOINT_ENABLED: chat requests may ask for the raw upstream exchange");
+        }
+        enabled
+    })
+}
+
+const REDACTED: &str = "[REDACTED]";
+const SECRET_HINTS: &[&str] = &["authorization", "token", "secret", "password", "key", "cookie", "credential", "credentials"];
+
+// By word, so `access_token`, `apiKey` and `X-Api-Key` are caught but
+// `max_tokens` and `prompt_tokens` are not.
+fn looks_secret(name: &str) -> bool {
+    name.to_ascii_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| SECRET_HINTS.iter().any(|hint| word.ends_with(hint)))
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if looks_secret(name) {
+                    *field = REDACTED.into();
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+// Raw payloads that aren't JSON are passed through as strings.
+fn raw_value(raw: &str) -> serde_json::Value {
+    let mut value = serde_json::from_str(raw).unwrap_or_else(|_| raw.into());
+    redact(&mut value);
+    value
+}
+
+pub fn exchange(
+    endpoint: &LlmEndpoint,
+    transport: Transport,
+    payload: &serde_json::Value,
+    trace: &TraceHeaders,
+    completion: &Completion,
+) -> serde_json::Value {
+    let mut headers = serde_json::Map::new();
+    headers.insert("authorization".to_string(), format!("Bearer {}", REDACTED).into());
+    for (name, value) in trace.pairs() {
+        let value = if looks_secret(name) { REDACTED } else { value };
+        headers.insert(name.to_string(), value.into());
+    }
+    let mut body = payload.clone();
+    redact(&mut body);
+    // As complete_streamed and open_stream send it
+    if let Transport::Streamed { .. } = transport {
+        headers.insert("accept".to_string(), "text/event-stream".into());
+        body["stream_options"] = serde_json::json!({ "include_usage": true });
+        body["stream"] = true.into();
+    }
+    let (transport, response) = match transport {
+        Transport::Buffered => ("buffered", completion.raw.first().map(|raw| raw_value(raw)).unwrap_or_default()),
+        Transport::Streamed { .. } => ("streamed", completion.raw.iter().map(|raw| raw_value(raw)).collect()),
+    };
+    serde_json::json!({
+        "endpoint": endpoint.name,
+        "url": endpoint.url,
+        "transport": transport,
+        "request": { "headers": headers, "body": body },
+        "response": response,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact;
+
+    #[test]
+    fn redacts_credential_fields_at_any_depth() {
+        let mut value = serde_json::json!({
+            "messages": [{ "role": "user", "content": "hi" }],
+            "extra": { "api_key": "sk-1", "headers": [{ "Authorization": "Bearer x" }] },
+            "max_tokens": 5,
+            "accessToken": "t"
+        });
+        redact(&mut value);
+        assert_eq!(value["extra"]["api_key"], "[REDACTED]");
+        assert_eq!(value["extra"]["headers"][0]["Authorization"], "[REDACTED]");
+        assert_eq!(value["messages"][0]["content"], "hi");
+        assert_eq!(value["max_tokens"], 5);
+        assert_eq!(value["accessToken"], "[REDACTED]");
+    }
+}
@@ -0,0 +1,135 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{count_upstream_error, upstream_failure};
+use crate::request_id::RequestId;
+use crate::trace::TraceHeaders;
+use crate::upstream::{self, LlmEndpoint};
+use crate::AppState;
+
+// Bulk embeddings through EMBEDDINGS_ENDPOINT, a serving endpoint on
+// DATABRICKS_HOST. Inputs are sent EMBEDDINGS_CHUNK_SIZE (default 32) at a
+// time with at most EMBEDDINGS_CONCURRENCY (default 4) chunks in flight.
+pub struct Embeddings {
+    endpoint: LlmEndpoint,
+    chunk_size: usize,
+    concurrency: usize,
+}
+
+const MAX_INPUTS: usize = 10_000;
+
+fn positive_from_env(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .map(|v| match v.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => panic!("{} must be a positive integer", name),
+        })
+        .unwrap_or(default)
+}
+
+impl Embeddings {
+    pub fn from_env(host: &str) -> Option<Self> {
+        let name = std::env::var("EMBEDDINGS_ENDPOINT").ok().filter(|name| !name.is_empty())?;
+        if !LlmEndpoint::valid_name(&name) {
+            panic!("EMBEDDINGS_ENDPOINT {:?} is not a valid endpoint name", name);
+        }
+        let embeddings = Self {
+            endpoint: LlmEndpoint::new(host, &name),
+            chunk_size: positive_from_env("EMBEDDINGS_CHUNK_SIZE", 32),
+            concurrency: positive_from_env("EMBEDDINGS_CONCURRENCY", 4),
+        };
+        log::info!(
+            "Embeddings served by {} ({} inputs per call, {} calls at a time)",
+            embeddings.endpoint.name,
+            embeddings.chunk_size,
+            embeddings.concurrency
+        );
+        Some(embeddings)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    inputs: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct FailedChunk {
+    // Positions in `inputs`
+    inputs: Vec<usize>,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    // One per input, in order; null where its chunk failed
+    embeddings: Vec<Option<Vec<f32>>>,
+    failed: Vec<FailedChunk>,
+}
+
+// A chunk that fails doesn't fail the batch; its inputs come back null and
+// are listed in `failed`. Only when every chunk fails is the request an error.
+#[post("/api/embeddings/batch")]
+async fn embed_batch(
+    http_req: HttpRequest,
+    req: web::Json<BatchRequest>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(embeddings) = &app_state.embeddings else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Embeddings are not enabled on this server"
+        })));
+    };
+    let inputs = req.into_inner().inputs;
+    if inputs.is_empty() || inputs.len() > MAX_INPUTS {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("inputs must hold between 1 and {} strings", MAX_INPUTS)
+        })));
+    }
+
+    let endpoint = &embeddings.endpoint;
+    let trace = TraceHeaders::for_request(&http_req, &request_id, app_state.trace_context, &app_state.forward_headers);
+    log::info!("Embedding {} inputs in chunks of {}", inputs.len(), embeddings.chunk_size);
+    let calls = inputs.chunks(embeddings.chunk_size).enumerate().map(|(i, chunk)| {
+        let (client, app_state, trace) = (&client, &app_state, &trace);
+        async move {
+            app_state.metrics.llm_requests.inc(&[&endpoint.name, "embeddings"]);
+            let result = upstream::embed(client, endpoint, &app_state.api_key, chunk, Some(trace)).await;
+            (i * embeddings.chunk_size, chunk.len(), result)
+        }
+    });
+    let mut results: Vec<_> = stream::iter(calls).buffer_unordered(embeddings.concurrency).collect().await;
+    results.sort_by_key(|(start, _, _)| *start);
+
+    let mut vectors = Vec::with_capacity(inputs.len());
+    let mut failed = Vec::new();
+    let mut first_error = None;
+    for (start, len, result) in results {
+        match result {
+            Ok(chunk) => vectors.extend(chunk.into_iter().map(Some)),
+            Err(e) => {
+                count_upstream_error(&app_state, &endpoint.name, &e);
+                log::warn!("Embedding inputs {}..{} failed: {}", start, start + len, e);
+                vectors.extend(std::iter::repeat_with(|| None).take(len));
+                failed.push(FailedChunk {
+                    inputs: (start..start + len).collect(),
+                    error: e.to_string(),
+                });
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if vectors.iter().all(Option::is_none) {
+        if let Some(e) = first_error {
+            return upstream_failure(e, &request_id);
+        }
+    }
+    Ok(HttpResponse::Ok().json(BatchResponse {
+        embeddings: vectors,
+        failed,
+    }))
+}
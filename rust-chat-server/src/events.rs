@@ -0,0 +1,114 @@
+use actix_web::web;
+use serde::Serialize;
+use std::net::IpAddr;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::audit::AuditEntry;
+use crate::usage::Usage;
+use crate::AppState;
+
+// Every successful /api/chat publishes a CompletionEvent here, and the
+// webhook, audit and token metrics consumers each read it on their own
+// task, off the request path. The channel holds COMPLETION_EVENTS_CAPACITY
+// (default 1024) events; a consumer that falls further behind loses the
+// oldest ones, with a warning and a count in completion_events_dropped_total.
+pub struct CompletionEvents {
+    sender: broadcast::Sender<CompletionEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CompletionEvent {
+    pub request_id: String,
+    pub conversation_id: Option<String>,
+    pub model: String,
+    pub route: &'static str,
+    pub usage: Option<Usage>,
+    pub finish_reason: Option<String>,
+    pub latency_ms: u64,
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+}
+
+impl CompletionEvents {
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("COMPLETION_EVENTS_CAPACITY")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("COMPLETION_EVENTS_CAPACITY must be a positive integer"),
+            })
+            .unwrap_or(1024);
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    // Never blocks; with no consumers the event is simply dropped.
+    pub fn publish(&self, event: CompletionEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+// Starts one task per configured consumer. The metrics consumer always runs.
+pub fn spawn_consumers(app_state: &web::Data<AppState>, client: &reqwest::Client) {
+    if app_state.completion_webhook.is_some() {
+        let client = client.clone();
+        consume(app_state, "webhook", move |app_state, event| {
+            if let Some(webhook) = &app_state.completion_webhook {
+                webhook.notify(&client, serde_json::json!({
+                    "event": "chat.completed",
+                    "request_id": event.request_id,
+                    "conversation_id": event.conversation_id,
+                    "model": event.model,
+                    "usage": event.usage,
+                    "latency_ms": event.latency_ms,
+                }));
+            }
+        });
+    }
+    if app_state.audit_log.is_some() {
+        consume(app_state, "audit", |app_state, event| {
+            app_state.audit(AuditEntry {
+                event: "chat.completed",
+                request_id: Some(event.request_id.clone()),
+                subject: None,
+                client_ip: event.client_ip,
+                details: serde_json::json!({
+                    "model": event.model,
+                    "route": event.route,
+                    "conversation_id": event.conversation_id,
+                    "usage": event.usage,
+                    "finish_reason": event.finish_reason,
+                    "latency_ms": event.latency_ms,
+                }),
+            });
+        });
+    }
+    consume(app_state, "metrics", |app_state, event| {
+        if let Some(usage) = &event.usage {
+            let tokens = &app_state.metrics.llm_tokens;
+            tokens.add(&[&event.model, "prompt"], usage.prompt_tokens);
+            tokens.add(&[&event.model, "completion"], usage.completion_tokens);
+        }
+    });
+}
+
+fn consume(
+    app_state: &web::Data<AppState>,
+    name: &'static str,
+    handle: impl Fn(&AppState, &CompletionEvent) + Send + 'static,
+) {
+    let mut receiver = app_state.completion_events.sender.subscribe();
+    let app_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => handle(&app_state, &event),
+                Err(RecvError::Lagged(dropped)) => {
+                    log::warn!("Completion event consumer {} fell behind, dropped {} events", name, dropped);
+                    app_state.metrics.completion_events_dropped.add(&[name], dropped);
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
@@ -0,0 +1,112 @@
+use rand::Rng;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::upstream::UpstreamError;
+
+// Failure injection for resilience testing. FAULT_INJECT holds
+// comma-separated settings, e.g. `error_rate=0.2,latency_ms=500`:
+//
+//   error_rate=<0..1>     share of upstream attempts failed with a synthetic 503
+//   latency_ms=<ms>       delay added before an attempt
+//   latency_rate=<0..1>   share of attempts delayed (default 1)
+//
+// Faults hit each chat attempt before it is sent, so retries, cooldowns and
+// fallbacks see them like real upstream failures. Release builds ignore
+// FAULT_INJECT unless FAULT_INJECT_ALLOW_RELEASE=true.
+#[derive(Debug)]
+pub struct FaultInjection {
+    error_rate: f64,
+    latency: Duration,
+    latency_rate: f64,
+}
+
+fn rate(setting: &str, value: &str) -> f64 {
+    match value.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+        _ => panic!("FAULT_INJECT: {} must be between 0 and 1", setting),
+    }
+}
+
+impl FaultInjection {
+    fn parse(spec: &str) -> Self {
+        let mut faults = Self {
+            error_rate: 0.0,
+            latency: Duration::ZERO,
+            latency_rate: 1.0,
+        };
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .unwrap_or_else(|| panic!("FAULT_INJECT: {:?} must look like key=value", setting));
+            match key.trim() {
+                "error_rate" => faults.error_rate = rate(key, value.trim()),
+                "latency_rate" => faults.latency_rate = rate(key, value.trim()),
+                "latency_ms" => {
+                    let ms = value.trim().parse().expect("FAULT_INJECT: latency_ms must be a whole number");
+                    faults.latency = Duration::from_millis(ms);
+                }
+                other => panic!("FAULT_INJECT: unknown setting {:?}; use error_rate, latency_ms or latency_rate", other),
+            }
+        }
+        faults
+    }
+
+    // Waits out any injected delay, then fails the attempt if it was picked.
+    pub async fn before_attempt(&self) -> Result<(), UpstreamError> {
+        let (delay, fail) = {
+            let mut rng = rand::thread_rng();
+            (
+                !self.latency.is_zero() && rng.gen::<f64>() < self.latency_rate,
+                rng.gen::<f64>() < self.error_rate,
+            )
+        };
+        if delay {
+            log::warn!("Injected fault: delaying upstream attempt by {} ms", self.latency.as_millis());
+            tokio::time::sleep(self.latency).await;
+        }
+        if fail {
+            log::warn!("Injected fault: failing upstream attempt");
+            return Err(UpstreamError::Status(
+                reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                "injected fault".to_string(),
+                None,
+                Vec::new(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// None unless FAULT_INJECT is set and allowed in this build.
+pub fn settings() -> Option<&'static FaultInjection> {
+    static SETTINGS: OnceLock<Option<FaultInjection>> = OnceLock::new();
+    SETTINGS
+        .get_or_init(|| {
+            let spec = std::env::var("FAULT_INJECT").ok().filter(|s| !s.is_empty())?;
+            let allowed = cfg!(debug_assertions)
+                || std::env::var("FAULT_INJECT_ALLOW_RELEASE").map(|v| v == "true").unwrap_or(false);
+            if !allowed {
+                log::error!("FAULT_INJECT is ignored in release builds without FAULT_INJECT_ALLOW_RELEASE=true");
+                return None;
+            }
+            let faults = FaultInjection::parse(&spec);
+            log::warn!("Injecting upstream faults: {:?}", faults);
+            Some(faults)
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FaultInjection;
+    use std::time::Duration;
+
+    #[test]
+    fn settings_parse_with_defaults() {
+        let faults = FaultInjection::parse("error_rate=0.2, latency_ms=500");
+        assert_eq!(faults.error_rate, 0.2);
+        assert_eq!(faults.latency, Duration::from_millis(500));
+        assert_eq!(faults.latency_rate, 1.0);
+    }
+}
@@ -0,0 +1,86 @@
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::conversations::{now_ms, Feedback, FeedbackCounts, Rating};
+use crate::AppState;
+
+const MAX_COMMENT_CHARS: usize = 2000;
+
+#[derive(Debug, Deserialize)]
+struct FeedbackRequest {
+    conversation_id: String,
+    message_id: u64,
+    rating: Rating,
+    comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsQuery {
+    // Unix epoch milliseconds; the range defaults to everything up to now
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct FeedbackStats {
+    since: u64,
+    until: u64,
+    #[serde(flatten)]
+    counts: FeedbackCounts,
+    total: u64,
+}
+
+#[post("/api/feedback")]
+async fn submit_feedback(
+    req: web::Json<FeedbackRequest>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let req = req.into_inner();
+    let comment = req
+        .comment
+        .map(|c| c.trim().chars().take(MAX_COMMENT_CHARS).collect::<String>())
+        .filter(|c| !c.is_empty());
+
+    let feedback = Feedback {
+        message_id: req.message_id,
+        rating: req.rating,
+        comment,
+        created_at_ms: now_ms(),
+    };
+    if !app_state.conversations.add_feedback(&req.conversation_id, feedback) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Unknown conversation_id or message_id"
+        })));
+    }
+
+    log::info!(
+        "Recorded {:?} feedback for message {} in conversation {}",
+        req.rating,
+        req.message_id,
+        req.conversation_id
+    );
+    Ok(HttpResponse::Created().json(serde_json::json!({ "status": "recorded" })))
+}
+
+#[get("/api/feedback/stats")]
+async fn feedback_stats(
+    query: web::Query<StatsQuery>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let since = query.since.unwrap_or(0);
+    let until = query.until.unwrap_or_else(|| now_ms() + 1);
+    if since >= until {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "since must be earlier than until"
+        })));
+    }
+
+    let counts = app_state.conversations.feedback_counts(since, until);
+    let total = counts.up + counts.down;
+    Ok(HttpResponse::Ok().json(FeedbackStats {
+        since,
+        until,
+        counts,
+        total,
+    }))
+}
@@ -0,0 +1,97 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::upstream::LlmEndpoint;
+use crate::AppState;
+
+// Per-endpoint status for /health. Every configured serving endpoint (the
+// primary or each region, canary, shadow and filter fallback) gets a HEAD
+// probe like the region prober's; results are reused for
+// HEALTH_PROBE_CACHE_SECS (default 15) so polling /health doesn't turn into
+// upstream traffic. Concurrent callers share one probe round.
+pub struct EndpointHealth {
+    ttl: Duration,
+    last: Mutex<Option<(Instant, Vec<EndpointStatus>)>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointStatus {
+    name: String,
+    pub healthy: bool,
+    latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl EndpointHealth {
+    pub fn from_env() -> Self {
+        let secs: u64 = std::env::var("HEALTH_PROBE_CACHE_SECS")
+            .ok()
+            .map(|v| v.parse().expect("HEALTH_PROBE_CACHE_SECS must be a whole number of seconds"))
+            .unwrap_or(15);
+        Self {
+            ttl: Duration::from_secs(secs),
+            last: Mutex::new(None),
+        }
+    }
+
+    pub async fn statuses(&self, app_state: &AppState, client: &reqwest::Client) -> Vec<EndpointStatus> {
+        let mut last = self.last.lock().await;
+        if let Some((at, statuses)) = last.as_ref() {
+            if at.elapsed() < self.ttl {
+                return statuses.clone();
+            }
+        }
+        let endpoints = endpoints(app_state);
+        let probes = endpoints.iter().map(|endpoint| probe(client, endpoint, &app_state.api_key));
+        let statuses = futures::future::join_all(probes).await;
+        *last = Some((Instant::now(), statuses.clone()));
+        statuses
+    }
+}
+
+pub fn endpoints(app_state: &AppState) -> Vec<LlmEndpoint> {
+    let mut endpoints: Vec<LlmEndpoint> = match &app_state.regions {
+        Some(regions) => regions.endpoints().cloned().collect(),
+        None => vec![app_state.primary_endpoint()],
+    };
+    let others = [
+        app_state.canary.as_ref().map(|canary| &canary.endpoint),
+        app_state.shadow.as_ref().map(|shadow| &shadow.endpoint),
+        app_state.filter_fallback.as_ref(),
+    ];
+    for endpoint in others.into_iter().flatten() {
+        if !endpoints.iter().any(|e| e.url == endpoint.url) {
+            endpoints.push(endpoint.clone());
+        }
+    }
+    endpoints
+}
+
+// Any answer short of a server error means the endpoint is up.
+async fn probe(client: &reqwest::Client, endpoint: &LlmEndpoint, api_key: &str) -> EndpointStatus {
+    let started = Instant::now();
+    let result = client
+        .head(&endpoint.url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let (healthy, error) = match result {
+        Ok(response) if response.status().is_server_error() => {
+            (false, Some(format!("HTTP {}", response.status().as_u16())))
+        }
+        Ok(_) => (true, None),
+        Err(e) => (false, Some(e.to_string())),
+    };
+    EndpointStatus {
+        name: endpoint.name.clone(),
+        healthy,
+        latency_ms: healthy.then_some(latency_ms),
+        error,
+    }
+}
@@ -0,0 +1,132 @@
+use actix_web::body::{self, MessageBody};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const HEADER: &str = "idempotency-key";
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+// Remembers chat responses by client-supplied Idempotency-Key for
+// IDEMPOTENCY_TTL_SECS (default 600, 0 disables). A retry with the same
+// key gets the stored status, headers and body instead of a second LLM
+// call. Server errors and 429s are not stored, so retrying those re-runs
+// the call.
+pub struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+enum Entry {
+    InFlight(Instant),
+    Done(Instant, StoredResponse),
+}
+
+#[derive(Clone)]
+pub struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    // Buffers the response body so it can be both stored and returned.
+    pub async fn capture(response: HttpResponse) -> Self {
+        let (head, response_body) = response.into_parts();
+        let body = body::to_bytes(response_body.boxed()).await.unwrap_or_default();
+        Self {
+            status: head.status(),
+            headers: head.headers().clone(),
+            body,
+        }
+    }
+
+    pub fn to_response(&self, replayed: bool) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        for (name, value) in self.headers.iter() {
+            response.append_header((name.clone(), value.clone()));
+        }
+        if replayed {
+            response.insert_header((REPLAYED_HEADER, "true"));
+        }
+        response.body(self.body.clone())
+    }
+}
+
+pub enum Begin<'a> {
+    // First use of the key; the claim releases it if dropped unfinished
+    New(Claim<'a>),
+    InProgress,
+    Replay(StoredResponse),
+}
+
+pub struct Claim<'a> {
+    store: &'a IdempotencyStore,
+    key: String,
+    finished: bool,
+}
+
+impl Claim<'_> {
+    pub fn finish(mut self, stored: &StoredResponse) {
+        self.finished = true;
+        let mut entries = self.store.entries.lock().unwrap();
+        if stored.status.is_server_error() || stored.status == StatusCode::TOO_MANY_REQUESTS {
+            entries.remove(&self.key);
+        } else {
+            entries.insert(self.key.clone(), Entry::Done(Instant::now(), stored.clone()));
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.store.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+impl IdempotencyStore {
+    pub fn from_env() -> Option<Self> {
+        let ttl_secs: u64 = std::env::var("IDEMPOTENCY_TTL_SECS")
+            .ok()
+            .map(|v| v.parse().expect("IDEMPOTENCY_TTL_SECS must be a whole number of seconds"))
+            .unwrap_or(600);
+        (ttl_secs > 0).then(|| Self {
+            ttl: Duration::from_secs(ttl_secs),
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn begin(&self, key: &str) -> Begin<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| match entry {
+            Entry::InFlight(at) | Entry::Done(at, _) => at.elapsed() < self.ttl,
+        });
+        match entries.get(key) {
+            Some(Entry::InFlight(_)) => Begin::InProgress,
+            Some(Entry::Done(_, stored)) => Begin::Replay(stored.clone()),
+            None => {
+                entries.insert(key.to_string(), Entry::InFlight(Instant::now()));
+                Begin::New(Claim {
+                    store: self,
+                    key: key.to_string(),
+                    finished: false,
+                })
+            }
+        }
+    }
+}
+
+// Valid keys are 1-255 visible ASCII characters.
+pub fn key_from(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty() && key.len() <= 255 && key.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+}
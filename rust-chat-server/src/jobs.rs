@@ -0,0 +1,154 @@
+use actix_web::body::{self, MessageBody};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::chat::{self, ChatRequest};
+use crate::conversations::now_ms;
+use crate::request_id::RequestId;
+use crate::AppState;
+
+// Background chats for clients behind proxies that cut long requests off.
+// POST /api/chat/async answers 202 with a job id right away and runs the
+// /api/chat pipeline in the background; GET /api/chat/async/{job_id}
+// reports progress and, once finished, the status and body /api/chat would
+// have answered with. Finished jobs are kept for CHAT_JOB_TTL_SECS (default
+// 3600), in memory only.
+pub struct ChatJobs {
+    ttl: Duration,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+// Jobs held at once, finished or not
+const MAX_JOBS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JobResult {
+    status: u16,
+    body: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Job {
+    job_id: String,
+    status: JobState,
+    created_at_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<JobResult>,
+    #[serde(skip)]
+    finished: Option<Instant>,
+}
+
+impl ChatJobs {
+    pub fn from_env() -> Self {
+        let ttl_secs: u64 = std::env::var("CHAT_JOB_TTL_SECS")
+            .ok()
+            .map(|v| v.parse().expect("CHAT_JOB_TTL_SECS must be a whole number of seconds"))
+            .unwrap_or(3600);
+        Self {
+            ttl: Duration::from_secs(ttl_secs),
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // None when the store is full of jobs that haven't expired yet.
+    fn create(&self) -> Option<String> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| job.finished.is_none_or(|at| at.elapsed() < self.ttl));
+        if jobs.len() >= MAX_JOBS {
+            return None;
+        }
+        let job_id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        jobs.insert(
+            job_id.clone(),
+            Job {
+                job_id: job_id.clone(),
+                status: JobState::Pending,
+                created_at_ms: now_ms(),
+                finished_at_ms: None,
+                result: None,
+                finished: None,
+            },
+        );
+        Some(job_id)
+    }
+
+    fn get(&self, job_id: &str) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(job_id)
+            .filter(|job| job.finished.is_none_or(|at| at.elapsed() < self.ttl))
+            .cloned()
+    }
+
+    fn update(&self, job_id: &str, status: JobState, result: Option<JobResult>) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            job.status = status;
+            if result.is_some() {
+                job.result = result;
+                job.finished = Some(Instant::now());
+                job.finished_at_ms = Some(now_ms());
+            }
+        }
+    }
+}
+
+#[post("/api/chat/async")]
+async fn chat_async(
+    http_req: HttpRequest,
+    req: web::Json<ChatRequest>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let Some(job_id) = app_state.chat_jobs.create() else {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "Too many chat jobs; try again later"
+        }));
+    };
+    log::info!("Queued chat job {} for request {}", job_id, request_id.0);
+    let location = format!("/api/chat/async/{}", job_id);
+    let job = job_id.clone();
+    let (req, request_id) = (req.into_inner(), request_id.into_inner());
+    actix_web::rt::spawn(async move {
+        let jobs = &app_state.chat_jobs;
+        jobs.update(&job, JobState::Running, None);
+        let response = match chat::complete_chat(http_req, req, request_id, client, app_state.clone()).await {
+            Ok(response) => response,
+            Err(e) => e.error_response(),
+        };
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body().boxed()).await.unwrap_or_default();
+        let body = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        let state = if status.is_success() { JobState::Done } else { JobState::Failed };
+        log::info!("Chat job {} finished with status {}", job, status.as_u16());
+        jobs.update(&job, state, Some(JobResult { status: status.as_u16(), body }));
+    });
+    HttpResponse::Accepted()
+        .insert_header(("Location", location))
+        .json(serde_json::json!({ "job_id": job_id, "status": JobState::Pending }))
+}
+
+#[get("/api/chat/async/{job_id}")]
+async fn chat_job(path: web::Path<String>, app_state: web::Data<AppState>) -> HttpResponse {
+    match app_state.chat_jobs.get(&path) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Unknown or expired job"
+        })),
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use crate::upstream::LlmEndpoint;
+
+// Detects the language of chat messages when DETECT_LANGUAGE=true or
+// LANG_ROUTING is set. LANG_ROUTING ("es=chat-es,ja=chat-ja") sends
+// messages in those languages to the named endpoints on DATABRICKS_HOST.
+//
+// Detection is a cheap heuristic: the dominant Unicode script decides for
+// non-Latin text (so any Cyrillic reports `ru` and Arabic script `ar`), and
+// Latin-script text is scored on common words of a handful of European
+// languages. Short or ambiguous messages report nothing.
+pub struct LanguageDetection {
+    routes: HashMap<&'static str, LlmEndpoint>,
+}
+
+const MIN_LETTERS: usize = 3;
+
+const STOPWORDS: &[(&str, &[&str])] = &[
+    ("en", &["the", "and", "is", "are", "you", "what", "how", "of", "to", "it", "this", "that", "with", "for", "can", "please", "my"]),
+    ("es", &["el", "los", "las", "que", "y", "es", "por", "para", "con", "una", "cómo", "qué", "está", "hola", "gracias", "mi"]),
+    ("fr", &["le", "les", "des", "et", "est", "une", "pour", "pas", "vous", "je", "comment", "bonjour", "avec", "merci", "mon"]),
+    ("de", &["der", "die", "das", "und", "ist", "nicht", "ich", "du", "sie", "mit", "wie", "ein", "eine", "was", "bitte", "für"]),
+    ("it", &["il", "lo", "di", "che", "è", "per", "non", "come", "sono", "ciao", "grazie", "mi", "gli", "della"]),
+    ("pt", &["o", "os", "que", "é", "não", "um", "uma", "para", "com", "como", "você", "olá", "obrigado", "meu"]),
+    ("nl", &["het", "een", "en", "is", "van", "niet", "ik", "je", "wat", "hoe", "met", "voor", "dat", "zijn", "bedankt"]),
+];
+
+impl LanguageDetection {
+    pub fn from_env(host: &str) -> Option<Self> {
+        let routing = std::env::var("LANG_ROUTING").unwrap_or_default();
+        let detect = std::env::var("DETECT_LANGUAGE").map(|v| v == "true").unwrap_or(false);
+        if !detect && routing.trim().is_empty() {
+            return None;
+        }
+        let mut routes = HashMap::new();
+        for entry in routing.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (language, endpoint) = entry
+                .split_once('=')
+                .map(|(l, e)| (l.trim(), e.trim()))
+                .filter(|(_, e)| LlmEndpoint::valid_name(e))
+                .expect("LANG_ROUTING must look like es=endpoint-name,ja=other-endpoint");
+            let language = known_code(language)
+                .unwrap_or_else(|| panic!("LANG_ROUTING: unsupported language {:?}", language));
+            routes.insert(language, LlmEndpoint::new(host, endpoint));
+        }
+        log::info!("Detecting message language, routing {:?}", routes.keys().collect::<Vec<_>>());
+        Some(Self { routes })
+    }
+
+    pub fn route(&self, language: &str) -> Option<&LlmEndpoint> {
+        self.routes.get(language)
+    }
+}
+
+// Every code `detect` can return
+const SCRIPT_CODES: &[&str] = &["ja", "zh", "ko", "ru", "ar", "he", "el", "hi", "th"];
+
+fn known_code(code: &str) -> Option<&'static str> {
+    let code = code.to_ascii_lowercase();
+    SCRIPT_CODES
+        .iter()
+        .copied()
+        .chain(STOPWORDS.iter().map(|(language, _)| *language))
+        .find(|known| *known == code)
+}
+
+// ISO 639-1 code of the text's language, if it is clear enough.
+pub fn detect(text: &str) -> Option<&'static str> {
+    let mut scripts: HashMap<&'static str, usize> = HashMap::new();
+    let mut latin = 0;
+    let mut letters = 0;
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        match script_language(c) {
+            Some(language) => *scripts.entry(language).or_default() += 1,
+            None => latin += 1,
+        }
+    }
+    if letters < MIN_LETTERS {
+        return None;
+    }
+    // Japanese mixes kana with kanji, which alone would read as Chinese
+    if scripts.get("ja").is_some_and(|&kana| kana > 0) {
+        let kanji = scripts.remove("zh").unwrap_or_default();
+        *scripts.entry("ja").or_default() += kanji;
+    }
+    let dominant = scripts.into_iter().max_by_key(|(_, count)| *count);
+    match dominant {
+        Some((language, count)) if count > latin => Some(language),
+        _ => latin_language(text),
+    }
+}
+
+fn script_language(c: char) -> Option<&'static str> {
+    let language = match c as u32 {
+        0x3040..=0x30FF | 0x31F0..=0x31FF => "ja",
+        0x4E00..=0x9FFF | 0x3400..=0x4DBF => "zh",
+        0xAC00..=0xD7AF | 0x1100..=0x11FF | 0x3130..=0x318F => "ko",
+        0x0400..=0x04FF => "ru",
+        0x0600..=0x06FF | 0x0750..=0x077F => "ar",
+        0x0590..=0x05FF => "he",
+        0x0370..=0x03FF => "el",
+        0x0900..=0x097F => "hi",
+        0x0E00..=0x0E7F => "th",
+        _ => return None,
+    };
+    Some(language)
+}
+
+// Scores common words; None without a single clear winner.
+fn latin_language(text: &str) -> Option<&'static str> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let mut scores: Vec<(&'static str, usize)> = STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| {
+            let hits = words.iter().filter(|w| stopwords.contains(&w.as_str())).count();
+            (*language, hits)
+        })
+        .collect();
+    scores.sort_by_key(|(_, hits)| std::cmp::Reverse(*hits));
+    match scores.as_slice() {
+        [(language, best), (_, runner_up), ..] if *best > 0 && best > runner_up => Some(language),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect;
+
+    #[test]
+    fn detects_by_script_and_common_words() {
+        assert_eq!(detect("What is the capital of France?"), Some("en"));
+        assert_eq!(detect("¿Cuál es la capital de España? Gracias"), Some("es"));
+        assert_eq!(detect("Wie spät ist es, bitte?"), Some("de"));
+        assert_eq!(detect("Bonjour, comment allez-vous ?"), Some("fr"));
+        assert_eq!(detect("東京の天気はどうですか"), Some("ja"));
+        assert_eq!(detect("今天天气怎么样"), Some("zh"));
+        assert_eq!(detect("Привет, как дела?"), Some("ru"));
+        assert_eq!(detect("ok"), None);
+        assert_eq!(detect("Kubernetes Terraform"), None);
+    }
+}
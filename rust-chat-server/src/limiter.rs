@@ -0,0 +1,317 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Caps concurrent upstream chat calls with an AIMD limit. Every
+// SAMPLE_WINDOW completed calls the p95 latency is compared to the SLO:
+// under it the limit grows by one, over it the limit is cut by a fifth.
+// A free slot goes to the highest-priority waiter; lower lanes only get one
+// while no higher-priority call is queued. With MAX_QUEUED_LLM set, calls
+// arriving to a queue that long are shed with an estimate of when to retry.
+pub struct AdaptiveLimiter {
+    target_latency: Duration,
+    min_limit: usize,
+    max_limit: usize,
+    max_queue: Option<usize>,
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+struct LimiterState {
+    limit: usize,
+    in_flight: usize,
+    samples: VecDeque<Duration>,
+    // Calls waiting for a slot, indexed by Priority
+    waiting: [usize; Priority::ALL.len()],
+    // EWMA of call latency, for Retry-After estimates
+    mean_latency: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+}
+
+const SAMPLE_WINDOW: usize = 20;
+const BACKOFF_FACTOR: f64 = 0.8;
+const LATENCY_ALPHA: f64 = 0.2;
+// Bounds of the Retry-After given to shed calls
+const MIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+fn usize_from_env(key: &str, default: usize) -> usize {
+    env::var(key)
+        .ok()
+        .map(|v| v.parse().unwrap_or_else(|_| panic!("{} must be a positive integer", key)))
+        .unwrap_or(default)
+}
+
+impl AdaptiveLimiter {
+    // Enabled by LLM_LATENCY_SLO_MS; MAX_CONCURRENT_LLM (default 64) and
+    // MIN_CONCURRENT_LLM (default 1) bound the limit.
+    pub fn from_env() -> Option<Self> {
+        let target_ms: u64 = env::var("LLM_LATENCY_SLO_MS")
+            .ok()
+            .map(|v| v.parse().expect("LLM_LATENCY_SLO_MS must be a number"))?;
+        let max_limit = usize_from_env("MAX_CONCURRENT_LLM", 64);
+        let min_limit = usize_from_env("MIN_CONCURRENT_LLM", 1);
+        if min_limit == 0 || min_limit > max_limit {
+            panic!("MIN_CONCURRENT_LLM must be between 1 and MAX_CONCURRENT_LLM");
+        }
+        let max_queue = env::var("MAX_QUEUED_LLM").ok().map(|v| match v.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => panic!("MAX_QUEUED_LLM must be a positive integer"),
+        });
+        let limit = max_limit.min(min_limit.max(8));
+        log::info!(
+            "Adaptive concurrency limit starting at {} ({}..={}), p95 target {} ms",
+            limit,
+            min_limit,
+            max_limit,
+            target_ms
+        );
+        Some(Self {
+            target_latency: Duration::from_millis(target_ms),
+            min_limit,
+            max_limit,
+            max_queue,
+            state: Mutex::new(LimiterState {
+                limit,
+                in_flight: 0,
+                samples: VecDeque::with_capacity(SAMPLE_WINDOW),
+                waiting: [0; Priority::ALL.len()],
+                mean_latency: None,
+            }),
+            released: Notify::new(),
+        })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    pub fn waiting(&self, priority: Priority) -> usize {
+        self.state.lock().unwrap().waiting[priority as usize]
+    }
+
+    // Waits for a free slot not claimed by a higher-priority waiter. The
+    // call's latency is sampled when the permit is dropped. Err is a shed
+    // call, with how long the caller should wait before trying again.
+    pub async fn acquire(&self, priority: Priority) -> Result<Permit<'_>, Duration> {
+        let started = self.claim(priority).await?;
+        Ok(Permit { limiter: self, started })
+    }
+
+    // `acquire` for a slot held by a spawned task, such as a stream pump.
+    pub async fn acquire_owned(self: &Arc<Self>, priority: Priority) -> Result<OwnedPermit, Duration> {
+        let started = self.claim(priority).await?;
+        Ok(OwnedPermit {
+            limiter: self.clone(),
+            started,
+        })
+    }
+
+    // Takes a slot, returning when it was taken.
+    async fn claim(&self, priority: Priority) -> Result<Instant, Duration> {
+        let mut queued: Option<Queued<'_>> = None;
+        loop {
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                let ahead: usize = state.waiting[..priority as usize].iter().sum();
+                if state.in_flight < state.limit && ahead == 0 {
+                    state.in_flight += 1;
+                    drop(state);
+                    drop(queued);
+                    return Ok(Instant::now());
+                }
+                let queue: usize = state.waiting.iter().sum();
+                if queued.is_none() && self.max_queue.is_some_and(|max| queue >= max) {
+                    return Err(self.retry_after(&state, queue));
+                }
+                if queued.is_none() {
+                    state.waiting[priority as usize] += 1;
+                    queued = Some(Queued { limiter: self, priority });
+                }
+            }
+            released.await;
+        }
+    }
+
+    // Time for the queue ahead to drain: one mean call latency per `limit`
+    // queued calls, bounded to something a client can act on.
+    fn retry_after(&self, state: &LimiterState, queue: usize) -> Duration {
+        let per_wave = state.mean_latency.unwrap_or(self.target_latency);
+        let waves = (queue / state.limit.max(1) + 1) as u32;
+        per_wave.saturating_mul(waves).clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER)
+    }
+
+    fn release(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+        state.mean_latency = Some(match state.mean_latency {
+            Some(mean) => mean.mul_f64(1.0 - LATENCY_ALPHA) + latency.mul_f64(LATENCY_ALPHA),
+            None => latency,
+        });
+        state.samples.push_back(latency);
+        if state.samples.len() >= SAMPLE_WINDOW {
+            let mut window: Vec<Duration> = state.samples.drain(..).collect();
+            window.sort();
+            let p95 = window[(window.len() * 95 / 100).min(window.len() - 1)];
+            let previous = state.limit;
+            state.limit = if p95 > self.target_latency {
+                ((previous as f64 * BACKOFF_FACTOR) as usize).max(self.min_limit)
+            } else {
+                (previous + 1).min(self.max_limit)
+            };
+            if state.limit != previous {
+                log::info!(
+                    "Concurrency limit {} -> {} (p95 {} ms)",
+                    previous,
+                    state.limit,
+                    p95.as_millis()
+                );
+            }
+        }
+        drop(state);
+        // Everyone re-checks, so the slot goes to the right lane
+        self.released.notify_waiters();
+    }
+}
+
+impl AdaptiveLimiter {
+    // Immediate decrease for an explicit overload signal such as a 429,
+    // without waiting for the latency window.
+    pub fn back_off(&self) {
+        let mut state = self.state.lock().unwrap();
+        let previous = state.limit;
+        state.limit = ((previous as f64 * BACKOFF_FACTOR) as usize).max(self.min_limit);
+        if state.limit != previous {
+            log::info!("Concurrency limit {} -> {} (upstream rate limit)", previous, state.limit);
+        }
+    }
+}
+
+pub struct Permit<'a> {
+    limiter: &'a AdaptiveLimiter,
+    started: Instant,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(self.started.elapsed());
+    }
+}
+
+pub struct OwnedPermit {
+    limiter: Arc<AdaptiveLimiter>,
+    started: Instant,
+}
+
+impl Drop for OwnedPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.started.elapsed());
+    }
+}
+
+// Counts a call in its lane's queue, including one abandoned mid-wait.
+struct Queued<'a> {
+    limiter: &'a AdaptiveLimiter,
+    priority: Priority,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().waiting[self.priority as usize] -= 1;
+        // Lower lanes may have been waiting on this call
+        self.limiter.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdaptiveLimiter, LimiterState, Priority, SAMPLE_WINDOW};
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    fn limiter(max_queue: Option<usize>) -> AdaptiveLimiter {
+        AdaptiveLimiter {
+            target_latency: Duration::from_secs(1),
+            min_limit: 1,
+            max_limit: 1,
+            max_queue,
+            state: Mutex::new(LimiterState {
+                limit: 1,
+                in_flight: 0,
+                samples: VecDeque::with_capacity(SAMPLE_WINDOW),
+                waiting: [0; 3],
+                mean_latency: None,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn free_slot_goes_to_the_highest_priority_waiter() {
+        let limiter = Rc::new(limiter(None));
+        let order = Rc::new(Mutex::new(Vec::new()));
+        let permit = limiter.acquire(Priority::Normal).await.unwrap();
+        let mut waiters = Vec::new();
+        for priority in [Priority::Low, Priority::High, Priority::Normal] {
+            let (limiter, order) = (limiter.clone(), order.clone());
+            waiters.push(actix_web::rt::spawn(async move {
+                let _permit = limiter.acquire(priority).await.unwrap();
+                order.lock().unwrap().push(priority);
+            }));
+            actix_web::rt::task::yield_now().await;
+        }
+        assert_eq!(limiter.waiting(Priority::Low), 1);
+        drop(permit);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), [Priority::High, Priority::Normal, Priority::Low]);
+        assert_eq!(limiter.waiting(Priority::High), 0);
+    }
+
+    #[actix_web::test]
+    async fn full_queue_sheds_with_a_bounded_retry_after() {
+        let limiter = Rc::new(limiter(Some(1)));
+        let _permit = limiter.acquire(Priority::Normal).await.unwrap();
+        let queued = {
+            let limiter = limiter.clone();
+            actix_web::rt::spawn(async move { limiter.acquire(Priority::Low).await.is_ok() })
+        };
+        actix_web::rt::task::yield_now().await;
+        // One call queued ahead at the 1s SLO: one wave in flight, one queued
+        let retry_after = limiter.acquire(Priority::High).await.err();
+        assert_eq!(retry_after, Some(Duration::from_secs(2)));
+        queued.abort();
+    }
+}
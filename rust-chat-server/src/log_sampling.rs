@@ -0,0 +1,61 @@
+use log::{Level, Log, Metadata, Record};
+use rand::Rng;
+use std::future::Future;
+
+// LOG_SAMPLE_RATE (default 1) is the share of requests whose INFO and DEBUG
+// lines are logged. Warnings and errors are always logged, and so is the
+// access line of any request answered with 400 or above. Audit lines and
+// background tasks (stream pumps, probes) are never sampled.
+tokio::task_local! {
+    static SAMPLED: bool;
+}
+
+pub fn rate_from_env() -> f64 {
+    let rate = std::env::var("LOG_SAMPLE_RATE")
+        .ok()
+        .map(|v| v.parse::<f64>().expect("LOG_SAMPLE_RATE must be a number"))
+        .unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&rate) {
+        panic!("LOG_SAMPLE_RATE must be between 0 and 1");
+    }
+    rate
+}
+
+pub fn sample(rate: f64) -> bool {
+    rate >= 1.0 || rand::thread_rng().gen::<f64>() < rate
+}
+
+// Runs a request's handler with its sampling decision in scope.
+pub fn scope<F: Future>(sampled: bool, fut: F) -> impl Future<Output = F::Output> {
+    SAMPLED.scope(sampled, fut)
+}
+
+// env_logger, minus the low-level lines of unsampled requests
+struct SampledLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for SampledLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let droppable = record.level() > Level::Warn && record.target() != "audit";
+        if droppable && SAMPLED.try_with(|sampled| !*sampled).unwrap_or(false) {
+            return;
+        }
+        self.inner.log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+// In place of env_logger::init(); same RUST_LOG handling.
+pub fn init() {
+    let inner = env_logger::Builder::from_default_env().build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(SampledLogger { inner })).expect("logger already initialized");
+}
@@ -1,62 +1,93 @@
+// The hand-written OpenAPI spec is one large json! literal
+#![recursion_limit = "256"]
+
 use actix_cors::Cors;
 use actix_files::Files;
 use actix_web::{
-    get, post, web, App, HttpResponse, HttpServer, Responder,
-    middleware::Logger,
-    HttpRequest,
+    get, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+    body::{BodySize, MessageBody},
+    dev::{Server, Service},
+    error::JsonPayloadError,
+    http::{header::{HeaderName, HeaderValue, CONTENT_TYPE}, KeepAlive},
+    middleware::DefaultHeaders,
 };
 use serde::{Deserialize, Serialize};
-use std::{env, path::PathBuf, time::Duration, time::Instant};
-use std::process::Command;
+use std::{env, net::TcpListener, path::{Path, PathBuf}, time::{Duration, Instant}};
 use std::str;
-use futures::future::join_all;
+use futures::future::{join_all, FutureExt};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, RwLock};
+
+mod admin;
+mod audit;
+mod bench;
+mod budget;
+mod cache;
+mod cancel;
+mod chat;
+mod client_ip;
+mod conversations;
+mod debug;
+mod embeddings;
+mod events;
+mod faults;
+mod feedback;
+mod health;
+mod idempotency;
+mod injection;
+mod jobs;
+mod language;
+mod limiter;
+mod log_sampling;
+mod metrics;
+mod openapi;
+mod panic;
+mod postprocess;
+mod recent;
+mod regions;
+mod request_id;
+mod resume;
+mod sanitize;
+mod schema;
+mod statsd;
+mod stream;
+mod templates;
+mod tokens;
+mod turns;
+mod trace;
+mod upstream;
+mod usage;
+mod version;
+mod warmup;
+mod watchdog;
+mod ws;
+mod webhook;
+
+use audit::{AuditEntry, AuditLog};
+use budget::TokenBudget;
+use cache::ResponseCache;
+use chat::{GenerationDefaults, HistoryLimit, SystemPrompt};
+use client_ip::ProxyTrust;
+use conversations::ConversationStore;
+use idempotency::IdempotencyStore;
+use limiter::AdaptiveLimiter;
+use metrics::Metrics;
+use regions::RegionPool;
+use stream::StreamSettings;
+use templates::PromptTemplates;
+use tokens::TokenEstimator;
+use upstream::{Canary, Cooldowns, EndpointOverride, LlmEndpoint, RetryPolicy, Shadow, Transport};
+use usage::Pricing;
+use watchdog::StallWatchdog;
+use webhook::CompletionWebhook;
 
 // Equivalent struct definitions
-#[derive(Debug, Deserialize)]
-struct ChatRequest {
-    message: String,
-}
-
-#[derive(Debug, Serialize)]
-struct ChatResponse {
-    content: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct LLMResponse {
-    choices: Vec<Choice>,
-}
-
-#[derive(Debug, Deserialize)]
-struct Choice {
-    message: Message,
-}
-
-#[derive(Debug, Deserialize)]
-struct Message {
-    content: String,
-}
-
 #[derive(Debug, Deserialize)]
 struct LoadTestRequest {
     requests: u64,     // Total number of requests
     concurrency: u64,  // Concurrent users
-}
-
-#[derive(Debug, Serialize)]
-struct ResponseTime {
-    min: Duration,
-    max: Duration,
-    mean: Duration,
-    p95: Duration,
-    p99: Duration,
-}
-
-#[derive(Debug, Serialize)]
-struct ErrorDetail {
-    name: String,
-    count: i64,
-    error_type: String,
+    // Time the whole body download (default) or only until the response head
+    read_body: Option<bool>,
 }
 
 #[derive(Debug, Serialize)]
@@ -67,85 +98,79 @@ struct LoadTestResult {
     total_duration_ms: u64,
     average_response_ms: f64,
     requests_per_second: f64,
+    read_body: bool,
 }
 
-// API handlers
-#[get("/api")]
-async fn hello() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "message": "Welcome to the LLM Chat API"
-    }))
+const UI_PLACEHOLDER_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>LLM Chat API</title></head>
+<body>
+  <h1>LLM Chat API</h1>
+  <p>The web UI is not bundled with this server: no <code>client/build</code>
+  directory was found at startup. Build the client and restart to enable it.</p>
+  <p>The API is available; see <a href="/docs">/docs</a>.</p>
+</body>
+</html>
+"#;
+
+async fn ui_placeholder() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(UI_PLACEHOLDER_HTML)
 }
 
-#[post("/api/chat")]
-async fn chat_with_llm(
-    req: web::Json<ChatRequest>,
-    client: web::Data<reqwest::Client>,
-    app_state: web::Data<AppState>,
-) -> actix_web::Result<HttpResponse> {
-    log::info!("Received message: {}", req.message);
-
-    let payload = serde_json::json!({
-        "messages": [
-            {
-                "role": "user",
-                "content": req.message
+// API handlers
+// Capability discovery: what this deployment serves and has switched on,
+// so clients don't have to probe for it. `endpoints` comes from the
+// OpenAPI description, as "METHOD /path".
+#[get("/api")]
+async fn hello(app_state: web::Data<AppState>) -> impl Responder {
+    let models: Vec<String> = health::endpoints(&app_state).into_iter().map(|endpoint| endpoint.name).collect();
+    let spec = openapi::spec();
+    let mut endpoints = Vec::new();
+    if let Some(paths) = spec["paths"].as_object() {
+        for (path, operations) in paths {
+            if path == "/api/loadtest" && !app_state.loadtest_enabled {
+                continue;
             }
-        ]
-    });
-
-    log::info!("Sending request to LLM endpoint: {}", app_state.llm_endpoint);
-    
-    let response = client
-        .post(&app_state.llm_endpoint)
-        .header("Authorization", format!("Bearer {}", app_state.api_key))
-        .json(&payload)
-        .send()
-        .await
-        .map_err(|e| {
-            log::error!("Failed to send request: {}", e);
-            actix_web::error::ErrorInternalServerError("Failed to send request to LLM")
-        })?;
-
-    // Get status before consuming the response
-    let status = response.status();
-    
-    if !status.is_success() {
-        let error_body = response.text().await.unwrap_or_default();
-        log::error!(
-            "HTTP error occurred. Status: {}, Body: {}",
-            status,
-            error_body
-        );
-        return Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Error from LLM endpoint"
-        })));
+            for method in operations.as_object().into_iter().flat_map(|ops| ops.keys()) {
+                endpoints.push(format!("{} {}", method.to_uppercase(), path));
+            }
+        }
     }
-
-    log::info!("Received response from LLM");
-
-    let llm_resp: LLMResponse = response.json().await.map_err(|e| {
-        log::error!("Failed to decode response: {}", e);
-        actix_web::error::ErrorInternalServerError("Invalid response from LLM endpoint")
-    })?;
-
-    let content = llm_resp
-        .choices
-        .first()
-        .and_then(|choice| Some(choice.message.content.clone()))
-        .ok_or_else(|| {
-            log::error!("Invalid response structure from LLM");
-            actix_web::error::ErrorInternalServerError("Invalid response structure from LLM endpoint")
-        })?;
-
-    Ok(HttpResponse::Ok().json(ChatResponse { content }))
+    HttpResponse::Ok().json(serde_json::json!({
+        "message": "Welcome to the LLM Chat API",
+        "api_version": version::VERSION,
+        "models": models,
+        "features": {
+            "streaming": true,
+            "websocket": true,
+            // Admin routes need ADMIN_TOKEN; chat itself is unauthenticated
+            "admin_auth": app_state.admin_token.is_some(),
+            // Conversations are kept in memory and lost on restart
+            "persistence": false,
+            "response_cache": app_state.response_cache.is_some(),
+            "endpoint_override": app_state.endpoint_override.is_some(),
+            "embeddings": app_state.embeddings.is_some(),
+        },
+        "templates": app_state.templates.names(),
+        "endpoints": endpoints,
+    }))
 }
 
 #[get("/api/loadtest")]
 async fn handle_load_test(
+    req: HttpRequest,
     query: web::Query<LoadTestRequest>,
     client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
 ) -> actix_web::Result<HttpResponse> {
+    // Open when no admin token is configured, as in local development
+    if app_state.admin_token.is_some() {
+        if let Err(response) = admin::authorize(&req, &app_state) {
+            return Ok(response);
+        }
+    }
     log::info!("Starting load test with {} requests, {} concurrent users", 
         query.requests, query.concurrency);
 
@@ -153,6 +178,7 @@ async fn handle_load_test(
     let mut durations = Vec::new();
     let mut success_count = 0;
     let mut failure_count = 0;
+    let read_body = query.read_body.unwrap_or(true);
 
     // Create batches of concurrent requests
     for batch in (0..query.requests).collect::<Vec<_>>().chunks(query.concurrency as usize) {
@@ -164,16 +190,21 @@ async fn handle_load_test(
                     .get("http://localhost:8000/api")
                     .send()
                     .await;
-                let duration = request_start.elapsed();
-                
+                let result = match result {
+                    Ok(response) if read_body => response.bytes().await.map(|_| request_start.elapsed()),
+                    Ok(mut response) => {
+                        let duration = request_start.elapsed();
+                        // Drained untimed, a chunk at a time, so the connection can be reused
+                        while let Ok(Some(_)) = response.chunk().await {}
+                        Ok(duration)
+                    }
+                    Err(e) => Err(e),
+                };
+
                 match result {
-                    Ok(_) => {
-                        success_count += 1;
-                        Some(duration.as_millis() as u64)
-                    },
+                    Ok(duration) => Some(duration.as_millis() as u64),
                     Err(e) => {
                         log::error!("Request failed: {}", e);
-                        failure_count += 1;
                         None
                     }
                 }
@@ -182,7 +213,15 @@ async fn handle_load_test(
 
         // Execute concurrent batch
         let batch_results = join_all(requests).await;
-        durations.extend(batch_results.into_iter().flatten());
+        for result in batch_results {
+            match result {
+                Some(duration) => {
+                    success_count += 1;
+                    durations.push(duration);
+                }
+                None => failure_count += 1,
+            }
+        }
     }
 
     let total_duration = start_time.elapsed();
@@ -196,27 +235,189 @@ async fn handle_load_test(
         total_duration_ms: total_duration.as_millis() as u64,
         average_response_ms: avg_response,
         requests_per_second: requests_per_sec,
+        read_body,
     };
 
     log::info!("Load test completed: {:?}", result);
     Ok(HttpResponse::Ok().json(result))
 }
 
-// Helper function to check if file exists
-fn file_exists(path: &PathBuf) -> bool {
-    path.exists()
-}
-
 struct AppState {
-    llm_endpoint: String,
+    // Swapped by POST /admin/reload; read it through primary_endpoint()
+    llm_endpoint: RwLock<LlmEndpoint>,
+    canary: Option<Canary>,
+    endpoint_override: Option<EndpointOverride>,
+    shadow: Option<Shadow>,
     api_key: String,
+    pricing: Pricing,
+    token_budget: Option<TokenBudget>,
+    admin_token: Option<String>,
+    completion_webhook: Option<CompletionWebhook>,
+    audit_log: Option<AuditLog>,
+    metrics: Metrics,
+    conversations: Box<dyn ConversationStore>,
+    templates: PromptTemplates,
+    // NFC-normalize incoming text (NORMALIZE_UNICODE, default on)
+    normalize_unicode: bool,
+    history_limit: HistoryLimit,
+    generation_defaults: GenerationDefaults,
+    stream_settings: StreamSettings,
+    // Reply served in place of upstream errors (FALLBACK_RESPONSE, off when unset)
+    fallback_response: Option<String>,
+    limiter: Option<Arc<AdaptiveLimiter>>,
+    response_cache: Option<ResponseCache>,
+    retry_policy: RetryPolicy,
+    upstream_transport: Transport,
+    // Send a W3C traceparent upstream (TRACE_CONTEXT=true)
+    trace_context: bool,
+    // Client headers passed through upstream (FORWARD_HEADERS, none by default)
+    forward_headers: Vec<String>,
+    token_estimator: TokenEstimator,
+    // Register /api/loadtest (LOADTEST_ENABLED, default on only in debug builds)
+    loadtest_enabled: bool,
+    // Built client served as the UI; None when client/build is missing
+    static_dir: Option<PathBuf>,
+    idempotency: Option<IdempotencyStore>,
+    system_prompt: Option<SystemPrompt>,
+    // Retried once when the reply is content-filtered (FALLBACK_MODEL_ENDPOINT)
+    filter_fallback: Option<LlmEndpoint>,
+    // Latency-ranked or weighted regions replacing the primary (REGION_HOSTS)
+    regions: Option<RegionPool>,
+    // Ask the model once to fix a reply that fails its schema (SCHEMA_REPAIR=true)
+    schema_repair: bool,
+    stall_watchdog: Option<StallWatchdog>,
+    cooldowns: Cooldowns,
+    rooms: ws::Rooms,
+    stream_resume: Option<resume::ResumeBuffers>,
+    // Attribute requests to the X-Forwarded-For client (TRUST_PROXY=true)
+    proxy_trust: Option<ProxyTrust>,
+    // Replies are cut to this many characters (MAX_RESPONSE_CHARS, off when unset)
+    max_response_chars: Option<usize>,
+    language_detection: Option<language::LanguageDetection>,
+    // X-Upstream-Duration-Ms and X-Total-Duration-Ms on /api/chat (DEBUG_HEADERS, default on)
+    debug_headers: bool,
+    injection_guard: Option<injection::InjectionGuard>,
+    endpoint_health: health::EndpointHealth,
+    // POST /api/embeddings/batch (EMBEDDINGS_ENDPOINT, off when unset)
+    embeddings: Option<embeddings::Embeddings>,
+    cancellations: cancel::Cancellations,
+    // Share of requests that log below WARN (LOG_SAMPLE_RATE, default 1)
+    log_sample_rate: f64,
+    completion_events: events::CompletionEvents,
+    chat_jobs: jobs::ChatJobs,
+    reply_filters: Option<postprocess::ReplyFilters>,
+    recent_requests: recent::RecentRequests,
+    // Pooled connections opened at startup and by POST /admin/warmup
+    // (WARM_CONNECTIONS, default 2)
+    warm_connections: usize,
+    // Prompts POST /admin/warmup runs into the response cache (WARMUP_PROMPTS_PATH)
+    warmup_prompts: Vec<String>,
+    // One turn at a time per conversation (SERIALIZE_CONVERSATIONS, default off)
+    conversation_turns: Option<turns::ConversationTurns>,
+}
+
+impl AppState {
+    // Everything except the primary endpoint and credentials comes from the
+    // environment here; `main` and the bench both start from this.
+    fn from_env(llm_endpoint: LlmEndpoint, api_key: String, databricks_host: &str) -> std::io::Result<Self> {
+        let canary = Canary::from_env(databricks_host);
+        let shadow = Shadow::from_env(databricks_host);
+        let templates = match env::var("PROMPTS_DIR") {
+            Ok(dir) => PromptTemplates::load(Path::new(&dir))?,
+            Err(_) => PromptTemplates::default(),
+        };
+        let stream_settings = StreamSettings::from_env();
+        let regions = RegionPool::from_env(&llm_endpoint.name);
+        Ok(AppState {
+            llm_endpoint: RwLock::new(llm_endpoint),
+            canary,
+            endpoint_override: EndpointOverride::from_env(databricks_host),
+            shadow,
+            api_key,
+            pricing: Pricing::from_env(),
+            token_budget: TokenBudget::from_env(),
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            completion_webhook: CompletionWebhook::from_env(),
+            audit_log: AuditLog::from_env()?,
+            metrics: Metrics::default(),
+            conversations: conversations::from_env(),
+            templates,
+            normalize_unicode: env::var("NORMALIZE_UNICODE").map(|v| v != "false").unwrap_or(true),
+            history_limit: HistoryLimit::from_env(),
+            generation_defaults: GenerationDefaults::from_env(),
+            upstream_transport: Transport::from_env(stream_settings.idle_timeout),
+            stream_settings,
+            fallback_response: env::var("FALLBACK_RESPONSE").ok().filter(|r| !r.is_empty()),
+            limiter: AdaptiveLimiter::from_env().map(Arc::new),
+            response_cache: ResponseCache::from_env(),
+            retry_policy: RetryPolicy::from_env(),
+            trace_context: env::var("TRACE_CONTEXT").map(|v| v == "true").unwrap_or(false),
+            forward_headers: trace::forward_headers_from_env(),
+            token_estimator: TokenEstimator::from_env(),
+            loadtest_enabled: env::var("LOADTEST_ENABLED")
+                .map(|v| v == "true")
+                .unwrap_or(cfg!(debug_assertions)),
+            static_dir: None,
+            idempotency: IdempotencyStore::from_env(),
+            system_prompt: SystemPrompt::from_env(),
+            filter_fallback: env::var("FALLBACK_MODEL_ENDPOINT")
+                .ok()
+                .filter(|name| !name.is_empty())
+                .map(|name| LlmEndpoint::new(databricks_host, &name)),
+            regions,
+            schema_repair: env::var("SCHEMA_REPAIR").map(|v| v == "true").unwrap_or(false),
+            stall_watchdog: StallWatchdog::from_env(),
+            cooldowns: Cooldowns::default(),
+            rooms: ws::Rooms::from_env(),
+            stream_resume: resume::ResumeBuffers::from_env(),
+            proxy_trust: ProxyTrust::from_env(),
+            max_response_chars: env::var("MAX_RESPONSE_CHARS").ok().map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("MAX_RESPONSE_CHARS must be a positive integer"),
+            }),
+            language_detection: language::LanguageDetection::from_env(databricks_host),
+            debug_headers: env::var("DEBUG_HEADERS").map(|v| v != "false").unwrap_or(true),
+            injection_guard: injection::InjectionGuard::from_env(),
+            endpoint_health: health::EndpointHealth::from_env(),
+            embeddings: embeddings::Embeddings::from_env(databricks_host),
+            cancellations: cancel::Cancellations::default(),
+            log_sample_rate: log_sampling::rate_from_env(),
+            completion_events: events::CompletionEvents::from_env(),
+            chat_jobs: jobs::ChatJobs::from_env(),
+            reply_filters: postprocess::ReplyFilters::from_env(),
+            recent_requests: recent::RecentRequests::from_env(),
+            warm_connections: env::var("WARM_CONNECTIONS")
+                .ok()
+                .map(|v| v.parse().expect("WARM_CONNECTIONS must be a non-negative integer"))
+                .unwrap_or(2),
+            warmup_prompts: warmup::prompts_from_env(),
+            conversation_turns: turns::ConversationTurns::from_env(),
+        })
+    }
+
+    fn audit(&self, entry: AuditEntry) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(entry);
+        }
+    }
+
+    fn primary_endpoint(&self) -> LlmEndpoint {
+        self.llm_endpoint.read().unwrap().clone()
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Initialize environment variables and logging
     dotenv::dotenv().ok();
-    env_logger::init();
+    log_sampling::init();
+    panic::install_hook();
+    statsd::install_from_env()?;
+
+    if env::args().nth(1).as_deref() == Some("bench") {
+        let args: Vec<String> = env::args().skip(2).collect();
+        return bench::run(&args).await;
+    }
 
     // Load environment variables
     let databricks_host = env::var("DATABRICKS_HOST")
@@ -225,13 +426,29 @@ async fn main() -> std::io::Result<()> {
         .expect("SERVING_ENDPOINT_NAME must be set");
     let api_key = env::var("DATABRICKS_TOKEN")
         .expect("DATABRICKS_TOKEN must be set");
-    let llm_endpoint = format!("https://{}/serving-endpoints/{}/invocations", databricks_host, llm_endpoint);
-    let app_state = web::Data::new(AppState {
-        llm_endpoint,
-        api_key,
-    });
+    if upstream::url_template() != upstream::DEFAULT_URL_TEMPLATE {
+        log::info!("Building endpoint URLs from LLM_URL_TEMPLATE {}", upstream::url_template());
+    }
+    let llm_endpoint = LlmEndpoint::new(&databricks_host, &llm_endpoint);
+    let mut app_state = AppState::from_env(llm_endpoint, api_key, &databricks_host)?;
+
+    let client = upstream::build_client();
+    // Checked here so a bad FAULT_INJECT fails startup, not the first chat
+    faults::settings();
+
+    if env::var("STARTUP_SELFTEST").map(|v| v == "true").unwrap_or(false) {
+        let endpoint = app_state.primary_endpoint();
+        upstream::self_test(&client, &endpoint, &app_state.api_key)
+            .await
+            .map_err(|e| {
+                log::error!("Startup self-test against {} failed: {}", endpoint.url, e);
+                std::io::Error::other(format!("startup self-test failed: {}", e))
+            })?;
+    }
 
-    let client = reqwest::Client::new();
+    if app_state.warm_connections > 0 {
+        upstream::warm_pool(&client, &app_state.primary_endpoint(), &app_state.api_key, app_state.warm_connections).await;
+    }
     
     // Get the current directory (where client/build should be)
     let current_dir = env::current_dir()?
@@ -241,13 +458,109 @@ async fn main() -> std::io::Result<()> {
     
     // Debug logging
     log::info!("Static files path: {:?}", static_path);
-    if !static_path.exists() {
-        log::warn!("Static files directory does not exist at: {:?}", static_path);
+    if static_path.exists() {
+        app_state.static_dir = Some(static_path);
+    } else {
+        log::warn!("UI disabled: static dir not found at {:?}", static_path);
     }
 
     log::info!("Starting the Rust server...");
 
-    HttpServer::new(move || {
+    let app_state = web::Data::new(app_state);
+    if app_state.regions.is_some() {
+        actix_web::rt::spawn(regions::run_probes(app_state.clone(), client.clone()));
+    }
+    if app_state.token_budget.is_some() {
+        actix_web::rt::spawn(budget::run_persistence(app_state.clone()));
+    }
+    events::spawn_consumers(&app_state, &client);
+
+    let listener = TcpListener::bind(("127.0.0.1", 8000))?;
+    serve(app_state.clone(), client, listener)?.await?;
+    // Whatever spend the background writer hadn't saved yet
+    if let Some(budget) = &app_state.token_budget {
+        budget.persist_now();
+    }
+    Ok(())
+}
+
+// Body errors on JSON endpoints come back as `{"error": ...}`: 415 for a
+// missing or non-JSON Content-Type, 400 for anything else.
+fn json_config(lenient_content_type: bool) -> web::JsonConfig {
+    let config = web::JsonConfig::default().error_handler(|err, req| {
+        let response = match &err {
+            JsonPayloadError::ContentType => {
+                let received = req
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("none");
+                HttpResponse::UnsupportedMediaType().json(serde_json::json!({
+                    "error": format!("Content-Type must be application/json, got {}", received)
+                }))
+            }
+            _ => HttpResponse::BadRequest().json(serde_json::json!({ "error": err.to_string() })),
+        };
+        actix_web::error::InternalError::from_response(err, response).into()
+    });
+    if lenient_content_type {
+        config.content_type_required(false).content_type(|_| true)
+    } else {
+        config
+    }
+}
+
+fn serve(
+    app_state: web::Data<AppState>,
+    client: reqwest::Client,
+    listener: TcpListener,
+) -> std::io::Result<Server> {
+    // Actix starts one worker per CPU it can see, which in a container with
+    // a CPU limit is usually the host's count; WORKERS overrides it
+    let workers = env::var("WORKERS")
+        .ok()
+        .map(|v| match v.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => panic!("WORKERS must be a positive integer"),
+        })
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    log::info!("Starting {} HTTP workers", workers);
+
+    // Connection timeouts, in whole seconds; 0 turns each one off. The
+    // defaults are actix's own.
+    let timeout_secs = |name: &str, default: u64| -> Duration {
+        let secs = env::var(name)
+            .ok()
+            .map(|v| v.parse().unwrap_or_else(|_| panic!("{} must be a whole number of seconds", name)))
+            .unwrap_or(default);
+        Duration::from_secs(secs)
+    };
+    let keep_alive = timeout_secs("KEEP_ALIVE_SECS", 5);
+    let client_request_timeout = timeout_secs("CLIENT_REQUEST_TIMEOUT_SECS", 5);
+    let client_disconnect_timeout = timeout_secs("CLIENT_DISCONNECT_TIMEOUT_SECS", 1);
+    log::info!(
+        "Keep-alive {}s, client request timeout {}s, client disconnect timeout {}s",
+        keep_alive.as_secs(),
+        client_request_timeout.as_secs(),
+        client_disconnect_timeout.as_secs()
+    );
+    let keep_alive = if keep_alive.is_zero() {
+        KeepAlive::Disabled
+    } else {
+        KeepAlive::Timeout(keep_alive)
+    };
+
+    // Accept JSON bodies whatever their Content-Type says, for older clients
+    let lenient_content_type = env::var("LENIENT_CONTENT_TYPE").map(|v| v == "true").unwrap_or(false);
+    if lenient_content_type {
+        log::warn!("Accepting JSON request bodies with any or no Content-Type");
+    }
+
+    Ok(HttpServer::new(move || {
+        // The factory runs once on each worker thread
+        if app_state.stall_watchdog.is_some() {
+            actix_web::rt::spawn(watchdog::run(app_state.clone()));
+        }
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -255,24 +568,233 @@ async fn main() -> std::io::Result<()> {
             .supports_credentials()
             .max_age(43200);
 
-        let app = App::new()
-            .wrap(Logger::default())
+        let mut app = App::new()
+            // Innermost, so the request id is already assigned. Anything held
+            // by the handler, such as a concurrency permit, is released while
+            // unwinding. The panic response is returned as an error because
+            // the request can't be kept around for a ServiceResponse: routing
+            // needs sole ownership of it. The error carries the request id.
+            .wrap_fn(|req, srv| {
+                let request_id = req
+                    .extensions()
+                    .get::<request_id::RequestId>()
+                    .map(|id| id.0.clone());
+                let route = format!("{} {}", req.method(), req.path());
+                let fut = AssertUnwindSafe(srv.call(req)).catch_unwind();
+                async move {
+                    fut.await.unwrap_or_else(|_| {
+                        log::error!(
+                            "Handler for {} panicked (request {}): {}",
+                            route,
+                            request_id.as_deref().unwrap_or("-"),
+                            panic::take_report()
+                        );
+                        Err(panic::HandlerPanicked { request_id }.into())
+                    })
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let request_id = request_id::assign(&req);
+                let fut = srv.call(req);
+                async move {
+                    // A panicked handler's error response has the header already
+                    let mut res = fut.await?;
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        res.headers_mut().insert(HeaderName::from_static(request_id::HEADER), value);
+                    }
+                    Ok(res)
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let request_size = metrics::content_length(req.headers());
+                let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                let method = req.method().to_string();
+                let app_state = req.app_data::<web::Data<AppState>>().cloned();
+                if let Some(app_state) = &app_state {
+                    app_state.metrics.http_in_flight.add(1.0);
+                }
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    if let Some(app_state) = &app_state {
+                        app_state.metrics.http_in_flight.add(-1.0);
+                        // Only a panicked handler's request ends in an error here
+                        let (request_id, status, error, response_size) = match &res {
+                            Ok(res) => (
+                                res.request().extensions().get::<request_id::RequestId>().map(|id| id.0.clone()),
+                                res.status(),
+                                res.response().error().map(ToString::to_string),
+                                res.response().body().size(),
+                            ),
+                            Err(e) => (
+                                e.as_error::<panic::HandlerPanicked>().and_then(|p| p.request_id.clone()),
+                                e.as_response_error().status_code(),
+                                Some(e.to_string()),
+                                BodySize::None,
+                            ),
+                        };
+                        app_state.recent_requests.record(recent::RequestSummary {
+                            request_id,
+                            method: method.clone(),
+                            route: route.clone(),
+                            status: status.as_u16(),
+                            duration_ms: started.elapsed().as_millis() as u64,
+                            finished_at_ms: conversations::now_ms(),
+                            error,
+                        });
+                        app_state.metrics.observe_http(
+                            &route,
+                            &method,
+                            status.as_u16(),
+                            started.elapsed(),
+                            request_size,
+                            response_size,
+                        );
+                    }
+                    res
+                }
+            })
+            .wrap(DefaultHeaders::new().add(("X-Server-Version", version::header_value())))
+            // Access log in actix Logger's default format, with the address
+            // client_ip settles on rather than any X-Forwarded-For, and the
+            // time to the response head. Decides LOG_SAMPLE_RATE sampling for
+            // everything the request logs.
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let app_state = req.app_data::<web::Data<AppState>>().cloned();
+                let sampled = app_state.as_ref().is_none_or(|app_state| log_sampling::sample(app_state.log_sample_rate));
+                let header = |name: &str| {
+                    req.headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("-")
+                        .to_string()
+                };
+                let line = format!(
+                    "{} \"{} {} {:?}\"",
+                    app_state
+                        .as_ref()
+                        .and_then(|app_state| client_ip::client_ip(app_state, req.request()))
+                        .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+                    req.method(),
+                    req.uri(),
+                    req.version()
+                );
+                let (referer, user_agent) = (header("Referer"), header("User-Agent"));
+                let fut = log_sampling::scope(sampled, srv.call(req));
+                async move {
+                    let res = fut.await;
+                    let (status, size) = match &res {
+                        Ok(res) => (res.status(), res.response().body().size()),
+                        Err(e) => (e.as_response_error().status_code(), BodySize::None),
+                    };
+                    if sampled || status.as_u16() >= 400 {
+                        let size = match size {
+                            BodySize::Sized(n) => n.to_string(),
+                            _ => "-".to_string(),
+                        };
+                        log::info!(
+                            target: "access",
+                            "{} {} {} \"{}\" \"{}\" {:.6}",
+                            line,
+                            status.as_u16(),
+                            size,
+                            referer,
+                            user_agent,
+                            started.elapsed().as_secs_f64()
+                        );
+                    }
+                    res
+                }
+            })
             .wrap(cors)
+            .app_data(json_config(lenient_content_type))
             .app_data(app_state.clone())
             .app_data(web::Data::new(client.clone()))
             .service(hello)
-            .service(chat_with_llm)
-            .service(handle_load_test);
+            .service(version::version)
+            .service(version::health)
+            .service(chat::chat_with_llm)
+            .service(chat::validate_chat)
+            .service(jobs::chat_async)
+            .service(jobs::chat_job)
+            .service(chat::replay_conversation)
+            .service(chat::regenerate_reply)
+            .service(cancel::cancel_generation)
+            .service(stream::chat_stream)
+            .service(ws::chat_ws)
+            .service(tokens::tokenize)
+            .service(embeddings::embed_batch)
+            .service(conversations::search_conversations)
+            .service(conversations::get_messages)
+            .service(conversations::export_conversation)
+            .service(conversations::delete_conversation)
+            .service(conversations::delete_old_conversations)
+            .service(feedback::submit_feedback)
+            .service(feedback::feedback_stats)
+            .service(admin::token_budget)
+            .service(admin::flush_cache)
+            .service(admin::reload)
+            .service(recent::recent_requests)
+            .service(warmup::warmup)
+            .service(metrics::metrics)
+            .service(metrics::stats)
+            .service(openapi::openapi_json)
+            .service(openapi::swagger_ui);
+        if app_state.loadtest_enabled {
+            app = app.service(handle_load_test);
+        }
 
         // Only add static file handlers if the directory exists
-        if static_path.exists() {
+        if let Some(static_path) = &app_state.static_dir {
             app.service(Files::new("/static", static_path.join("static")))
-                .service(Files::new("/", &static_path).index_file("index.html"))
+                .service(Files::new("/", static_path).index_file("index.html"))
         } else {
-            app
+            app.route("/", web::get().to(ui_placeholder))
         }
     })
-    .bind(("127.0.0.1", 8000))?
-    .run()
-    .await
-} 
\ No newline at end of file
+    .workers(workers)
+    .keep_alive(keep_alive)
+    .client_request_timeout(client_request_timeout)
+    .client_disconnect_timeout(client_disconnect_timeout)
+    .listen(listener)?
+    .run())
+}
+
+// Serves the whole app on a free port, as the bench does, for tests that go
+// through the HTTP handlers. Returns the base URL.
+#[cfg(test)]
+async fn test_server(llm_endpoint: LlmEndpoint, configure: impl FnOnce(&mut AppState)) -> String {
+    let mut app_state = AppState::from_env(llm_endpoint, "test".to_string(), "localhost").unwrap();
+    configure(&mut app_state);
+    let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let server = serve(web::Data::new(app_state), reqwest::Client::new(), listener).unwrap();
+    actix_web::rt::spawn(server);
+    base_url
+}
+
+// An upstream that answers every request with the same 200 body.
+#[cfg(test)]
+async fn fixed_upstream(content_type: &'static str, body: &'static str) -> LlmEndpoint {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut request = vec![0; 4096];
+            let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            );
+            let _ = tokio::io::AsyncWriteExt::write_all(&mut socket, response.as_bytes()).await;
+        }
+    });
+    LlmEndpoint {
+        name: "fixed".to_string(),
+        url: format!("http://{}/invocations", addr),
+    }
+}
@@ -0,0 +1,596 @@
+use actix_web::body::BodySize;
+use actix_web::http::header::{HeaderMap, CONTENT_LENGTH};
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::limiter::Priority;
+use crate::statsd;
+use crate::AppState;
+
+// Minimal Prometheus text-format metrics. Each family knows how to encode
+// itself; `Metrics::render` decides what gets exported. Updates are also
+// forwarded to StatsD when STATSD_ADDR is set.
+
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    pub fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        self.add(label_values, 1);
+    }
+
+    pub fn add(&self, label_values: &[&str], amount: u64) {
+        debug_assert_eq!(label_values.len(), self.labels.len());
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_default() += amount;
+        statsd::count(self.name, self.labels, label_values, amount as f64);
+    }
+
+    // Sum across all label values
+    pub fn total(&self) -> u64 {
+        self.values.lock().unwrap().values().sum()
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                format_labels(self.labels, label_values),
+                value
+            );
+        }
+    }
+}
+
+// Monotonic floating point counter, stored as f64 bits.
+pub struct FloatCounter {
+    name: &'static str,
+    help: &'static str,
+    bits: AtomicU64,
+}
+
+impl FloatCounter {
+    pub fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            bits: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn add(&self, amount: f64) {
+        let _ = self.bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + amount).to_bits())
+        });
+        statsd::count(self.name, &[], &[], amount);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+// Point-in-time value, refreshed when metrics are scraped. Stored as f64
+// bits like FloatCounter.
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    bits: AtomicU64,
+}
+
+impl Gauge {
+    pub fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            bits: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn set(&self, value: f64) {
+        self.bits.store(value.to_bits(), Ordering::Relaxed);
+        statsd::gauge(self.name, value);
+    }
+
+    pub fn add(&self, delta: f64) {
+        let previous = self.bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + delta).to_bits())
+        });
+        if let Ok(bits) = previous {
+            statsd::gauge(self.name, f64::from_bits(bits) + delta);
+        }
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+
+    // Raises the value to `value` if it is higher; for high-water marks.
+    pub fn set_max(&self, value: f64) {
+        let raised = self.bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            (value > f64::from_bits(bits)).then_some(value.to_bits())
+        });
+        if raised.is_ok() {
+            statsd::gauge(self.name, value);
+        }
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "gauge");
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+// Labelled point-in-time values, refreshed when metrics are scraped.
+pub struct GaugeVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, f64>>,
+}
+
+impl GaugeVec {
+    pub fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set(&self, label_values: &[&str], value: f64) {
+        debug_assert_eq!(label_values.len(), self.labels.len());
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        self.values.lock().unwrap().insert(key, value);
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "gauge");
+        for (label_values, value) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                format_labels(self.labels, label_values),
+                value
+            );
+        }
+    }
+}
+
+// Cumulative histogram with fixed upper bounds. Unlabelled histograms are
+// exported from the start; labelled ones once a label set is observed.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    bounds: &'static [f64],
+    series: Mutex<BTreeMap<Vec<String>, HistogramState>>,
+}
+
+struct HistogramState {
+    // One count per bound plus +Inf, not yet cumulative
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl HistogramState {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        bounds: &'static [f64],
+    ) -> Self {
+        let mut series = BTreeMap::new();
+        if labels.is_empty() {
+            series.insert(Vec::new(), HistogramState::new(bounds));
+        }
+        Self {
+            name,
+            help,
+            labels,
+            bounds,
+            series: Mutex::new(series),
+        }
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        debug_assert_eq!(label_values.len(), self.labels.len());
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        let state = series
+            .entry(key)
+            .or_insert_with(|| HistogramState::new(self.bounds));
+        state.counts[bucket] += 1;
+        state.sum += value;
+        statsd::histogram(self.name, self.labels, label_values, value);
+    }
+
+    fn encode(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (label_values, state) in self.series.lock().unwrap().iter() {
+            let labels = format_labels(self.labels, label_values);
+            // Bucket lines add `le` to the series labels
+            let bucket_labels = |le: &str| match labels.strip_suffix('}') {
+                Some(open) => format!("{},le=\"{}\"}}", open, le),
+                None => format!("{{le=\"{}\"}}", le),
+            };
+            let mut cumulative = 0;
+            for (i, count) in state.counts.iter().enumerate() {
+                cumulative += count;
+                let le = match self.bounds.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, bucket_labels(&le), cumulative);
+            }
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, state.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, cumulative);
+        }
+    }
+}
+
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+const TOKENS_PER_SECOND_BUCKETS: &[f64] = &[
+    5.0, 10.0, 20.0, 30.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0,
+];
+
+// Chat payloads run from a few hundred bytes to tens of KB.
+const SIZE_BUCKETS: &[f64] = &[
+    128.0, 256.0, 512.0, 1024.0, 2048.0, 4096.0, 8192.0, 16384.0, 32768.0, 65536.0,
+];
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn format_labels(names: &[&str], values: &[String]) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| {
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, escaped)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+pub struct Metrics {
+    pub started_at: Instant,
+    pub http_requests: CounterVec,
+    pub http_in_flight: Gauge,
+    pub http_request_duration: Histogram,
+    pub llm_requests: CounterVec,
+    pub llm_request_duration: Histogram,
+    pub llm_errors: CounterVec,
+    pub llm_request_errors: CounterVec,
+    pub llm_retries: CounterVec,
+    pub llm_rate_limited: CounterVec,
+    pub cost_usd: FloatCounter,
+    pub concurrency_limit: Gauge,
+    pub in_flight: Gauge,
+    pub queue_depth: GaugeVec,
+    pub http_request_size: Histogram,
+    pub http_response_size: Histogram,
+    pub tokens_per_second: Histogram,
+    pub cache_hits: CounterVec,
+    pub cache_misses: CounterVec,
+    pub cache_hit_ratio: Gauge,
+    pub event_loop_stalls: CounterVec,
+    pub event_loop_stall_max: Gauge,
+    pub ws_connections: Gauge,
+    pub llm_tokens: CounterVec,
+    pub completion_events_dropped: CounterVec,
+    pub endpoint_weight: GaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            http_requests: CounterVec::new(
+                "http_requests_total",
+                "HTTP requests served, by route pattern, method and status",
+                &["route", "method", "status"],
+            ),
+            http_in_flight: Gauge::new(
+                "http_in_flight",
+                "HTTP requests being handled; streams count until their response head is sent",
+            ),
+            http_request_duration: Histogram::new(
+                "http_request_duration_seconds",
+                "Time until the response head was ready; streams are not timed to the end",
+                &["route"],
+                LATENCY_BUCKETS,
+            ),
+            llm_requests: CounterVec::new(
+                "llm_requests_total",
+                "Chat requests routed to a serving endpoint, by route; response cache hits included",
+                &["endpoint", "route"],
+            ),
+            llm_request_duration: Histogram::new(
+                "llm_request_duration_seconds",
+                "Upstream chat call latency including retries, by serving endpoint",
+                &["endpoint"],
+                LATENCY_BUCKETS,
+            ),
+            llm_errors: CounterVec::new(
+                "llm_errors_total",
+                "Failed upstream chat requests, by serving endpoint and failure kind",
+                &["endpoint", "kind"],
+            ),
+            llm_request_errors: CounterVec::new(
+                "llm_request_errors_total",
+                "Failed upstream chat calls by category: timeout, connection, http_4xx, http_5xx, decode, or local for calls this server refused before sending (limiter queue full, endpoint cooling down after a 429)",
+                &["category"],
+            ),
+            llm_retries: CounterVec::new(
+                "llm_retries_total",
+                "Retried upstream chat calls, by reason (timeout, connection or HTTP status)",
+                &["reason"],
+            ),
+            llm_rate_limited: CounterVec::new(
+                "llm_rate_limited_total",
+                "429 responses from the serving endpoint, including retried ones",
+                &["endpoint"],
+            ),
+            cost_usd: FloatCounter::new(
+                "cost_usd_total",
+                "Estimated cumulative upstream spend in US dollars",
+            ),
+            concurrency_limit: Gauge::new(
+                "llm_concurrency_limit",
+                "Current adaptive limit on concurrent upstream chat calls",
+            ),
+            in_flight: Gauge::new(
+                "llm_in_flight",
+                "Upstream chat calls currently holding a concurrency slot",
+            ),
+            queue_depth: GaugeVec::new(
+                "llm_queue_depth",
+                "Upstream chat calls waiting for a concurrency slot, by priority",
+                &["priority"],
+            ),
+            http_request_size: Histogram::new(
+                "http_request_size_bytes",
+                "HTTP request body sizes, from Content-Length",
+                &[],
+                SIZE_BUCKETS,
+            ),
+            http_response_size: Histogram::new(
+                "http_response_size_bytes",
+                "HTTP response body sizes; streamed responses are not counted",
+                &[],
+                SIZE_BUCKETS,
+            ),
+            tokens_per_second: Histogram::new(
+                "llm_tokens_per_second",
+                "Streamed completion throughput from first token to end of stream",
+                &["endpoint"],
+                TOKENS_PER_SECOND_BUCKETS,
+            ),
+            cache_hits: CounterVec::new(
+                "cache_hits_total",
+                "Chat requests answered from the response cache",
+                &["cache"],
+            ),
+            cache_misses: CounterVec::new(
+                "cache_misses_total",
+                "Chat requests that missed the response cache",
+                &["cache"],
+            ),
+            cache_hit_ratio: Gauge::new(
+                "cache_hit_ratio",
+                "Response cache hits over lookups since startup",
+            ),
+            event_loop_stalls: CounterVec::new(
+                "event_loop_stalls_total",
+                "Times a worker's event loop was starved past EVENT_LOOP_STALL_MS",
+                &[],
+            ),
+            event_loop_stall_max: Gauge::new(
+                "event_loop_stall_max_seconds",
+                "Worst scheduling delay seen by the event loop watchdog since startup",
+            ),
+            ws_connections: Gauge::new(
+                "ws_connections",
+                "Open /api/chat/ws sockets",
+            ),
+            llm_tokens: CounterVec::new(
+                "llm_tokens_total",
+                "Tokens reported by the serving endpoint for /api/chat replies, by endpoint and kind (prompt or completion)",
+                &["endpoint", "kind"],
+            ),
+            completion_events_dropped: CounterVec::new(
+                "completion_events_dropped_total",
+                "Completion events a lagging consumer (webhook, audit, metrics) never saw",
+                &["consumer"],
+            ),
+            endpoint_weight: GaugeVec::new(
+                "llm_endpoint_weight",
+                "REGION_WEIGHTS weight of each region, 0 while it is unhealthy; llm_requests_total shows the traffic each got",
+                &["endpoint"],
+            ),
+        }
+    }
+}
+
+impl Metrics {
+    // Called by the HTTP middleware once the response is ready. Sizes that
+    // are unknown up front (chunked requests, streams) are skipped. `route`
+    // is the matched pattern, keeping label values bounded.
+    pub fn observe_http(
+        &self,
+        route: &str,
+        method: &str,
+        status: u16,
+        elapsed: Duration,
+        request_size: Option<u64>,
+        response_size: BodySize,
+    ) {
+        self.http_requests.inc(&[route, method, &status.to_string()]);
+        self.http_request_duration.observe(&[route], elapsed.as_secs_f64());
+        if let Some(size) = request_size {
+            self.http_request_size.observe(&[], size as f64);
+        }
+        if let BodySize::Sized(size) = response_size {
+            self.http_response_size.observe(&[], size as f64);
+        }
+    }
+
+    // The weight, concurrency and cache families are only exported when the
+    // corresponding feature is enabled.
+    pub fn render(&self, weighted: bool, limiter_enabled: bool, cache_enabled: bool) -> String {
+        let mut out = String::new();
+        self.http_requests.encode(&mut out);
+        self.http_in_flight.encode(&mut out);
+        self.http_request_duration.encode(&mut out);
+        self.llm_requests.encode(&mut out);
+        self.llm_request_duration.encode(&mut out);
+        self.llm_errors.encode(&mut out);
+        self.llm_request_errors.encode(&mut out);
+        self.llm_retries.encode(&mut out);
+        self.llm_rate_limited.encode(&mut out);
+        self.cost_usd.encode(&mut out);
+        self.http_request_size.encode(&mut out);
+        self.http_response_size.encode(&mut out);
+        self.tokens_per_second.encode(&mut out);
+        self.event_loop_stalls.encode(&mut out);
+        self.event_loop_stall_max.encode(&mut out);
+        self.ws_connections.encode(&mut out);
+        self.llm_tokens.encode(&mut out);
+        self.completion_events_dropped.encode(&mut out);
+        if weighted {
+            self.endpoint_weight.encode(&mut out);
+        }
+        if limiter_enabled {
+            self.concurrency_limit.encode(&mut out);
+            self.in_flight.encode(&mut out);
+            self.queue_depth.encode(&mut out);
+        }
+        if cache_enabled {
+            if let Some(ratio) = self.cache_ratio() {
+                self.cache_hit_ratio.set(ratio);
+            }
+            self.cache_hits.encode(&mut out);
+            self.cache_misses.encode(&mut out);
+            self.cache_hit_ratio.encode(&mut out);
+        }
+        out
+    }
+
+    // None until the cache has been consulted
+    fn cache_ratio(&self) -> Option<f64> {
+        let hits = self.cache_hits.total();
+        let lookups = hits + self.cache_misses.total();
+        (lookups > 0).then(|| hits as f64 / lookups as f64)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ServerStats {
+    uptime_secs: u64,
+    requests_total: u64,
+    in_flight: u64,
+    // Only tracked when DAILY_TOKEN_BUDGET is set
+    tokens_today: Option<u64>,
+    cache_hit_ratio: Option<f64>,
+    upstream_errors_total: u64,
+}
+
+// A JSON summary of the same counters /metrics exports, for a dashboard.
+#[get("/api/stats")]
+async fn stats(app_state: web::Data<AppState>) -> impl Responder {
+    let m = &app_state.metrics;
+    HttpResponse::Ok().json(ServerStats {
+        uptime_secs: m.started_at.elapsed().as_secs(),
+        requests_total: m.http_requests.total(),
+        in_flight: m.http_in_flight.get().max(0.0) as u64,
+        tokens_today: app_state.token_budget.as_ref().map(|budget| budget.snapshot().used),
+        cache_hit_ratio: app_state.response_cache.as_ref().and(m.cache_ratio()),
+        upstream_errors_total: m.llm_errors.total(),
+    })
+}
+
+#[get("/metrics")]
+async fn metrics(app_state: web::Data<AppState>) -> impl Responder {
+    app_state.metrics.ws_connections.set(app_state.rooms.connections() as f64);
+    let weights = app_state.regions.as_ref().and_then(|regions| regions.effective_weights());
+    for (endpoint, weight) in weights.iter().flatten() {
+        app_state.metrics.endpoint_weight.set(&[endpoint], *weight as f64);
+    }
+    if let Some(limiter) = &app_state.limiter {
+        app_state.metrics.concurrency_limit.set(limiter.limit() as f64);
+        app_state.metrics.in_flight.set(limiter.in_flight() as f64);
+        for priority in Priority::ALL {
+            let depth = limiter.waiting(priority) as f64;
+            app_state.metrics.queue_depth.set(&[priority.as_str()], depth);
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(app_state.metrics.render(
+            weights.is_some(),
+            app_state.limiter.is_some(),
+            app_state.response_cache.is_some(),
+        ))
+}
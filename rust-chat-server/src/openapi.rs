@@ -0,0 +1,1104 @@
+use actix_web::{get, HttpResponse, Responder};
+use serde_json::{json, Value};
+
+// OpenAPI 3 description of the public API, written by hand: utoipa isn't
+// available to this build, so nothing generates it. Schemas mirror the
+// request and response structs in chat.rs and jobs.rs, and the load-test
+// ones in main.rs; keep them in step when fields are added.
+pub fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "LLM Chat API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/api": {
+                "get": {
+                    "summary": "Welcome message and service capabilities",
+                    "responses": {
+                        "200": {
+                            "description": "Welcome message, configured models, enabled features and served endpoints",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "properties": {
+                                    "message": { "type": "string" },
+                                    "api_version": { "type": "string" },
+                                    "models": {
+                                        "type": "array",
+                                        "items": { "type": "string" },
+                                        "description": "Serving endpoints in use: primary or regions, then canary, shadow and filter fallback"
+                                    },
+                                    "features": {
+                                        "type": "object",
+                                        "additionalProperties": { "type": "boolean" },
+                                        "description": "streaming, websocket, admin_auth, persistence, response_cache, endpoint_override, embeddings"
+                                    },
+                                    "templates": { "type": "array", "items": { "type": "string" } },
+                                    "endpoints": {
+                                        "type": "array",
+                                        "items": { "type": "string" },
+                                        "description": "Documented operations, as \"METHOD /path\""
+                                    }
+                                }
+                            })),
+                        }
+                    }
+                }
+            },
+            "/api/version": {
+                "get": {
+                    "summary": "Build information of the running server",
+                    "description": "Every response also carries an X-Server-Version header with the version and commit.",
+                    "responses": {
+                        "200": {
+                            "description": "Version, git commit and build time",
+                            "content": json_content(schema_ref("BuildInfo")),
+                        }
+                    }
+                }
+            },
+            "/health": {
+                "get": {
+                    "summary": "Liveness check",
+                    "description": "Serving endpoints are probed with HEAD requests, at most once per HEALTH_PROBE_CACHE_SECS (default 15)",
+                    "responses": {
+                        "200": {
+                            "description": "Server is up",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "properties": {
+                                    "status": {
+                                        "type": "string",
+                                        "enum": ["ok", "unhealthy"],
+                                        "description": "ok while at least one serving endpoint is up"
+                                    },
+                                    "endpoints": {
+                                        "type": "array",
+                                        "items": {
+                                            "type": "object",
+                                            "properties": {
+                                                "name": { "type": "string" },
+                                                "healthy": { "type": "boolean" },
+                                                "latency_ms": { "type": "integer", "nullable": true, "description": "Probe latency, when healthy" },
+                                                "error": { "type": "string", "description": "Why the last probe failed" }
+                                            }
+                                        }
+                                    },
+                                    "build": schema_ref("BuildInfo"),
+                                    "ui_available": {
+                                        "type": "boolean",
+                                        "description": "Whether the web UI is being served; false when client/build was missing at startup"
+                                    }
+                                }
+                            })),
+                        }
+                    }
+                }
+            },
+            "/api/chat": {
+                "post": {
+                    "summary": "Send a message to the LLM",
+                    "description": "The reply is read from the serving endpoint as a stream and returned whole; a stream that sends nothing for STREAM_IDLE_TIMEOUT_SECS fails the request. CHAT_UPSTREAM_BUFFERED=true makes a single non-streaming upstream call instead. Client headers named in FORWARD_HEADERS (empty by default) are passed through to the serving endpoint; Host, Authorization, cookies and framing headers never are. The reply is JSON unless Accept prefers text/event-stream: it has to name that type itself, since wildcards don't count, and rank it above application/json by q value, or list it first at equal q. The reply is then the event stream of /api/chat/stream, Last-Event-ID included, and Idempotency-Key is ignored. Responses carry Vary: Accept",
+                    "parameters": [
+                        {
+                            "name": "Accept",
+                            "in": "header",
+                            "required": false,
+                            "description": "text/event-stream for a streamed reply; anything else, or nothing, for JSON",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "Idempotency-Key",
+                            "in": "header",
+                            "required": false,
+                            "description": "Retries with the same key within IDEMPOTENCY_TTL_SECS replay the first response (marked Idempotent-Replayed: true) instead of calling the LLM again. Server errors are not stored",
+                            "schema": { "type": "string", "maxLength": 255 }
+                        }
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("ChatRequest")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Model reply",
+                            "headers": {
+                                "X-LLM-Endpoint": {
+                                    "description": "Serving endpoint that produced the reply. Turns sharing a conversation_id stay on one endpoint (canary decision and region) while it is healthy; with REGION_HOSTS the value is name@region, and REGION_WEIGHTS spreads requests across regions by weight",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-LLM-Route": {
+                                    "description": "primary, canary, override, language (LANG_ROUTING), or filter_fallback when a content-filtered reply was retried on FALLBACK_MODEL_ENDPOINT",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-History-Trimmed": {
+                                    "description": "Number of oldest history messages dropped to fit MAX_HISTORY_MESSAGES",
+                                    "schema": { "type": "integer" }
+                                },
+                                "X-Detected-Language": {
+                                    "description": "ISO 639-1 code of the message when language detection is on and confident",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-Prompt-Injection-Score": {
+                                    "description": "Number of prompt-injection patterns the message matched, when flagged under INJECTION_POLICY=warn-header",
+                                    "schema": { "type": "integer" }
+                                },
+                                "X-Request-Cost-USD": {
+                                    "description": "Estimated cost of the call, when pricing is configured",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-Cache": {
+                                    "description": "Set to `hit` when the reply came from the response cache. Entries are kept per serving endpoint, so canary and primary replies aren't shared",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-Fallback-Response": {
+                                    "description": "Set to `true` when the upstream failed and FALLBACK_RESPONSE was served instead",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-Schema-Repaired": {
+                                    "description": "Set to `true` when the first reply failed `schema` and the model was asked once to fix it (SCHEMA_REPAIR=true)",
+                                    "schema": { "type": "string" }
+                                },
+                                "X-Upstream-Duration-Ms": {
+                                    "description": "Time spent in upstream calls, including retries, a filter fallback or schema repair, and waiting for a concurrency slot. Omitted with DEBUG_HEADERS=false",
+                                    "schema": { "type": "integer" }
+                                },
+                                "X-Total-Duration-Ms": {
+                                    "description": "Time the server spent on the request. Omitted with DEBUG_HEADERS=false",
+                                    "schema": { "type": "integer" }
+                                }
+                            },
+                            "content": {
+                                "application/json": { "schema": schema_ref("ChatResponse") },
+                                "text/event-stream": { "schema": { "type": "string" } }
+                            },
+                        },
+                        "400": {
+                            "description": "Malformed request, unknown template or missing template variables",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "415": {
+                            "description": "Content-Type is not application/json (accepted anyway with LENIENT_CONTENT_TYPE=true)",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "409": {
+                            "description": "A request with the same Idempotency-Key is still in progress, or last_message_id is no longer the newest message of the conversation. The latter carries latest_message_id, plus the unsaved reply as content when it was already generated. With SERIALIZE_CONVERSATIONS=true, also when another turn of conversation_id was still running after CONVERSATION_TURN_WAIT_SECS",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "422": {
+                            "description": "The message was flagged as a likely prompt injection under INJECTION_POLICY=block",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "429": {
+                            "description": "Daily token budget exhausted (Retry-After gives seconds until the UTC reset), or the serving endpoint rate-limited the call. Upstream Retry-After and x-ratelimit-*/ratelimit* headers are passed through; after an upstream 429 with Retry-After, calls fail fast until it passes",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "500": {
+                            "description": "Upstream LLM failure",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "503": {
+                            "description": "Shed because MAX_QUEUED_LLM calls were already waiting for a concurrency slot. Retry-After (1-60 s) estimates when the queue will have drained",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "502": {
+                            "description": "The reply did not match the request's `schema` (SchemaMismatch), or the serving endpoint answered a success status with a non-JSON body such as an HTML error page, or a reply larger than MAX_UPSTREAM_BYTES (ErrorResponse with request_id)",
+                            "content": json_content(json!({
+                                "oneOf": [schema_ref("SchemaMismatch"), schema_ref("ErrorResponse")]
+                            })),
+                        }
+                    }
+                }
+            },
+            "/api/chat/async": {
+                "post": {
+                    "summary": "Start a chat in the background and poll for the reply",
+                    "description": "Runs the /api/chat pipeline as a job, for clients whose proxies time out long requests. Validation errors show up in the job's result, not here.",
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("ChatRequest")),
+                    },
+                    "responses": {
+                        "202": {
+                            "description": "Job accepted; Location points at its status",
+                            "content": json_content(schema_ref("ChatJob")),
+                        },
+                        "503": {
+                            "description": "Too many jobs held",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/chat/async/{job_id}": {
+                "get": {
+                    "summary": "Status and, once finished, result of a chat job",
+                    "description": "Finished jobs are kept for CHAT_JOB_TTL_SECS (default 3600).",
+                    "parameters": [path_param("job_id", "Id returned by POST /api/chat/async")],
+                    "responses": {
+                        "200": {
+                            "description": "The job",
+                            "content": json_content(schema_ref("ChatJob")),
+                        },
+                        "404": {
+                            "description": "Unknown or expired job",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/chat/{request_id}/cancel": {
+                "post": {
+                    "summary": "Stop a streaming generation from another connection",
+                    "description": "The stream, SSE or WebSocket, ends with `done` and finish_reason `cancelled`; the partial reply is kept. Needs the generation's cancel token: the X-Cancel-Token header of the SSE response, or the cancel_token of the WebSocket `generation` frame.",
+                    "parameters": [
+                        path_param("request_id", "X-Request-Id of the streaming request"),
+                        {
+                            "name": "X-Cancel-Token",
+                            "in": "header",
+                            "required": true,
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "202": { "description": "Cancellation signalled" },
+                        "400": {
+                            "description": "No X-Cancel-Token header",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "404": {
+                            "description": "No generation in progress under that id and token",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/chat/validate": {
+                "post": {
+                    "summary": "Check a chat request without calling the LLM",
+                    "description": "Runs the same validation as /api/chat and returns the upstream payload it would send. Nothing is recorded and no tokens are spent",
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("ChatRequest")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The request is valid",
+                            "content": json_content(schema_ref("ValidatedChat")),
+                        },
+                        "400": {
+                            "description": "The request would be rejected by /api/chat",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "409": {
+                            "description": "last_message_id is no longer the newest message of the conversation",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "429": {
+                            "description": "Daily token budget exhausted",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/chat/ws": {
+                "get": {
+                    "summary": "Chat over a WebSocket, optionally shared with a room",
+                    "description": "Upgrades to a WebSocket. Each text frame sent is a ChatRequest. Replies are streamed to every connection in the same room as JSON text frames: {\"type\": \"prompt\"} when a member asks, {\"type\": \"generation\"} with the request_id and cancel_token, to the sender only, {\"type\": \"status\", \"value\": \"thinking\"} when the upstream call starts, {\"type\": \"status\", \"value\": \"streaming\"} just before the first `delta` frame, then exactly one `done` (fields as the SSE done event) or `error`. One reply is generated per room at a time. Without `room` the connection has a private room",
+                    "parameters": [
+                        {
+                            "name": "room",
+                            "in": "query",
+                            "required": false,
+                            "description": "Room to join: 1-64 letters, digits, '-' or '_'",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "101": { "description": "Switched to the WebSocket protocol" },
+                        "400": {
+                            "description": "Invalid room name or not a WebSocket handshake",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "503": {
+                            "description": "MAX_WS_CONNECTIONS sockets are already open",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/chat/stream": {
+                "post": {
+                    "summary": "Stream the LLM reply as server-sent events",
+                    "description": "Emits `data: {\"delta\": ...}` events per content fragment, then exactly one of `event: done` (with finish_reason, completion_tokens, elapsed_ms, tokens_per_second and model, the serving endpoint) or `event: error`. The stream is aborted if the upstream sends nothing for STREAM_IDLE_TIMEOUT_SECS. When the whole stream exceeds STREAM_TIMEOUT_SECS it ends with `event: done` and finish_reason `timeout`, keeping the fragments already sent, unless STREAM_PARTIAL_ON_TIMEOUT=false. POST /api/chat/{request_id}/cancel with the X-Cancel-Token response header stops it the same way, with finish_reason `cancelled`. With STREAM_RESUME_SECS > 0 (default 30) every event has an `id:` of `<stream id>/<sequence>`, the reply is buffered until that long after it ends, and X-Stream-Id names the stream. Sending the request again with Last-Event-ID replays the events after that id and follows the rest live. An unknown or expired id gets a single `event: restart`, meaning the request must be sent again.",
+                    "parameters": [
+                        {
+                            "name": "Last-Event-ID",
+                            "in": "header",
+                            "required": false,
+                            "description": "Resume a dropped stream after this event id; the body is then ignored",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("ChatRequest")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Event stream",
+                            "content": { "text/event-stream": { "schema": { "type": "string" } } },
+                        },
+                        "400": {
+                            "description": "Malformed request",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "415": {
+                            "description": "Content-Type is not application/json (accepted anyway with LENIENT_CONTENT_TYPE=true)",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "409": {
+                            "description": "last_message_id is no longer the newest message of the conversation. If that changes while streaming, the stream ends with an error event and the turn is not stored. With SERIALIZE_CONVERSATIONS=true, also when another turn of conversation_id was still running after CONVERSATION_TURN_WAIT_SECS",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "429": {
+                            "description": "Daily token budget exhausted, or the serving endpoint rate-limited the call (same headers as /api/chat)",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "500": {
+                            "description": "Upstream LLM failure before streaming started",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "502": {
+                            "description": "The serving endpoint answered with an HTML page instead of a stream",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/loadtest": {
+                "get": {
+                    "summary": "Run a load test against the API",
+                    "description": "Only registered when LOADTEST_ENABLED=true (the default in debug builds). Requires the admin bearer token when ADMIN_TOKEN is set.",
+                    "parameters": [
+                        query_param("requests", "Total number of requests", true),
+                        query_param("concurrency", "Concurrent users", true),
+                        {
+                            "name": "read_body",
+                            "in": "query",
+                            "description": "Time each request through the full body download (default) or only until the response head; unread bodies are drained untimed",
+                            "required": false,
+                            "schema": { "type": "boolean", "default": true }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Load test summary",
+                            "content": json_content(schema_ref("LoadTestResult")),
+                        },
+                        "400": { "description": "Invalid query parameters" },
+                        "401": { "description": "Admin token missing or wrong" },
+                        "404": { "description": "Load testing is disabled" }
+                    }
+                }
+            },
+            "/api/stats": {
+                "get": {
+                    "summary": "Server activity since startup, as a JSON summary of the /metrics counters",
+                    "responses": {
+                        "200": {
+                            "description": "Current stats",
+                            "content": json_content(schema_ref("ServerStats")),
+                        }
+                    }
+                }
+            },
+            "/api/tokenize": {
+                "post": {
+                    "summary": "Estimate the token count of a piece of text",
+                    "description": "Uses the server's heuristic (characters per token, TOKEN_ESTIMATE_CHARS_PER_TOKEN), not the model's tokenizer.",
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("TokenizeRequest")),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Token estimate",
+                            "content": json_content(schema_ref("TokenizeResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/embeddings/batch": {
+                "post": {
+                    "summary": "Embed many inputs, chunked and sent with bounded concurrency",
+                    "description": "Needs EMBEDDINGS_ENDPOINT. EMBEDDINGS_CHUNK_SIZE inputs go in each upstream call, EMBEDDINGS_CONCURRENCY calls at a time. A failed chunk leaves its inputs null and is listed in `failed`.",
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "required": ["inputs"],
+                            "properties": {
+                                "inputs": { "type": "array", "items": { "type": "string" }, "minItems": 1, "maxItems": 10000 }
+                            }
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "One vector per input, in input order",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "properties": {
+                                    "embeddings": {
+                                        "type": "array",
+                                        "items": { "type": "array", "items": { "type": "number" }, "nullable": true }
+                                    },
+                                    "failed": {
+                                        "type": "array",
+                                        "items": {
+                                            "type": "object",
+                                            "properties": {
+                                                "inputs": { "type": "array", "items": { "type": "integer" } },
+                                                "error": { "type": "string" }
+                                            }
+                                        }
+                                    }
+                                }
+                            })),
+                        },
+                        "400": {
+                            "description": "No inputs, or too many",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "404": {
+                            "description": "Embeddings are not enabled",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "5XX": {
+                            "description": "Every chunk failed; the first chunk's error, answered as /api/chat would",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/feedback": {
+                "post": {
+                    "summary": "Rate an assistant response",
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref("FeedbackRequest")),
+                    },
+                    "responses": {
+                        "201": { "description": "Feedback recorded" },
+                        "400": {
+                            "description": "Unknown conversation or message id",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/feedback/stats": {
+                "get": {
+                    "summary": "Aggregate thumbs up/down counts over a time range",
+                    "parameters": [
+                        query_param("since", "Start of range, unix epoch milliseconds (inclusive)", false),
+                        query_param("until", "End of range, unix epoch milliseconds (exclusive)", false),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Rating counts",
+                            "content": json_content(schema_ref("FeedbackStats")),
+                        },
+                        "400": {
+                            "description": "Empty or inverted range",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/conversations": {
+                "delete": {
+                    "summary": "Delete conversations with no activity within a retention window",
+                    "description": "Admin only: requires Authorization: Bearer $ADMIN_TOKEN",
+                    "parameters": [
+                        {
+                            "name": "older_than",
+                            "in": "query",
+                            "description": "Age such as 3600 (seconds), 30m, 12h or 7d",
+                            "required": true,
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Number of conversations deleted",
+                            "content": json_content(json!({
+                                "type": "object",
+                                "properties": { "deleted": { "type": "integer" } }
+                            })),
+                        },
+                        "400": {
+                            "description": "Unparseable or zero older_than",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "401": {
+                            "description": "Missing or wrong admin token",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "404": { "description": "No ADMIN_TOKEN configured" }
+                    }
+                }
+            },
+            "/api/conversations/{id}": {
+                "delete": {
+                    "summary": "Erase a conversation and all of its messages",
+                    "parameters": [path_param("id", "Conversation id")],
+                    "responses": {
+                        "204": { "description": "Conversation deleted" },
+                        "404": {
+                            "description": "Unknown conversation",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/conversations/search": {
+                "get": {
+                    "summary": "Keyword search over stored message content",
+                    "parameters": [
+                        {
+                            "name": "q",
+                            "in": "query",
+                            "description": "Case-insensitive search text",
+                            "required": true,
+                            "schema": { "type": "string" }
+                        },
+                        query_param("limit", "Maximum number of conversations (default and max 50)", false),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Matching conversations with snippets",
+                            "content": json_content(schema_ref("SearchResults")),
+                        },
+                        "400": {
+                            "description": "Empty query",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/conversations/{id}/messages": {
+                "get": {
+                    "summary": "Page through a conversation, newest messages first",
+                    "parameters": [
+                        path_param("id", "Conversation id"),
+                        query_param("limit", "Page size (default 20, max 100)", false),
+                        query_param("before", "Cursor: only return messages with a smaller id", false),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "A page of messages",
+                            "content": json_content(schema_ref("MessagesPage")),
+                        },
+                        "404": {
+                            "description": "Unknown conversation",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/conversations/{id}/export": {
+                "get": {
+                    "summary": "Download a whole conversation as Markdown or JSON",
+                    "parameters": [
+                        path_param("id", "Conversation id"),
+                        {
+                            "name": "format",
+                            "in": "query",
+                            "required": false,
+                            "description": "md (default) or json",
+                            "schema": { "type": "string", "enum": ["md", "json"] }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The conversation, oldest message first, sent as an attachment named conversation-<id>.md or .json",
+                            "content": {
+                                "text/markdown": { "schema": { "type": "string" } },
+                                "application/json": { "schema": schema_ref("ConversationExport") }
+                            }
+                        },
+                        "400": {
+                            "description": "Unknown format",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "404": {
+                            "description": "Unknown conversation",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/conversations/{id}/replay": {
+                "post": {
+                    "summary": "Re-run a stored conversation's user turns and compare the replies (admin token required)",
+                    "parameters": [path_param("id", "Conversation id")],
+                    "requestBody": {
+                        "required": false,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "properties": {
+                                "endpoint": {
+                                    "type": "string",
+                                    "description": "Endpoint to replay against, as for ChatRequest.endpoint; the primary by default"
+                                }
+                            }
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Original and replayed reply for each user turn",
+                            "content": json_content(schema_ref("ReplayResult")),
+                        },
+                        "400": {
+                            "description": "Endpoint override rejected",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "401": {
+                            "description": "Missing or wrong admin token",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "404": {
+                            "description": "Unknown conversation, or no ADMIN_TOKEN configured",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "429": {
+                            "description": "Daily token budget exhausted",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            },
+            "/api/conversations/{id}/regenerate": {
+                "post": {
+                    "summary": "Replace the conversation's last reply with a new one",
+                    "description": "Re-sends the user message the closing reply answered, with the stored transcript before it, and stores the new reply in place of the old one under a new message id. Turns rendered from a template are re-sent as the stored message. The stored transcript isn't held to the history limit or the prompt-injection guard again. Not served from or stored in the response cache",
+                    "parameters": [path_param("id", "Conversation id")],
+                    "requestBody": {
+                        "required": false,
+                        "content": json_content(json!({
+                            "type": "object",
+                            "properties": {
+                                "temperature": {
+                                    "type": "number",
+                                    "nullable": true,
+                                    "description": "Sampling temperature for the new reply, instead of DEFAULT_TEMPERATURE; between 0 and 2"
+                                },
+                                "seed": {
+                                    "type": "integer",
+                                    "format": "int64",
+                                    "nullable": true,
+                                    "description": "Sampling seed for the new reply"
+                                }
+                            }
+                        })),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The new reply, as stored",
+                            "content": json_content(schema_ref("ChatResponse")),
+                        },
+                        "400": {
+                            "description": "temperature outside 0 to 2",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "404": {
+                            "description": "Unknown conversation",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "409": {
+                            "description": "The conversation doesn't end with a user message and its reply, or gained messages while the new reply was generated (with latest_message_id and the unsaved reply as content). With SERIALIZE_CONVERSATIONS=true, also when another turn was still running after CONVERSATION_TURN_WAIT_SECS",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        },
+                        "500": {
+                            "description": "Upstream LLM failure",
+                            "content": json_content(schema_ref("ErrorResponse")),
+                        }
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "ChatRequest": {
+                    "type": "object",
+                    "required": ["message"],
+                    "properties": {
+                        "message": { "type": "string" },
+                        "history": {
+                            "type": "array",
+                            "items": schema_ref("ChatMessage"),
+                            "description": "Earlier turns, oldest first (at most MAX_HISTORY_MESSAGES)"
+                        },
+                        "conversation_id": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Records the turn under this conversation"
+                        },
+                        "template": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Server-side prompt template to render; the message is available as {{message}}"
+                        },
+                        "variables": {
+                            "type": "object",
+                            "additionalProperties": { "type": "string" },
+                            "description": "Values substituted into the template's {{placeholders}}"
+                        },
+                        "prefill": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Start of the assistant reply, e.g. \"```json\"; included at the start of the returned content. Not allowed with an extra.n above 1"
+                        },
+                        "seed": {
+                            "type": "integer",
+                            "format": "int64",
+                            "nullable": true,
+                            "description": "Sampling seed forwarded to the serving endpoint. Replies are only reproducible if the backend supports seeded sampling"
+                        },
+                        "endpoint": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Serving endpoint to use instead of the configured routing, as name or host/name. Rejected with 400 unless ALLOW_ENDPOINT_OVERRIDE=true and the host is DATABRICKS_HOST or listed in ENDPOINT_OVERRIDE_HOSTS. Such replies bypass the response cache"
+                        },
+                        "priority": {
+                            "type": "string",
+                            "enum": ["high", "normal", "low"],
+                            "default": "normal",
+                            "description": "Queue lane when LLM_LATENCY_SLO_MS limits concurrent upstream calls; waiting higher-priority requests get free slots first. Streamed turns hold their slot until the stream ends"
+                        },
+                        "debug": {
+                            "type": "boolean",
+                            "nullable": true,
+                            "description": "Include the upstream exchange in the reply as _debug. Ignored unless DEBUG_ENDPOINT_ENABLED=true. Non-streaming chat only"
+                        },
+                        "last_message_id": {
+                            "type": "integer",
+                            "format": "int64",
+                            "nullable": true,
+                            "description": "Newest message id the client has of conversation_id (0 if new). If other messages were added since, the turn is refused with 409 and not stored"
+                        },
+                        "schema": {
+                            "type": "object",
+                            "nullable": true,
+                            "description": "JSON Schema the reply must satisfy, sent upstream as response_format. Supports type, enum, const, properties, required, additionalProperties, items, length, pattern, numeric bounds, allOf, anyOf and oneOf; $ref is not resolved. Not accepted for streamed replies"
+                        },
+                        "extra": {
+                            "type": "object",
+                            "nullable": true,
+                            "additionalProperties": true,
+                            "description": "Fields merged into the upstream payload as-is, e.g. logit_bias. messages and stream are ignored; seed and schema take precedence over the same keys here, while temperature and max_tokens here override DEFAULT_TEMPERATURE and DEFAULT_MAX_TOKENS"
+                        }
+                    }
+                },
+                "ChatMessage": {
+                    "type": "object",
+                    "required": ["role", "content"],
+                    "properties": {
+                        "role": { "type": "string", "enum": ["system", "user", "assistant", "tool"] },
+                        "content": { "type": "string" }
+                    }
+                },
+                "ChatResponse": {
+                    "type": "object",
+                    "required": ["content"],
+                    "properties": {
+                        "content": { "type": "string" },
+                        "conversation_id": { "type": "string", "nullable": true },
+                        "message_id": {
+                            "type": "integer",
+                            "format": "int64",
+                            "nullable": true,
+                            "description": "Stored assistant message id, usable with /api/feedback"
+                        },
+                        "finish_reason": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Upstream finish reason, or truncated_by_server when MAX_RESPONSE_CHARS cut the reply (which then ends with …). Also reported in the stream's done event"
+                        },
+                        "model": {
+                            "type": "string",
+                            "nullable": true,
+                            "description": "Serving endpoint that produced the reply, as in X-LLM-Endpoint; after a filter fallback, the fallback endpoint. Also reported in the stream's done event; cached replies report the endpoint that produced them. Omitted for FALLBACK_RESPONSE replies"
+                        },
+                        "_debug": {
+                            "type": "object",
+                            "nullable": true,
+                            "description": "With debug: true and DEBUG_ENDPOINT_ENABLED=true: endpoint, url, transport, the request headers and body sent upstream, and the raw response (the body, or the list of SSE data payloads). The bearer token and credential-like headers and fields are redacted. Not included for cached or fallback replies",
+                            "properties": {
+                                "endpoint": { "type": "string" },
+                                "url": { "type": "string" },
+                                "transport": { "type": "string", "enum": ["buffered", "streamed"] },
+                                "request": {
+                                    "type": "object",
+                                    "properties": {
+                                        "headers": { "type": "object", "additionalProperties": { "type": "string" } },
+                                        "body": { "type": "object" }
+                                    }
+                                },
+                                "response": {}
+                            }
+                        }
+                    }
+                },
+                "LoadTestRequest": {
+                    "type": "object",
+                    "required": ["requests", "concurrency"],
+                    "properties": {
+                        "requests": { "type": "integer", "format": "int64", "minimum": 0 },
+                        "concurrency": { "type": "integer", "format": "int64", "minimum": 0 }
+                    }
+                },
+                "ValidatedChat": {
+                    "type": "object",
+                    "properties": {
+                        "payload": { "type": "object", "description": "Upstream request body, as /api/chat would send it" },
+                        "history_trimmed": { "type": "integer", "description": "Oldest history messages dropped to fit MAX_HISTORY_MESSAGES" },
+                        "language": { "type": "string", "nullable": true, "description": "Detected language, when detection is on" }
+                    }
+                },
+                "LoadTestResult": {
+                    "type": "object",
+                    "properties": {
+                        "total_requests": { "type": "integer", "format": "int64" },
+                        "successful_requests": { "type": "integer", "format": "int64" },
+                        "failed_requests": { "type": "integer", "format": "int64" },
+                        "total_duration_ms": { "type": "integer", "format": "int64" },
+                        "average_response_ms": { "type": "number", "format": "double" },
+                        "requests_per_second": { "type": "number", "format": "double" },
+                        "read_body": { "type": "boolean", "description": "Whether response times include the body download" }
+                    }
+                },
+                "StoredMessage": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "integer", "format": "int64" },
+                        "role": { "type": "string" },
+                        "content": { "type": "string" },
+                        "created_at_ms": { "type": "integer", "format": "int64" }
+                    }
+                },
+                "MessagesPage": {
+                    "type": "object",
+                    "properties": {
+                        "conversation_id": { "type": "string" },
+                        "messages": { "type": "array", "items": schema_ref("StoredMessage") },
+                        "total": { "type": "integer" },
+                        "next_cursor": { "type": "integer", "format": "int64", "nullable": true }
+                    }
+                },
+                "ChatJob": {
+                    "type": "object",
+                    "properties": {
+                        "job_id": { "type": "string" },
+                        "status": { "type": "string", "enum": ["pending", "running", "done", "failed"] },
+                        "created_at_ms": { "type": "integer", "format": "int64" },
+                        "finished_at_ms": { "type": "integer", "format": "int64" },
+                        "result": {
+                            "type": "object",
+                            "description": "What /api/chat would have answered: a ChatResponse when done, an ErrorResponse when failed",
+                            "properties": {
+                                "status": { "type": "integer" },
+                                "body": {}
+                            }
+                        }
+                    }
+                },
+                "ReplayResult": {
+                    "type": "object",
+                    "properties": {
+                        "conversation_id": { "type": "string" },
+                        "endpoint": { "type": "string" },
+                        "turns": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "message_id": { "type": "integer", "format": "int64" },
+                                    "user": { "type": "string" },
+                                    "original": { "type": "string", "nullable": true },
+                                    "replay": { "type": "string", "nullable": true },
+                                    "error": { "type": "string", "description": "Why the replayed call failed" }
+                                }
+                            }
+                        }
+                    }
+                },
+                "ServerStats": {
+                    "type": "object",
+                    "properties": {
+                        "uptime_secs": { "type": "integer" },
+                        "requests_total": { "type": "integer", "description": "HTTP requests answered since startup" },
+                        "in_flight": { "type": "integer", "description": "HTTP requests being handled now" },
+                        "tokens_today": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Tokens used this UTC day; null unless DAILY_TOKEN_BUDGET is set"
+                        },
+                        "cache_hit_ratio": {
+                            "type": "number",
+                            "nullable": true,
+                            "description": "Response cache hits over lookups; null when the cache is off or unused"
+                        },
+                        "upstream_errors_total": { "type": "integer" }
+                    }
+                },
+                "ConversationExport": {
+                    "type": "object",
+                    "properties": {
+                        "conversation_id": { "type": "string" },
+                        "messages": { "type": "array", "items": schema_ref("StoredMessage") }
+                    }
+                },
+                "SearchHit": {
+                    "type": "object",
+                    "properties": {
+                        "conversation_id": { "type": "string" },
+                        "matches": { "type": "integer" },
+                        "snippets": { "type": "array", "items": { "type": "string" } },
+                        "last_match_id": { "type": "integer", "format": "int64" }
+                    }
+                },
+                "SearchResults": {
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string" },
+                        "results": { "type": "array", "items": schema_ref("SearchHit") }
+                    }
+                },
+                "FeedbackRequest": {
+                    "type": "object",
+                    "required": ["conversation_id", "message_id", "rating"],
+                    "properties": {
+                        "conversation_id": { "type": "string" },
+                        "message_id": { "type": "integer", "format": "int64" },
+                        "rating": { "type": "string", "enum": ["up", "down"] },
+                        "comment": { "type": "string", "nullable": true }
+                    }
+                },
+                "FeedbackStats": {
+                    "type": "object",
+                    "properties": {
+                        "since": { "type": "integer", "format": "int64" },
+                        "until": { "type": "integer", "format": "int64" },
+                        "up": { "type": "integer", "format": "int64" },
+                        "down": { "type": "integer", "format": "int64" },
+                        "total": { "type": "integer", "format": "int64" }
+                    }
+                },
+                "TokenizeRequest": {
+                    "type": "object",
+                    "required": ["text"],
+                    "properties": {
+                        "text": { "type": "string" }
+                    }
+                },
+                "TokenizeResponse": {
+                    "type": "object",
+                    "properties": {
+                        "tokens": { "type": "integer", "format": "int64" },
+                        "chars": { "type": "integer", "format": "int64" },
+                        "method": { "type": "string" }
+                    }
+                },
+                "BuildInfo": {
+                    "type": "object",
+                    "properties": {
+                        "version": { "type": "string" },
+                        "git_commit": { "type": "string" },
+                        "build_timestamp": { "type": "string", "format": "date-time" }
+                    }
+                },
+                "ErrorResponse": {
+                    "type": "object",
+                    "properties": {
+                        "error": { "type": "string" },
+                        "request_id": { "type": "string", "description": "Correlation id (X-Request-Id), on upstream failures worth reporting" }
+                    }
+                },
+                "SchemaMismatch": {
+                    "type": "object",
+                    "properties": {
+                        "error": { "type": "string" },
+                        "validation_errors": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "One message per violation, prefixed with the JSON pointer of the value"
+                        },
+                        "content": { "type": "string", "description": "The reply that failed validation" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn query_param(name: &str, description: &str, required: bool) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "description": description,
+        "required": required,
+        "schema": { "type": "integer", "format": "int64", "minimum": 0 }
+    })
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "description": description,
+        "required": true,
+        "schema": { "type": "string" }
+    })
+}
+
+#[get("/api-docs/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(spec())
+}
+
+const SWAGGER_UI_HTML: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <title>LLM Chat API - Swagger UI</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    window.onload = () => {
+      window.ui = SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" });
+    };
+  </script>
+</body>
+</html>
+"##;
+
+#[get("/docs")]
+async fn swagger_ui() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(SWAGGER_UI_HTML)
+}
@@ -0,0 +1,64 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fmt;
+
+use crate::request_id;
+
+// Handler panics are caught by a middleware in main. By the time it sees
+// the panic the stack is unwound, so the hook records the message and
+// backtrace for the current worker thread and the middleware picks it up.
+
+thread_local! {
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Keeps the default hook so panics outside handlers still reach stderr.
+pub fn install_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = format!("{}\n{}", info, Backtrace::force_capture());
+        LAST_PANIC.with(|last| *last.borrow_mut() = Some(report));
+        default_hook(info);
+    }));
+}
+
+// Message and backtrace of the last panic on this thread.
+pub fn take_report() -> String {
+    LAST_PANIC
+        .with(|last| last.borrow_mut().take())
+        .unwrap_or_else(|| "panic (no report captured)".to_string())
+}
+
+// What a panicked handler's request gets: a JSON 500 with the request id in
+// the body and as X-Request-Id. It travels out as an error, so it carries
+// the id itself for the middlewares that would otherwise read it from the
+// request.
+#[derive(Debug)]
+pub struct HandlerPanicked {
+    pub request_id: Option<String>,
+}
+
+impl fmt::Display for HandlerPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("handler panicked")
+    }
+}
+
+impl ResponseError for HandlerPanicked {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::InternalServerError();
+        if let Some(request_id) = &self.request_id {
+            response.insert_header((request_id::HEADER, request_id.as_str()));
+        }
+        response.json(serde_json::json!({
+            "error": "internal error",
+            "request_id": self.request_id,
+        }))
+    }
+}
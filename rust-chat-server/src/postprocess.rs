@@ -0,0 +1,162 @@
+use regex::Regex;
+
+// Rewrites model replies before they reach the client, storage or cache.
+// REPLY_FILTERS_PATH names a file of rules, applied in order, one per line
+// (blank lines and `#` comments are skipped):
+//
+//   redact <regex>                  matches become [REDACTED]
+//   replace <regex> => <text>       matches become <text>; $1 etc. refer to groups
+//   trim                            drop leading and trailing whitespace
+//
+// Patterns are matched one line of the reply at a time, which is what lets
+// streamed and buffered replies come out the same: with filters on, a
+// stream is sent a line at a time. MAX_RESPONSE_CHARS still cuts the
+// filtered reply. Off when REPLY_FILTERS_PATH is unset.
+pub struct ReplyFilters {
+    rewrites: Vec<(Regex, String)>,
+    trim: bool,
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+fn pattern(rule: &str, source: &str) -> Regex {
+    Regex::new(source.trim()).unwrap_or_else(|e| panic!("REPLY_FILTERS_PATH: invalid regex in {:?}: {}", rule, e))
+}
+
+impl ReplyFilters {
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("REPLY_FILTERS_PATH").ok().filter(|p| !p.is_empty())?;
+        let rules = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Can't read REPLY_FILTERS_PATH {}: {}", path, e));
+        let filters = Self::parse(&rules);
+        log::info!(
+            "Filtering replies with {} rewrite rules{}",
+            filters.rewrites.len(),
+            if filters.trim { " and trimming" } else { "" }
+        );
+        Some(filters)
+    }
+
+    fn parse(rules: &str) -> Self {
+        let mut filters = Self {
+            rewrites: Vec::new(),
+            trim: false,
+        };
+        for rule in rules.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (kind, rest) = rule.split_once(char::is_whitespace).unwrap_or((rule, ""));
+            match kind {
+                "redact" => filters.rewrites.push((pattern(rule, rest), REDACTED.to_string())),
+                "replace" => {
+                    let (source, replacement) = rest
+                        .split_once(" => ")
+                        .unwrap_or_else(|| panic!("REPLY_FILTERS_PATH: {:?} must look like replace <regex> => <text>", rule));
+                    filters.rewrites.push((pattern(rule, source), replacement.trim().to_string()));
+                }
+                "trim" => filters.trim = true,
+                _ => panic!("REPLY_FILTERS_PATH: unknown rule {:?}; use redact, replace or trim", rule),
+            }
+        }
+        filters
+    }
+
+    fn rewrite(&self, line: &str) -> String {
+        self.rewrites.iter().fold(line.to_string(), |text, (pattern, replacement)| {
+            pattern.replace_all(&text, replacement.as_str()).into_owned()
+        })
+    }
+
+    // The whole reply at once, as `stream` would produce it.
+    pub fn apply(&self, content: &str) -> String {
+        let mut stream = self.stream();
+        let mut filtered = stream.feed(content);
+        filtered.push_str(&stream.finish());
+        filtered
+    }
+
+    pub fn stream(&self) -> FilterStream<'_> {
+        FilterStream {
+            filters: self,
+            line: String::new(),
+            started: false,
+            held_whitespace: String::new(),
+        }
+    }
+}
+
+// Filters a reply as it arrives. Holds back the unfinished line, and with
+// `trim` any whitespace that might turn out to be trailing.
+pub struct FilterStream<'a> {
+    filters: &'a ReplyFilters,
+    line: String,
+    started: bool,
+    held_whitespace: String,
+}
+
+impl FilterStream<'_> {
+    // The text that is ready to send; often empty.
+    pub fn feed(&mut self, delta: &str) -> String {
+        self.line.push_str(delta);
+        let mut ready = String::new();
+        while let Some(end) = self.line.find('\n') {
+            let line: String = self.line.drain(..=end).collect();
+            ready.push_str(&self.filters.rewrite(&line));
+        }
+        self.release(ready)
+    }
+
+    // Whatever is left once the reply has ended.
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.line);
+        let mut ready = self.release(self.filters.rewrite(&rest));
+        if !self.filters.trim {
+            ready.push_str(&std::mem::take(&mut self.held_whitespace));
+        }
+        ready
+    }
+
+    fn release(&mut self, mut text: String) -> String {
+        if !self.filters.trim {
+            return text;
+        }
+        if !self.started {
+            text = text.trim_start().to_string();
+            if text.is_empty() {
+                return text;
+            }
+            self.started = true;
+        }
+        let kept = text.trim_end().len();
+        if kept == 0 {
+            self.held_whitespace.push_str(&text);
+            return String::new();
+        }
+        let trailing = text.split_off(kept);
+        let ready = std::mem::take(&mut self.held_whitespace) + &text;
+        self.held_whitespace = trailing;
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplyFilters;
+
+    #[test]
+    fn streamed_and_buffered_replies_match() {
+        let filters = ReplyFilters::parse(
+            "redact \\b\\d{3}-\\d{2}-\\d{4}\\b\nreplace <\\|(\\w+)\\|> => [$1]\ntrim\n",
+        );
+        let reply = "  \nSSN 123-45-6789 on file.\n<|end|>  \n\n";
+        assert_eq!(filters.apply(reply), "SSN [REDACTED] on file.\n[end]");
+        for size in 1..6 {
+            let mut stream = filters.stream();
+            let chars: Vec<char> = reply.chars().collect();
+            let mut streamed: String = chars
+                .chunks(size)
+                .map(|chunk| stream.feed(&chunk.iter().collect::<String>()))
+                .collect();
+            streamed.push_str(&stream.finish());
+            assert_eq!(streamed, filters.apply(reply));
+        }
+    }
+}
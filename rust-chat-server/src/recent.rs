@@ -0,0 +1,69 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::admin;
+use crate::AppState;
+
+// Flight recorder: a summary of each of the last RECENT_REQUESTS_CAPACITY
+// (default 200) requests, newest first at GET /admin/recent. Always on and
+// in memory only, for a quick look after an incident.
+pub struct RecentRequests {
+    capacity: usize,
+    entries: Mutex<VecDeque<RequestSummary>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestSummary {
+    pub request_id: Option<String>,
+    pub method: String,
+    // Route pattern, as in the metrics
+    pub route: String,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub finished_at_ms: u64,
+    // The handler's error, when it returned one rather than a response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RecentRequests {
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("RECENT_REQUESTS_CAPACITY")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("RECENT_REQUESTS_CAPACITY must be a positive integer"),
+            })
+            .unwrap_or(200);
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, summary: RequestSummary) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_back();
+        }
+        entries.push_front(summary);
+    }
+
+    fn snapshot(&self) -> Vec<RequestSummary> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[get("/admin/recent")]
+async fn recent_requests(req: HttpRequest, app_state: web::Data<AppState>) -> HttpResponse {
+    if let Err(response) = admin::authorize(&req, &app_state) {
+        return response;
+    }
+    let recent = &app_state.recent_requests;
+    HttpResponse::Ok().json(serde_json::json!({
+        "capacity": recent.capacity,
+        "requests": recent.snapshot(),
+    }))
+}
@@ -0,0 +1,316 @@
+use actix_web::web;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::upstream::{LlmEndpoint, UpstreamError};
+use crate::AppState;
+
+// The serving endpoint deployed in several regions, given as
+// REGION_HOSTS="us-east=adb-1.cloud.databricks.com,eu-west=adb-2.cloud.databricks.com".
+// Each region keeps a latency EWMA fed by a HEAD probe every
+// REGION_PROBE_INTERVAL_SECS (default 30) and by chat calls. Requests go to
+// the fastest healthy region; a region is unhealthy after a failed probe or
+// call until the next success. Replaces the DATABRICKS_HOST endpoint as the
+// primary route.
+//
+// With REGION_WEIGHTS="us-east=3,eu-west=1" traffic is instead spread in
+// proportion to the weights (unlisted regions weigh 1) by smooth weighted
+// round robin over the healthy regions, and conversations hash to a region
+// with the same odds. Latency still ranks regions for admin calls and when
+// every region is unhealthy.
+pub struct RegionPool {
+    regions: Vec<Region>,
+    probe_interval: Duration,
+    // Each region's running score, when REGION_WEIGHTS is set
+    rotation: Option<Mutex<Vec<i64>>>,
+}
+
+struct Region {
+    endpoint: LlmEndpoint,
+    weight: u32,
+    state: Mutex<RegionState>,
+}
+
+#[derive(Default)]
+struct RegionState {
+    ewma_ms: Option<f64>,
+    unhealthy: bool,
+}
+
+// Weight of the newest sample
+const EWMA_ALPHA: f64 = 0.3;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl RegionPool {
+    pub fn from_env(endpoint_name: &str) -> Option<Self> {
+        let hosts = std::env::var("REGION_HOSTS").ok().filter(|h| !h.is_empty())?;
+        let mut weights = weights_from_env();
+        let weighted = !weights.is_empty();
+        let regions: Vec<Region> = hosts
+            .split(',')
+            .map(|entry| {
+                let (region, host) = entry
+                    .trim()
+                    .split_once('=')
+                    .expect("REGION_HOSTS entries must look like region=host");
+                let mut endpoint = LlmEndpoint::new(host.trim(), endpoint_name);
+                endpoint.name = format!("{}@{}", endpoint_name, region.trim());
+                Region {
+                    endpoint,
+                    weight: weights.remove(region.trim()).unwrap_or(1),
+                    state: Mutex::new(RegionState::default()),
+                }
+            })
+            .collect();
+        if let Some(unknown) = weights.keys().next() {
+            panic!("REGION_WEIGHTS names region {:?}, which is not in REGION_HOSTS", unknown);
+        }
+        let probe_secs: u64 = std::env::var("REGION_PROBE_INTERVAL_SECS")
+            .ok()
+            .map(|v| v.parse().expect("REGION_PROBE_INTERVAL_SECS must be a whole number of seconds"))
+            .unwrap_or(30);
+        if probe_secs == 0 {
+            panic!("REGION_PROBE_INTERVAL_SECS must be at least 1");
+        }
+        log::info!(
+            "Routing chat traffic across regions{}: {}",
+            if weighted { " by weight" } else { "" },
+            regions
+                .iter()
+                .map(|r| match weighted {
+                    true => format!("{} ({})", r.endpoint.name, r.weight),
+                    false => r.endpoint.name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Some(Self {
+            rotation: weighted.then(|| Mutex::new(vec![0; regions.len()])),
+            regions,
+            probe_interval: Duration::from_secs(probe_secs),
+        })
+    }
+
+    pub fn endpoints(&self) -> impl Iterator<Item = &LlmEndpoint> {
+        self.regions.iter().map(|region| &region.endpoint)
+    }
+
+    // Lowest-latency healthy region. Unmeasured regions rank after measured
+    // ones, ties go to the order in REGION_HOSTS, and when every region is
+    // unhealthy the fastest of them is still used.
+    pub fn best(&self) -> &LlmEndpoint {
+        let rank = |region: &Region| {
+            let state = region.state.lock().unwrap();
+            (state.unhealthy, state.ewma_ms.unwrap_or(f64::INFINITY))
+        };
+        self.regions
+            .iter()
+            .map(|region| (rank(region), region))
+            .min_by(|(a, _), (b, _)| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)))
+            .map(|(_, region)| &region.endpoint)
+            .expect("REGION_HOSTS has at least one entry")
+    }
+
+    // Where a request without a conversation goes: the next region in the
+    // weighted rotation, or the fastest one.
+    pub fn pick(&self) -> &LlmEndpoint {
+        let Some(rotation) = &self.rotation else {
+            return self.best();
+        };
+        let healthy: Vec<bool> = self.regions.iter().map(|r| !r.state.lock().unwrap().unhealthy).collect();
+        let mut scores = rotation.lock().unwrap();
+        let mut total = 0;
+        let mut chosen: Option<usize> = None;
+        for (index, region) in self.regions.iter().enumerate().filter(|(index, _)| healthy[*index]) {
+            scores[index] += region.weight as i64;
+            total += region.weight as i64;
+            if chosen.is_none_or(|c| scores[index] > scores[c]) {
+                chosen = Some(index);
+            }
+        }
+        match chosen {
+            Some(index) => {
+                scores[index] -= total;
+                &self.regions[index].endpoint
+            }
+            None => self.best(),
+        }
+    }
+
+    // The region a conversation hashes to, unless it is unhealthy. Weighted
+    // pools give each region a share of the hash space.
+    pub fn sticky(&self, hash: u64) -> &LlmEndpoint {
+        let region = match &self.rotation {
+            Some(_) => {
+                let total: u64 = self.regions.iter().map(|r| r.weight as u64).sum();
+                let mut slot = hash % total;
+                self.regions
+                    .iter()
+                    .find(|r| match slot.checked_sub(r.weight as u64) {
+                        Some(rest) => {
+                            slot = rest;
+                            false
+                        }
+                        None => true,
+                    })
+                    .expect("the slot is below the total weight")
+            }
+            None => &self.regions[(hash % self.regions.len() as u64) as usize],
+        };
+        if region.state.lock().unwrap().unhealthy {
+            return self.pick();
+        }
+        &region.endpoint
+    }
+
+    // Each region's weight, or 0 while it is out of rotation. None unless
+    // REGION_WEIGHTS is set.
+    pub fn effective_weights(&self) -> Option<Vec<(&str, u32)>> {
+        self.rotation.as_ref()?;
+        Some(
+            self.regions
+                .iter()
+                .map(|r| {
+                    let weight = if r.state.lock().unwrap().unhealthy { 0 } else { r.weight };
+                    (r.endpoint.name.as_str(), weight)
+                })
+                .collect(),
+        )
+    }
+
+    // Feeds the outcome of a chat call into the region's stats. Client
+    // errors still prove the region is up.
+    pub fn record_call(&self, endpoint: &LlmEndpoint, latency: Duration, error: Option<&UpstreamError>) {
+        let healthy = match error {
+            None => true,
+            // Never reached the region
+            Some(UpstreamError::Shed(_) | UpstreamError::CoolingDown(_)) => return,
+            Some(UpstreamError::Status(status, ..)) => !status.is_server_error(),
+            Some(_) => false,
+        };
+        self.record(&endpoint.name, healthy.then_some(latency));
+    }
+
+    // `latency` is None for a failure.
+    fn record(&self, name: &str, latency: Option<Duration>) {
+        let Some(region) = self.regions.iter().find(|r| r.endpoint.name == name) else {
+            return;
+        };
+        let mut state = region.state.lock().unwrap();
+        match latency {
+            Some(latency) => {
+                let sample = latency.as_secs_f64() * 1000.0;
+                state.ewma_ms = Some(match state.ewma_ms {
+                    Some(ewma) => EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * ewma,
+                    None => sample,
+                });
+                if state.unhealthy {
+                    log::info!("Region {} is healthy again", name);
+                }
+                state.unhealthy = false;
+            }
+            None => {
+                if !state.unhealthy {
+                    log::warn!("Region {} marked unhealthy", name);
+                }
+                state.unhealthy = true;
+            }
+        }
+    }
+}
+
+// REGION_WEIGHTS entries by region name.
+fn weights_from_env() -> HashMap<String, u32> {
+    let Some(spec) = std::env::var("REGION_WEIGHTS").ok().filter(|w| !w.is_empty()) else {
+        return HashMap::new();
+    };
+    spec.split(',')
+        .map(|entry| {
+            let (region, weight) = entry
+                .trim()
+                .split_once('=')
+                .expect("REGION_WEIGHTS entries must look like region=weight");
+            match weight.trim().parse::<u32>() {
+                Ok(weight) if weight > 0 => (region.trim().to_string(), weight),
+                _ => panic!("REGION_WEIGHTS: the weight of {} must be a positive integer", region.trim()),
+            }
+        })
+        .collect()
+}
+
+// Probes every region until the server stops. The first round runs
+// immediately so routing has measurements before much traffic arrives.
+pub async fn run_probes(app_state: web::Data<AppState>, client: reqwest::Client) {
+    let Some(pool) = &app_state.regions else {
+        return;
+    };
+    let mut interval = tokio::time::interval(pool.probe_interval);
+    loop {
+        interval.tick().await;
+        let probes = pool.regions.iter().map(|region| {
+            let request = client
+                .head(&region.endpoint.url)
+                .header("Authorization", format!("Bearer {}", app_state.api_key))
+                .timeout(PROBE_TIMEOUT);
+            async move {
+                let started = Instant::now();
+                let healthy = match request.send().await {
+                    Ok(response) => !response.status().is_server_error(),
+                    Err(e) => {
+                        log::debug!("Probe of {} failed: {}", region.endpoint.name, e);
+                        false
+                    }
+                };
+                pool.record(&region.endpoint.name, healthy.then(|| started.elapsed()));
+            }
+        });
+        futures::future::join_all(probes).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Region, RegionPool, RegionState};
+    use crate::upstream::LlmEndpoint;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn weighted_pool(weights: &[(&str, u32)]) -> RegionPool {
+        let regions = weights
+            .iter()
+            .map(|(name, weight)| {
+                let mut endpoint = LlmEndpoint::new("127.0.0.1:1", "chat");
+                endpoint.name = name.to_string();
+                Region {
+                    endpoint,
+                    weight: *weight,
+                    state: Mutex::new(RegionState::default()),
+                }
+            })
+            .collect::<Vec<_>>();
+        RegionPool {
+            rotation: Some(Mutex::new(vec![0; regions.len()])),
+            regions,
+            probe_interval: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn weighted_rotation_follows_weights_and_skips_unhealthy_regions() {
+        let pool = weighted_pool(&[("big", 3), ("small", 1)]);
+        let picks: Vec<&str> = (0..8).map(|_| pool.pick().name.as_str()).collect();
+        assert_eq!(picks.iter().filter(|name| **name == "big").count(), 6);
+        // Smooth: the small region isn't starved until the end of a cycle
+        assert_eq!(&picks[..4], ["big", "big", "small", "big"]);
+
+        pool.record("big", None);
+        assert!((0..4).all(|_| pool.pick().name == "small"));
+        assert_eq!(pool.effective_weights(), Some(vec![("big", 0), ("small", 1)]));
+
+        // Conversations split the hash space 3:1 while both are healthy
+        let pool = weighted_pool(&[("big", 3), ("small", 1)]);
+        let sticky: Vec<&str> = (0..4).map(|hash| pool.sticky(hash).name.as_str()).collect();
+        assert_eq!(sticky, ["big", "big", "big", "small"]);
+    }
+}
@@ -0,0 +1,34 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::HttpMessage;
+use rand::Rng;
+
+pub const HEADER: &str = "x-request-id";
+
+// Correlation id for a request, available to handlers as
+// `web::ReqData<RequestId>`.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// Reuses a sane client-supplied X-Request-Id, otherwise generates one, and
+// stores it in the request extensions.
+pub fn assign(req: &ServiceRequest) -> String {
+    let request_id = req
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= 128
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(generate);
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    request_id
+}
+
+fn generate() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
@@ -0,0 +1,139 @@
+use actix_web::web::Bytes;
+use futures::Stream;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+use crate::stream::Event;
+
+// Keeps the events of each /api/chat/stream reply for STREAM_RESUME_SECS
+// (default 30, 0 disables) after it finishes, so a client that lost the
+// connection can send the request again with `Last-Event-ID` and pick up
+// after the last event it saw. Event ids are `<stream id>/<sequence>`.
+// While buffering, the reply is generated to the end even if the client
+// goes away.
+pub struct ResumeBuffers {
+    window: Duration,
+    streams: Mutex<HashMap<String, Arc<Replay>>>,
+}
+
+struct Replay {
+    state: Mutex<ReplayState>,
+    // Bumped on every new event and when the reply finishes
+    changed: watch::Sender<usize>,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    events: Vec<Bytes>,
+    finished_at: Option<Instant>,
+}
+
+pub const LAST_EVENT_ID: &str = "last-event-id";
+
+impl ResumeBuffers {
+    pub fn from_env() -> Option<Self> {
+        let secs: u64 = std::env::var("STREAM_RESUME_SECS")
+            .ok()
+            .map(|v| v.parse().expect("STREAM_RESUME_SECS must be a whole number of seconds"))
+            .unwrap_or(30);
+        (secs > 0).then(|| Self {
+            window: Duration::from_secs(secs),
+            streams: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Buffers everything `pump` sends on `events` and returns the stream id
+    // with the body for the first client.
+    pub fn record(
+        &self,
+        mut events: mpsc::Receiver<Event>,
+    ) -> (String, impl Stream<Item = Result<Bytes, actix_web::Error>>) {
+        let stream_id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let replay = Arc::new(Replay {
+            state: Mutex::new(ReplayState::default()),
+            changed: watch::Sender::new(0),
+        });
+        {
+            let mut streams = self.streams.lock().unwrap();
+            let window = self.window;
+            streams.retain(|_, replay| {
+                replay
+                    .state
+                    .lock()
+                    .unwrap()
+                    .finished_at
+                    .is_none_or(|at| at.elapsed() < window)
+            });
+            streams.insert(stream_id.clone(), replay.clone());
+        }
+
+        let recorder = replay.clone();
+        let id = stream_id.clone();
+        actix_web::rt::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let mut state = recorder.state.lock().unwrap();
+                let event_id = format!("{}/{}", id, state.events.len() + 1);
+                state.events.push(event.to_sse(Some(&event_id)));
+                drop(state);
+                recorder.changed.send_modify(|n| *n += 1);
+            }
+            recorder.state.lock().unwrap().finished_at = Some(Instant::now());
+            recorder.changed.send_modify(|n| *n += 1);
+        });
+
+        let body = follow(replay, 0);
+        (stream_id, body)
+    }
+
+    // The rest of the reply after `last_event_id`, or None when that stream
+    // is unknown or has expired.
+    pub fn resume(&self, last_event_id: &str) -> Option<impl Stream<Item = Result<Bytes, actix_web::Error>>> {
+        let (stream_id, seen) = last_event_id.trim().split_once('/')?;
+        let seen: usize = seen.parse().ok()?;
+        let replay = self.streams.lock().unwrap().get(stream_id)?.clone();
+        {
+            let state = replay.state.lock().unwrap();
+            let expired = state.finished_at.is_some_and(|at| at.elapsed() >= self.window);
+            if expired || seen > state.events.len() {
+                return None;
+            }
+        }
+        log::info!("Resuming stream {} after event {}", stream_id, seen);
+        Some(follow(replay, seen))
+    }
+}
+
+// Tells a reconnecting client to send its request again from scratch.
+pub fn restart_event() -> Bytes {
+    Bytes::from(format!(
+        "event: restart\ndata: {}\n\n",
+        serde_json::json!({ "error": "The stream can't be resumed; send the request again" })
+    ))
+}
+
+// Yields buffered events from index `next` on, then live ones until the
+// reply finishes.
+fn follow(replay: Arc<Replay>, next: usize) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let changes = replay.changed.subscribe();
+    futures::stream::unfold((replay, next, changes), |(replay, next, mut changes)| async move {
+        loop {
+            let event = {
+                let state = replay.state.lock().unwrap();
+                match state.events.get(next) {
+                    Some(event) => Some(event.clone()),
+                    None if state.finished_at.is_some() => return None,
+                    None => None,
+                }
+            };
+            if let Some(event) = event {
+                return Some((Ok(event), (replay, next + 1, changes)));
+            }
+            if changes.changed().await.is_err() {
+                return None;
+            }
+        }
+    })
+}
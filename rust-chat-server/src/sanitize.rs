@@ -0,0 +1,61 @@
+use icu_normalizer::ComposingNormalizer;
+
+// Canonical composition, so visually identical strings compare equal. The
+// normalizer borrows baked-in data, so constructing it per call is free.
+pub fn nfc(input: &str) -> String {
+    ComposingNormalizer::new_nfc().normalize(input)
+}
+
+// Full cleanup applied to every piece of user-supplied text.
+pub fn prepare(input: &str, normalize_unicode: bool) -> String {
+    let cleaned = clean_text(input);
+    if normalize_unicode {
+        nfc(&cleaned)
+    } else {
+        cleaned
+    }
+}
+
+// Removes control characters that confuse the model or corrupt logs.
+// Line endings are normalized to \n; newline and tab are the only control
+// characters kept.
+pub fn clean_text(input: &str) -> String {
+    let mut cleaned = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => {
+                if chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                cleaned.push('\n');
+            }
+            '\n' | '\t' => cleaned.push(c),
+            c if c.is_control() => {}
+            c => cleaned.push(c),
+        }
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::clean_text;
+
+    #[test]
+    fn strips_nul_and_control_bytes_from_payload() {
+        let payload: serde_json::Value = serde_json::from_str(
+            r#"{"message": "he\u0000llo\u0007 wo\u001brld\u007f\u0085!"}"#,
+        )
+        .unwrap();
+        let message = payload["message"].as_str().unwrap();
+        assert!(message.contains('\0'));
+
+        assert_eq!(clean_text(message), "hello world!");
+    }
+
+    #[test]
+    fn keeps_tabs_and_normalizes_line_endings() {
+        assert_eq!(clean_text("a\tb\r\nc\rd\ne"), "a\tb\nc\nd\ne");
+    }
+}
@@ -0,0 +1,208 @@
+use regex::Regex;
+use serde_json::Value;
+
+// Checks a reply against the `schema` of a chat request. Supports the
+// JSON Schema keywords structured replies tend to use: type, enum, const,
+// properties, required, additionalProperties, items, minItems/maxItems,
+// minLength/maxLength, pattern, minimum/maximum (and the exclusive forms),
+// allOf, anyOf and oneOf. Other keywords, including $ref, are ignored.
+//
+// Returns one message per violation, prefixed with the JSON pointer of the
+// offending value.
+pub fn validate_reply(schema: &Value, content: &str) -> Vec<String> {
+    match serde_json::from_str::<Value>(content.trim()) {
+        Ok(instance) => {
+            let mut errors = Vec::new();
+            check(schema, &instance, "", &mut errors);
+            errors
+        }
+        Err(e) => vec![format!("reply is not valid JSON: {}", e)],
+    }
+}
+
+// Whether a client-supplied schema can be used at all.
+pub fn is_schema(schema: &Value) -> bool {
+    matches!(schema, Value::Object(_) | Value::Bool(_))
+}
+
+// Follow-up turn asking the model to correct a reply that failed validation.
+pub fn repair_prompt(errors: &[String]) -> String {
+    format!(
+        "Your reply did not match the required JSON schema:\n- {}\nReply again with only the corrected JSON.",
+        errors.join("\n- ")
+    )
+}
+
+fn check(schema: &Value, instance: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(false) => {
+            errors.push(format!("{}: no value is allowed here", pointer(path)));
+            return;
+        }
+        _ => return,
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let allowed: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| has_type(instance, t)) {
+            errors.push(format!(
+                "{}: expected {}, got {}",
+                pointer(path),
+                allowed.join(" or "),
+                type_name(instance)
+            ));
+            return;
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(instance) {
+            errors.push(format!("{}: {} is not one of {}", pointer(path), instance, Value::Array(options.clone())));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != instance {
+            errors.push(format!("{}: expected {}", pointer(path), expected));
+        }
+    }
+
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for sub in all {
+            check(sub, instance, path, errors);
+        }
+    }
+    if let Some(Value::Array(any)) = schema.get("anyOf") {
+        if !any.iter().any(|sub| passes(sub, instance)) {
+            errors.push(format!("{}: matches none of anyOf", pointer(path)));
+        }
+    }
+    if let Some(Value::Array(one)) = schema.get("oneOf") {
+        let matched = one.iter().filter(|sub| passes(sub, instance)).count();
+        if matched != 1 {
+            errors.push(format!("{}: matches {} of oneOf, expected exactly 1", pointer(path), matched));
+        }
+    }
+
+    match instance {
+        Value::Object(object) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(name) {
+                        errors.push(format!("{}: missing required property {:?}", pointer(path), name));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, value) in object {
+                let child = format!("{}/{}", path, escape(name));
+                match properties.and_then(|p| p.get(name)) {
+                    Some(sub) => check(sub, value, &child, errors),
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            errors.push(format!("{}: unexpected property {:?}", pointer(path), name))
+                        }
+                        Some(sub) => check(sub, value, &child, errors),
+                        None => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+                if (items.len() as u64) < min {
+                    errors.push(format!("{}: expected at least {} items, got {}", pointer(path), min, items.len()));
+                }
+            }
+            if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+                if items.len() as u64 > max {
+                    errors.push(format!("{}: expected at most {} items, got {}", pointer(path), max, items.len()));
+                }
+            }
+            if let Some(sub) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    check(sub, item, &format!("{}/{}", path, i), errors);
+                }
+            }
+        }
+        Value::String(s) => {
+            let chars = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if chars < min {
+                    errors.push(format!("{}: shorter than {} characters", pointer(path), min));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if chars > max {
+                    errors.push(format!("{}: longer than {} characters", pointer(path), max));
+                }
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                // An invalid pattern is the caller's problem, not the model's
+                if let Ok(re) = Regex::new(pattern) {
+                    if !re.is_match(s) {
+                        errors.push(format!("{}: does not match pattern {:?}", pointer(path), pattern));
+                    }
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+            if let Some(min) = bound("minimum").filter(|min| n < *min) {
+                errors.push(format!("{}: {} is less than {}", pointer(path), n, min));
+            }
+            if let Some(max) = bound("maximum").filter(|max| n > *max) {
+                errors.push(format!("{}: {} is greater than {}", pointer(path), n, max));
+            }
+            if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+                errors.push(format!("{}: {} is not greater than {}", pointer(path), n, min));
+            }
+            if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+                errors.push(format!("{}: {} is not less than {}", pointer(path), n, max));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn passes(schema: &Value, instance: &Value) -> bool {
+    let mut errors = Vec::new();
+    check(schema, instance, "", &mut errors);
+    errors.is_empty()
+}
+
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "integer" => instance.as_i64().is_some() || instance.as_u64().is_some(),
+        "number" => instance.is_number(),
+        other => type_name(instance) == other,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn pointer(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+// RFC 6901 escaping for a pointer segment
+fn escape(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
@@ -0,0 +1,81 @@
+use std::fmt::Write;
+use std::net::UdpSocket;
+use std::sync::OnceLock;
+
+// Optional StatsD mirror of the Prometheus families in metrics.rs. With
+// STATSD_ADDR=host:port every counter increment, gauge update and
+// histogram observation is also sent as a UDP datagram, named
+// STATSD_PREFIX + the Prometheus family name. Labels become DogStatsD tags
+// (`|#label:value`) after the fixed STATSD_TAGS ("env:prod,team:ml").
+// Histograms of seconds are sent as millisecond timers, others as `h`.
+// Sends never block and failures are dropped.
+struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+}
+
+static SINK: OnceLock<StatsdSink> = OnceLock::new();
+
+pub fn install_from_env() -> std::io::Result<()> {
+    let Some(addr) = std::env::var("STATSD_ADDR").ok().filter(|a| !a.is_empty()) else {
+        return Ok(());
+    };
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(&addr)?;
+    socket.set_nonblocking(true)?;
+    let tags = std::env::var("STATSD_TAGS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect();
+    log::info!("Sending metrics to StatsD at {}", addr);
+    let _ = SINK.set(StatsdSink {
+        socket,
+        prefix: std::env::var("STATSD_PREFIX").unwrap_or_default(),
+        tags,
+    });
+    Ok(())
+}
+
+pub fn count(name: &str, labels: &[&str], values: &[&str], amount: f64) {
+    send(name, labels, values, amount, "c");
+}
+
+pub fn gauge(name: &str, value: f64) {
+    send(name, &[], &[], value, "g");
+}
+
+pub fn histogram(name: &str, labels: &[&str], values: &[&str], value: f64) {
+    match name.strip_suffix("_seconds") {
+        Some(_) => send(name, labels, values, value * 1000.0, "ms"),
+        None => send(name, labels, values, value, "h"),
+    }
+}
+
+fn send(name: &str, labels: &[&str], values: &[&str], value: f64, kind: &str) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let mut datagram = format!("{}{}:{}|{}", sink.prefix, name, value, kind);
+    let mut tags = sink.tags.iter().cloned().chain(
+        labels
+            .iter()
+            .zip(values)
+            .map(|(label, value)| format!("{}:{}", label, sanitize_tag(value))),
+    );
+    if let Some(first) = tags.next() {
+        let _ = write!(datagram, "|#{}", first);
+        for tag in tags {
+            let _ = write!(datagram, ",{}", tag);
+        }
+    }
+    let _ = sink.socket.send(datagram.as_bytes());
+}
+
+// `|`, `,` and `#` would break the datagram
+fn sanitize_tag(value: &str) -> String {
+    value.replace(['|', ',', '#', '\n'], "_")
+}
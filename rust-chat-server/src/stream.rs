@@ -0,0 +1,526 @@
+use actix_web::http::header::{HeaderMap, ACCEPT};
+use actix_web::web::Bytes;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::cancel::{self, Cancellations, Registration};
+use crate::chat::{self, ChatRequest};
+use crate::client_ip;
+use crate::events::CompletionEvent;
+use crate::limiter::OwnedPermit;
+use crate::request_id::RequestId;
+use crate::resume;
+use crate::trace::TraceHeaders;
+use crate::turns::ConversationTurns;
+use crate::upstream::{self, SseDecoder, StreamEvent, UpstreamError};
+use crate::AppState;
+
+// Server-sent events emitted by /api/chat/stream:
+//
+//   data: {"delta": "..."}                       one per content fragment
+//   event: done   data: {"finish_reason": "...", "completion_tokens": n,
+//                        "elapsed_ms": n, "tokens_per_second": x,
+//                        "model": "..."}
+//                                             after the last fragment
+//   event: error  data: {"error": "..."}         the stream was cut short
+//
+// Exactly one of `done` or `error` ends every stream. When the overall
+// deadline passes and partial replies are enabled, the stream ends with
+// `done` and finish_reason "timeout" instead of an error; a generation
+// stopped through POST /api/chat/{request_id}/cancel ends the same way with
+// finish_reason "cancelled".
+
+pub struct StreamSettings {
+    // Longest gap allowed between upstream chunks (STREAM_IDLE_TIMEOUT_SECS)
+    pub idle_timeout: Duration,
+    // Deadline for the whole stream (STREAM_TIMEOUT_SECS)
+    pub total_timeout: Duration,
+    // Finish with the tokens received so far on deadline
+    // (STREAM_PARTIAL_ON_TIMEOUT, default on)
+    pub partial_on_timeout: bool,
+}
+
+fn secs_from_env(key: &str, default: u64) -> Duration {
+    let secs = std::env::var(key)
+        .ok()
+        .map(|v| {
+            v.parse::<u64>()
+                .unwrap_or_else(|_| panic!("{} must be a whole number of seconds", key))
+        })
+        .unwrap_or(default);
+    Duration::from_secs(secs)
+}
+
+impl StreamSettings {
+    pub fn from_env() -> Self {
+        Self {
+            idle_timeout: secs_from_env("STREAM_IDLE_TIMEOUT_SECS", 30),
+            total_timeout: secs_from_env("STREAM_TIMEOUT_SECS", 300),
+            partial_on_timeout: std::env::var("STREAM_PARTIAL_ON_TIMEOUT")
+                .map(|v| v != "false")
+                .unwrap_or(true),
+        }
+    }
+}
+
+// What `pump` needs to know about the call it relays, for logs, metrics and
+// the completion event.
+pub struct Generation {
+    pub request_id: String,
+    pub endpoint_name: String,
+    pub route: &'static str,
+    pub client_ip: Option<IpAddr>,
+    pub started: Instant,
+    pub cancel: Registration,
+    // The limiter slot, released when the pump returns
+    pub permit: Option<OwnedPermit>,
+}
+
+// What `pump` produces; the SSE and WebSocket endpoints encode it their
+// own way.
+pub enum Event {
+    Delta(String),
+    Done(serde_json::Value),
+    Error(&'static str),
+}
+
+impl Event {
+    // With `id`, the event carries an `id:` line for Last-Event-ID
+    pub fn to_sse(&self, id: Option<&str>) -> Bytes {
+        let id = id.map(|id| format!("id: {}\n", id)).unwrap_or_default();
+        match self {
+            Event::Delta(delta) => Bytes::from(format!("{}data: {}\n\n", id, serde_json::json!({ "delta": delta }))),
+            Event::Done(data) => Bytes::from(format!("{}event: done\ndata: {}\n\n", id, data)),
+            Event::Error(error) => Bytes::from(format!(
+                "{}event: error\ndata: {}\n\n",
+                id,
+                serde_json::json!({ "error": error })
+            )),
+        }
+    }
+
+    // `{"type": "delta" | "done" | "error", ...}` with the SSE data fields
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Event::Delta(delta) => serde_json::json!({ "type": "delta", "delta": delta }),
+            Event::Done(data) => {
+                let mut data = data.clone();
+                data["type"] = "done".into();
+                data
+            }
+            Event::Error(error) => serde_json::json!({ "type": "error", "error": error }),
+        }
+    }
+}
+
+// Whether an /api/chat client asked for server-sent events. It must name
+// text/event-stream itself, wildcards don't count, and rank it above
+// application/json: by q value, then by which is listed first.
+pub fn wants_event_stream(headers: &HeaderMap) -> bool {
+    let mut best: Option<(&str, f32)> = None;
+    for accept in headers.get_all(ACCEPT).filter_map(|value| value.to_str().ok()) {
+        for range in accept.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default();
+            let media_type = match media_type.to_ascii_lowercase().as_str() {
+                "text/event-stream" => "text/event-stream",
+                "application/json" => "application/json",
+                _ => continue,
+            };
+            let q = parts
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((media_type, q));
+            }
+        }
+    }
+    best.is_some_and(|(media_type, _)| media_type == "text/event-stream")
+}
+
+#[post("/api/chat/stream")]
+async fn chat_stream(
+    http_req: HttpRequest,
+    req: web::Json<ChatRequest>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    stream_chat(http_req, req.into_inner(), request_id.into_inner(), client, app_state).await
+}
+
+// The SSE reply, for /api/chat/stream and for /api/chat when the client
+// asked for it in Accept.
+pub async fn stream_chat(
+    http_req: HttpRequest,
+    req: ChatRequest,
+    request_id: RequestId,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let started = Instant::now();
+    let last_event_id = http_req
+        .headers()
+        .get(resume::LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok());
+    if let (Some(buffers), Some(last_event_id)) = (&app_state.stream_resume, last_event_id) {
+        let mut response = HttpResponse::Ok();
+        response
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"));
+        return Ok(match buffers.resume(last_event_id) {
+            Some(body) => response.streaming(body),
+            None => response.body(resume::restart_event()),
+        });
+    }
+
+    let turn = match ConversationTurns::begin(&app_state, req.conversation_id.as_deref()).await {
+        Ok(turn) => turn,
+        Err(response) => return Ok(response),
+    };
+    let prepared = match chat::prepare_chat(req, &app_state) {
+        Ok(prepared) => prepared,
+        Err(response) => return Ok(response),
+    };
+    if prepared.req.schema.is_some() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "schema is not supported on streamed replies"
+        })));
+    }
+    let payload = prepared.payload();
+
+    let (selected, route) = chat::select_endpoint(&app_state, &prepared);
+    let endpoint = &*selected;
+    let client_ip = client_ip::client_ip(&app_state, &http_req);
+    chat::audit_request(&app_state, &request_id, client_ip, endpoint, &prepared);
+    log::info!("Streaming from LLM endpoint: {} ({})", endpoint.url, route);
+
+    let trace = TraceHeaders::for_request(&http_req, &request_id, app_state.trace_context, &app_state.forward_headers);
+    // Registered before connecting so a cancel sent meanwhile still lands
+    let cancel = Cancellations::register(&app_state, &request_id.0);
+    let opened = chat::open_llm_stream(&app_state, &client, endpoint, &payload, &trace, prepared.req.priority).await;
+    let (upstream_response, permit) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            chat::count_upstream_error(&app_state, &endpoint.name, &e);
+            return chat::upstream_failure(e, &request_id);
+        }
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("X-LLM-Endpoint", endpoint.name.as_str()))
+        .insert_header(("X-LLM-Route", route))
+        .insert_header((cancel::TOKEN_HEADER, cancel.token.as_str()));
+    if prepared.history_trimmed > 0 {
+        response.insert_header(("X-History-Trimmed", prepared.history_trimmed.to_string()));
+    }
+    if let Some(language) = prepared.language {
+        response.insert_header(("X-Detected-Language", language));
+    }
+    if let Some(header) = chat::injection_header(&app_state, prepared.injection_score) {
+        response.insert_header(header);
+    }
+
+    let (tx, rx) = mpsc::channel::<Event>(32);
+    let generation = Generation {
+        request_id: request_id.0.clone(),
+        endpoint_name: endpoint.name.clone(),
+        route,
+        client_ip,
+        started,
+        cancel,
+        permit,
+    };
+    let pumped = pump(upstream_response, tx, app_state.clone(), prepared.req, generation);
+    // The turn lasts until the reply is stored
+    actix_web::rt::spawn(async move {
+        pumped.await;
+        drop(turn);
+    });
+
+    if let Some(buffers) = &app_state.stream_resume {
+        let (stream_id, body) = buffers.record(rx);
+        response.insert_header(("X-Stream-Id", stream_id));
+        return Ok(response.streaming(body));
+    }
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok::<_, actix_web::Error>(event.to_sse(None)), rx))
+    });
+    Ok(response.streaming(body))
+}
+
+// Relays upstream deltas to the client until the stream finishes, stalls
+// the client goes away or it is cancelled. Returning drops the upstream
+// response, which closes that connection.
+pub async fn pump(
+    mut upstream_response: reqwest::Response,
+    tx: mpsc::Sender<Event>,
+    app_state: web::Data<AppState>,
+    req: ChatRequest,
+    generation: Generation,
+) {
+    let Generation {
+        request_id,
+        endpoint_name,
+        route,
+        client_ip,
+        started,
+        cancel,
+        permit: _permit,
+    } = generation;
+    let settings = &app_state.stream_settings;
+    let idle_timeout = settings.idle_timeout;
+    let deadline = Instant::now() + settings.total_timeout;
+    let mut decoder = SseDecoder::default();
+    let mut content = String::new();
+    // Counted as we go for MAX_RESPONSE_CHARS
+    let mut content_chars = 0;
+    // REPLY_FILTERS_PATH rewrites; the prefill is part of the reply
+    let mut filter = app_state.reply_filters.as_ref().map(|filters| filters.stream());
+    // The prefill goes out first so the client sees the whole reply
+    if let Some(prefill) = &req.prefill {
+        let prefill = match &mut filter {
+            Some(filter) => filter.feed(prefill),
+            None => prefill.clone(),
+        };
+        content.push_str(&prefill);
+        content_chars += prefill.chars().count();
+        if !prefill.is_empty() && tx.send(Event::Delta(prefill)).await.is_err() {
+            return;
+        }
+    }
+    let mut finish_reason: Option<String> = None;
+    let mut usage = None;
+    // Each delta is counted as one token; throughput is measured from the
+    // first token to the end of the stream.
+    let mut completion_tokens: u64 = 0;
+    let mut first_token_at: Option<Instant> = None;
+    let mut received_bytes = 0;
+
+    loop {
+        let wait = idle_timeout.min(deadline.saturating_duration_since(Instant::now()));
+        let chunk = tokio::select! {
+            chunk = tokio::time::timeout(wait, upstream_response.chunk()) => chunk,
+            _ = cancel.cancelled() => {
+                log::info!("Stream for request {} cancelled after {} chars", request_id, content.len());
+                finish_reason = Some("cancelled".to_string());
+                break;
+            }
+        };
+        let chunk = match chunk {
+            Ok(Ok(Some(chunk))) => chunk,
+            // Upstream closed without [DONE]; treat what we have as complete
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                log::error!("Upstream stream for request {} failed: {}", request_id, e);
+                chat::count_upstream_error(&app_state, &endpoint_name, &UpstreamError::Send(e));
+                let _ = tx.send(Event::Error("Upstream stream failed")).await;
+                return;
+            }
+            Err(_) if Instant::now() >= deadline => {
+                if !settings.partial_on_timeout {
+                    log::warn!("Stream for request {} hit its deadline, aborting", request_id);
+                    let stalled = UpstreamError::Stalled(settings.total_timeout);
+                    chat::count_upstream_error(&app_state, &endpoint_name, &stalled);
+                    let _ = tx.send(Event::Error("Stream timed out")).await;
+                    return;
+                }
+                log::warn!(
+                    "Stream for request {} hit its deadline after {} chars, returning partial reply",
+                    request_id,
+                    content.len()
+                );
+                finish_reason = Some("timeout".to_string());
+                break;
+            }
+            Err(_) => {
+                log::warn!(
+                    "Upstream stream for request {} stalled: no data for {}s, aborting",
+                    request_id,
+                    idle_timeout.as_secs()
+                );
+                let stalled = UpstreamError::Stalled(idle_timeout);
+                chat::count_upstream_error(&app_state, &endpoint_name, &stalled);
+                let _ = tx.send(Event::Error("Upstream stopped sending tokens")).await;
+                return;
+            }
+        };
+        received_bytes += chunk.len();
+        if received_bytes > upstream::max_upstream_bytes() {
+            log::error!("Upstream stream for request {} passed MAX_UPSTREAM_BYTES, aborting", request_id);
+            let too_large = UpstreamError::TooLarge(upstream::max_upstream_bytes());
+            chat::count_upstream_error(&app_state, &endpoint_name, &too_large);
+            let _ = tx.send(Event::Error("Upstream response too large")).await;
+            return;
+        }
+
+        let mut done = false;
+        for data in decoder.feed(&chunk) {
+            if let Some(reported) = upstream::stream_usage(&data) {
+                usage = Some(reported);
+            }
+            match upstream::parse_stream_data(&data) {
+                Some(StreamEvent::Delta(delta)) => {
+                    first_token_at.get_or_insert_with(Instant::now);
+                    completion_tokens += 1;
+                    let mut delta = match &mut filter {
+                        Some(filter) => filter.feed(&delta),
+                        None => delta,
+                    };
+                    if delta.is_empty() {
+                        continue;
+                    }
+                    let room = app_state.max_response_chars.map(|max| max.saturating_sub(content_chars));
+                    let truncated = room.is_some_and(|room| chat::truncate_reply(&mut delta, room));
+                    content_chars += delta.chars().count();
+                    content.push_str(&delta);
+                    if tx.send(Event::Delta(delta)).await.is_err() {
+                        log::info!("Client disconnected from stream for request {}", request_id);
+                        return;
+                    }
+                    // The rest of the upstream reply is dropped unread
+                    if truncated {
+                        log::warn!("Stream for request {} exceeded MAX_RESPONSE_CHARS, truncated", request_id);
+                        finish_reason = Some(chat::TRUNCATED_BY_SERVER.to_string());
+                        done = true;
+                        break;
+                    }
+                }
+                Some(StreamEvent::Finished(reason)) => finish_reason = reason,
+                Some(StreamEvent::Done) => done = true,
+                None => {}
+            }
+        }
+        if done {
+            break;
+        }
+    }
+
+    // Flush what the filter held back, unless the reply was already cut
+    if let Some(mut rest) = filter.as_mut().map(|filter| filter.finish()).filter(|rest| !rest.is_empty()) {
+        if finish_reason.as_deref() != Some(chat::TRUNCATED_BY_SERVER) {
+            let room = app_state.max_response_chars.map(|max| max.saturating_sub(content_chars));
+            if room.is_some_and(|room| chat::truncate_reply(&mut rest, room)) {
+                finish_reason = Some(chat::TRUNCATED_BY_SERVER.to_string());
+            }
+            content.push_str(&rest);
+            if tx.send(Event::Delta(rest)).await.is_err() {
+                return;
+            }
+        }
+    }
+    let finish_reason = finish_reason.unwrap_or_else(|| "stop".to_string());
+    let elapsed = first_token_at.map(|at| at.elapsed()).unwrap_or_default();
+    let tokens_per_second = (completion_tokens > 0 && !elapsed.is_zero())
+        .then(|| completion_tokens as f64 / elapsed.as_secs_f64());
+    if let Some(rate) = tokens_per_second {
+        app_state.metrics.tokens_per_second.observe(&[&endpoint_name], rate);
+    }
+    log::info!(
+        "Stream for request {} completed ({}): {} tokens in {} ms",
+        request_id,
+        finish_reason,
+        completion_tokens,
+        elapsed.as_millis()
+    );
+    chat::record_usage(&app_state, usage);
+    if chat::record_turn(&app_state, &req, &content).is_err() {
+        log::warn!("Conversation changed during stream for request {}; turn not saved", request_id);
+        let _ = tx
+            .send(Event::Error("The conversation has new messages; this turn was not saved"))
+            .await;
+        return;
+    }
+    app_state.completion_events.publish(CompletionEvent {
+        request_id: request_id.clone(),
+        conversation_id: req.conversation_id.clone(),
+        model: endpoint_name.clone(),
+        route,
+        usage,
+        finish_reason: Some(finish_reason.clone()),
+        latency_ms: started.elapsed().as_millis() as u64,
+        client_ip,
+    });
+    let _ = tx
+        .send(Event::Done(serde_json::json!({
+            "finish_reason": finish_reason,
+            "completion_tokens": completion_tokens,
+            "elapsed_ms": elapsed.as_millis() as u64,
+            "tokens_per_second": tokens_per_second,
+            "model": endpoint_name,
+        })))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wants_event_stream;
+    use actix_web::http::header::{HeaderMap, HeaderValue, ACCEPT};
+
+    fn accepts(value: &str) -> bool {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_str(value).unwrap());
+        wants_event_stream(&headers)
+    }
+
+    #[test]
+    fn event_stream_must_be_named_and_preferred() {
+        assert!(!wants_event_stream(&HeaderMap::new()));
+        assert!(!accepts("*/*"));
+        assert!(!accepts("text/*"));
+        assert!(accepts("text/event-stream"));
+        assert!(accepts("Text/Event-Stream; charset=utf-8"));
+        assert!(accepts("text/event-stream, application/json"));
+        assert!(!accepts("application/json, text/event-stream"));
+        assert!(accepts("application/json;q=0.5, text/event-stream"));
+        assert!(!accepts("text/event-stream;q=0"));
+        assert!(accepts("text/event-stream, */*;q=0.1"));
+    }
+
+    #[actix_web::test]
+    async fn streamed_reply_is_charged_to_the_budget() {
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":5,\"completion_tokens\":2,\"total_tokens\":7}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let endpoint = crate::fixed_upstream("text/event-stream", body).await;
+        let path = std::env::temp_dir().join(format!("stream-budget-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let base_url = crate::test_server(endpoint, |app_state| {
+            app_state.admin_token = Some("secret".to_string());
+            app_state.token_budget = Some(crate::budget::TokenBudget::new(100, path.clone()));
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let reply = client
+            .post(format!("{}/api/chat/stream", base_url))
+            .json(&serde_json::json!({ "message": "Hello" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        let events = reply.text().await.unwrap();
+        assert!(events.contains("event: done"), "{}", events);
+        let budget = client
+            .get(format!("{}/admin/budget", base_url))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert_eq!(budget["used"], 7);
+        assert_eq!(budget["remaining"], 93);
+        let _ = std::fs::remove_file(&path);
+    }
+}
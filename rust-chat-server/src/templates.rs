@@ -0,0 +1,115 @@
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// Placeholders look like {{ name }}. The user's message is always available
+// as {{message}}.
+fn placeholder() -> &'static Regex {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap())
+}
+
+#[derive(Debug)]
+pub struct PromptTemplate {
+    body: String,
+    variables: BTreeSet<String>,
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Unknown(String),
+    MissingVariables(Vec<String>),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unknown(name) => write!(f, "Unknown prompt template: {}", name),
+            TemplateError::MissingVariables(names) => {
+                write!(f, "Missing template variables: {}", names.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PromptTemplates {
+    templates: HashMap<String, PromptTemplate>,
+}
+
+impl PromptTemplates {
+    // Loads every *.txt and *.hbs file in `dir`, keyed by file stem.
+    pub fn load(dir: &Path) -> std::io::Result<Self> {
+        let mut templates = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_template = matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("txt") | Some("hbs")
+            );
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !is_template || !path.is_file() {
+                continue;
+            }
+
+            let body = std::fs::read_to_string(&path)?;
+            let variables = placeholder()
+                .captures_iter(&body)
+                .map(|caps| caps[1].to_string())
+                .collect();
+            if templates
+                .insert(name.to_string(), PromptTemplate { body, variables })
+                .is_some()
+            {
+                log::warn!("Duplicate prompt template name {:?}, keeping {:?}", name, path);
+            }
+        }
+        log::info!("Loaded {} prompt templates from {:?}", templates.len(), dir);
+        Ok(Self { templates })
+    }
+
+    // Sorted, for listing on /api
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn render(
+        &self,
+        name: &str,
+        message: &str,
+        variables: &HashMap<String, String>,
+    ) -> Result<String, TemplateError> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or_else(|| TemplateError::Unknown(name.to_string()))?;
+
+        let lookup = |var: &str| -> Option<&str> {
+            match var {
+                "message" => Some(message),
+                _ => variables.get(var).map(String::as_str),
+            }
+        };
+        let missing: Vec<String> = template
+            .variables
+            .iter()
+            .filter(|var| lookup(var).is_none())
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(TemplateError::MissingVariables(missing));
+        }
+
+        Ok(placeholder()
+            .replace_all(&template.body, |caps: &regex::Captures| {
+                lookup(&caps[1]).unwrap_or_default().to_string()
+            })
+            .into_owned())
+    }
+}
@@ -0,0 +1,57 @@
+use actix_web::{post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+// Rough token count used wherever the server needs one without calling the
+// model. TOKEN_ESTIMATE_CHARS_PER_TOKEN (default 4) sets the ratio; no BPE
+// tokenizer is bundled.
+pub struct TokenEstimator {
+    chars_per_token: f64,
+}
+
+impl TokenEstimator {
+    pub fn from_env() -> Self {
+        let chars_per_token = std::env::var("TOKEN_ESTIMATE_CHARS_PER_TOKEN")
+            .ok()
+            .map(|v| v.parse::<f64>().expect("TOKEN_ESTIMATE_CHARS_PER_TOKEN must be a number"))
+            .unwrap_or(4.0);
+        if chars_per_token <= 0.0 {
+            panic!("TOKEN_ESTIMATE_CHARS_PER_TOKEN must be greater than 0");
+        }
+        Self { chars_per_token }
+    }
+
+    pub fn estimate(&self, text: &str) -> u64 {
+        (text.chars().count() as f64 / self.chars_per_token).ceil() as u64
+    }
+
+    pub fn method(&self) -> String {
+        format!("chars/{}", self.chars_per_token)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenizeRequest {
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenizeResponse {
+    tokens: u64,
+    chars: usize,
+    method: String,
+}
+
+#[post("/api/tokenize")]
+async fn tokenize(
+    req: web::Json<TokenizeRequest>,
+    app_state: web::Data<AppState>,
+) -> HttpResponse {
+    let estimator = &app_state.token_estimator;
+    HttpResponse::Ok().json(TokenizeResponse {
+        tokens: estimator.estimate(&req.text),
+        chars: req.text.chars().count(),
+        method: estimator.method(),
+    })
+}
@@ -0,0 +1,140 @@
+use actix_web::HttpRequest;
+use rand::Rng;
+
+use crate::request_id::{self, RequestId};
+
+pub const TRACEPARENT: &str = "traceparent";
+
+// Headers forwarded on upstream LLM calls so downstream instrumentation can
+// be tied back to the originating request, plus any client headers on the
+// FORWARD_HEADERS allowlist.
+#[derive(Debug, Clone)]
+pub struct TraceHeaders {
+    pub request_id: String,
+    // W3C trace context; None when TRACE_CONTEXT is off
+    pub traceparent: Option<String>,
+    pub forwarded: Vec<(String, String)>,
+}
+
+// Never taken from the client: credentials, routing and framing are the
+// server's, as are the headers set above.
+const UNFORWARDABLE: &[&str] = &[
+    "host",
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "content-length",
+    "content-type",
+    "content-encoding",
+    "transfer-encoding",
+    "connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "upgrade",
+    request_id::HEADER,
+    TRACEPARENT,
+];
+
+// Longer values are dropped rather than cut
+const MAX_FORWARDED_VALUE: usize = 1024;
+
+// FORWARD_HEADERS: comma-separated client header names passed through to
+// the serving endpoint. Empty by default, so nothing is forwarded.
+pub fn forward_headers_from_env() -> Vec<String> {
+    let names: Vec<String> = std::env::var("FORWARD_HEADERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in &names {
+        let token = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+        if !token {
+            panic!("FORWARD_HEADERS: {:?} is not a valid header name", name);
+        }
+        if UNFORWARDABLE.contains(&name.as_str()) {
+            panic!("FORWARD_HEADERS: {} can't be forwarded", name);
+        }
+    }
+    if !names.is_empty() {
+        log::info!("Forwarding client headers upstream: {}", names.join(", "));
+    }
+    names
+}
+
+impl TraceHeaders {
+    // Continues the caller's trace when it sent a valid traceparent, else
+    // starts a new one. Either way the upstream call gets a fresh span id.
+    pub fn for_request(
+        req: &HttpRequest,
+        request_id: &RequestId,
+        tracing_enabled: bool,
+        forward: &[String],
+    ) -> Self {
+        let traceparent = tracing_enabled.then(|| {
+            let incoming = req
+                .headers()
+                .get(TRACEPARENT)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_traceparent);
+            let (trace_id, flags) = match incoming {
+                Some((trace_id, flags)) => (trace_id.to_string(), flags.to_string()),
+                None => (format!("{:032x}", rand::thread_rng().gen::<u128>()), "01".to_string()),
+            };
+            format!("00-{}-{:016x}-{}", trace_id, rand::thread_rng().gen::<u64>(), flags)
+        });
+        let forwarded = forward
+            .iter()
+            .flat_map(|name| {
+                req.headers()
+                    .get_all(name.as_str())
+                    .filter_map(|value| value.to_str().ok())
+                    .map(str::trim)
+                    .filter(|value| value.len() <= MAX_FORWARDED_VALUE)
+                    .map(move |value| (name.clone(), value.to_string()))
+            })
+            .collect();
+        Self {
+            request_id: request_id.0.clone(),
+            traceparent,
+            forwarded,
+        }
+    }
+
+    // Every header `apply` adds
+    pub fn pairs(&self) -> Vec<(&str, &str)> {
+        let mut pairs = vec![(request_id::HEADER, self.request_id.as_str())];
+        if let Some(traceparent) = &self.traceparent {
+            pairs.push((TRACEPARENT, traceparent));
+        }
+        pairs.extend(self.forwarded.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        pairs
+    }
+
+    pub fn apply(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        self.pairs()
+            .into_iter()
+            .fold(builder, |builder, (name, value)| builder.header(name, value))
+    }
+}
+
+// Returns the trace id and flags of a version 00 traceparent.
+fn parse_traceparent(value: &str) -> Option<(&str, &str)> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |s: &str, len: usize| {
+        s.len() == len && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    };
+    let valid = parts.next().is_none()
+        && version == "00"
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        && trace_id.chars().any(|c| c != '0')
+        && parent_id.chars().any(|c| c != '0');
+    valid.then_some((trace_id, flags))
+}
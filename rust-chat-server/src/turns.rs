@@ -0,0 +1,88 @@
+use actix_web::{web, HttpResponse};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::AppState;
+
+// With SERIALIZE_CONVERSATIONS=true, turns that share a conversation_id run
+// one at a time, so parallel requests from a buggy client can't interleave
+// the stored history. A turn waits up to CONVERSATION_TURN_WAIT_SECS
+// (default 30; 0 refuses at once) for the one before it, then gets a 409.
+// Requests without a conversation_id are never held up.
+pub struct ConversationTurns {
+    wait: Duration,
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+// Held until the turn's reply is finished, streamed ones included.
+pub struct Turn {
+    app_state: web::Data<AppState>,
+    conversation_id: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl ConversationTurns {
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("SERIALIZE_CONVERSATIONS").map(|v| v != "true").unwrap_or(true) {
+            return None;
+        }
+        let wait_secs: u64 = std::env::var("CONVERSATION_TURN_WAIT_SECS")
+            .ok()
+            .map(|v| v.parse().expect("CONVERSATION_TURN_WAIT_SECS must be a whole number of seconds"))
+            .unwrap_or(30);
+        log::info!("Serializing turns per conversation (waiting up to {}s)", wait_secs);
+        Some(Self {
+            wait: Duration::from_secs(wait_secs),
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Ok(None) when serialization is off or the request has no conversation.
+    // Err is the 409 to return.
+    pub async fn begin(app_state: &web::Data<AppState>, conversation_id: Option<&str>) -> Result<Option<Turn>, HttpResponse> {
+        let (Some(turns), Some(conversation_id)) = (&app_state.conversation_turns, conversation_id) else {
+            return Ok(None);
+        };
+        let lock = turns
+            .locks
+            .lock()
+            .unwrap()
+            .entry(conversation_id.to_string())
+            .or_default()
+            .clone();
+        let guard = if turns.wait.is_zero() {
+            lock.try_lock_owned().ok()
+        } else {
+            tokio::time::timeout(turns.wait, lock.lock_owned()).await.ok()
+        };
+        // Built either way so a refused turn still cleans up the entry
+        let turn = Turn {
+            app_state: app_state.clone(),
+            conversation_id: conversation_id.to_string(),
+            guard,
+        };
+        if turn.guard.is_none() {
+            log::warn!("Refused a concurrent turn of conversation {}", conversation_id);
+            return Err(HttpResponse::Conflict().json(serde_json::json!({
+                "error": "Another turn of this conversation is still in progress"
+            })));
+        }
+        Ok(Some(turn))
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let Some(turns) = &self.app_state.conversation_turns else {
+            return;
+        };
+        let mut locks = turns.locks.lock().unwrap();
+        drop(self.guard.take());
+        // Only the map's own reference left: nobody holds or waits for it
+        if locks.get(&self.conversation_id).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&self.conversation_id);
+        }
+    }
+}
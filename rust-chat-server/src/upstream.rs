@@ -0,0 +1,1204 @@
+use actix_web::web::Bytes;
+use rand::Rng;
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+
+use crate::debug;
+use crate::faults;
+use crate::trace::TraceHeaders;
+use crate::usage::Usage;
+
+#[derive(Debug, Clone)]
+pub struct LlmEndpoint {
+    pub name: String,
+    pub url: String,
+}
+
+// Invocation URL for every configured endpoint; LLM_URL_TEMPLATE takes
+// {host} and {endpoint} placeholders for non-Databricks backends.
+pub const DEFAULT_URL_TEMPLATE: &str = "https://{host}/serving-endpoints/{endpoint}/invocations";
+
+pub fn url_template() -> &'static str {
+    static TEMPLATE: OnceLock<String> = OnceLock::new();
+    TEMPLATE.get_or_init(|| {
+        let template = std::env::var("LLM_URL_TEMPLATE")
+            .ok()
+            .filter(|t| !t.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_URL_TEMPLATE.to_string());
+        if !template.starts_with("https://") && !template.starts_with("http://") {
+            panic!("LLM_URL_TEMPLATE must be an http:// or https:// URL");
+        }
+        template
+    })
+}
+
+impl LlmEndpoint {
+    pub fn new(host: &str, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            url: url_template().replace("{host}", host).replace("{endpoint}", name),
+        }
+    }
+
+    pub fn valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    }
+}
+
+// Lets a chat request pick its own serving endpoint, for experiments
+// (ALLOW_ENDPOINT_OVERRIDE=true). A bare `name` is served from
+// DATABRICKS_HOST; `host/name` only for hosts in ENDPOINT_OVERRIDE_HOSTS.
+#[derive(Debug)]
+pub struct EndpointOverride {
+    default_host: String,
+    allowed_hosts: Vec<String>,
+}
+
+impl EndpointOverride {
+    pub fn from_env(host: &str) -> Option<Self> {
+        if std::env::var("ALLOW_ENDPOINT_OVERRIDE").map(|v| v != "true").unwrap_or(true) {
+            return None;
+        }
+        let allowed_hosts: Vec<String> = std::env::var("ENDPOINT_OVERRIDE_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .map(str::to_string)
+            .collect();
+        log::warn!(
+            "Chat requests may choose their endpoint on {} and {:?}",
+            host,
+            allowed_hosts
+        );
+        Some(Self {
+            default_host: host.to_string(),
+            allowed_hosts,
+        })
+    }
+
+    pub fn resolve(&self, requested: &str) -> Result<LlmEndpoint, String> {
+        let requested = requested.trim();
+        let (host, name) = requested
+            .rsplit_once('/')
+            .unwrap_or((self.default_host.as_str(), requested));
+        if !LlmEndpoint::valid_name(name) {
+            return Err(format!("invalid endpoint name {:?}", name));
+        }
+        let allowed = host.eq_ignore_ascii_case(&self.default_host)
+            || self.allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(host));
+        if !allowed {
+            return Err(format!("endpoint host {:?} is not allowed", host));
+        }
+        Ok(LlmEndpoint::new(host, name))
+    }
+}
+
+// A second endpoint that receives a fixed share of chat traffic.
+#[derive(Debug)]
+pub struct Canary {
+    pub endpoint: LlmEndpoint,
+    pub percent: f64,
+}
+
+impl Canary {
+    pub fn from_env(host: &str) -> Option<Self> {
+        let name = std::env::var("CANARY_ENDPOINT").ok().filter(|n| !n.is_empty())?;
+        let percent = std::env::var("CANARY_PERCENT")
+            .ok()
+            .map(|p| p.parse::<f64>().expect("CANARY_PERCENT must be a number"))
+            .unwrap_or(0.0);
+        if !(0.0..=100.0).contains(&percent) {
+            panic!("CANARY_PERCENT must be between 0 and 100");
+        }
+        log::info!("Routing {}% of chat traffic to canary endpoint {}", percent, name);
+        Some(Self {
+            endpoint: LlmEndpoint::new(host, &name),
+            percent,
+        })
+    }
+
+    pub fn selected(&self) -> bool {
+        rand::thread_rng().gen::<f64>() * 100.0 < self.percent
+    }
+
+    // Deterministic for a given hash, random without one.
+    pub fn selected_for(&self, hash: Option<u64>) -> bool {
+        match hash {
+            Some(hash) => ((hash % 10_000) as f64) < self.percent * 100.0,
+            None => self.selected(),
+        }
+    }
+}
+
+// Mirrors every chat request to a comparison endpoint. The shadow reply is
+// recorded next to the primary one and never reaches the client.
+#[derive(Debug)]
+pub struct Shadow {
+    pub endpoint: LlmEndpoint,
+    // JSON lines file for the paired responses; falls back to the log
+    pub log_path: Option<PathBuf>,
+}
+
+impl Shadow {
+    pub fn from_env(host: &str) -> Option<Self> {
+        let name = std::env::var("SHADOW_ENDPOINT").ok().filter(|n| !n.is_empty())?;
+        let log_path = std::env::var("SHADOW_LOG_PATH").ok().map(PathBuf::from);
+        log::info!("Mirroring chat traffic to shadow endpoint {}", name);
+        Some(Self {
+            endpoint: LlmEndpoint::new(host, &name),
+            log_path,
+        })
+    }
+
+    // Starts the shadow call in the background and returns a sender for the
+    // primary outcome. Dropping the sender records the primary as missing.
+    pub fn mirror(
+        &self,
+        client: &reqwest::Client,
+        api_key: &str,
+        primary_endpoint: &LlmEndpoint,
+        payload: &serde_json::Value,
+    ) -> oneshot::Sender<Result<String, String>> {
+        let (primary_tx, primary_rx) = oneshot::channel();
+        let client = client.clone();
+        let api_key = api_key.to_string();
+        let endpoint = self.endpoint.clone();
+        let primary_name = primary_endpoint.name.clone();
+        let payload = payload.clone();
+        let log_path = self.log_path.clone();
+
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let shadow = complete(&client, &endpoint, &api_key, &payload, None)
+                .await
+                .map(|completion| completion.content)
+                .map_err(|e| e.to_string());
+            let shadow_latency_ms = started.elapsed().as_millis() as u64;
+            let primary = primary_rx
+                .await
+                .unwrap_or_else(|_| Err("primary request did not complete".to_string()));
+
+            let record = serde_json::json!({
+                "timestamp_ms": crate::conversations::now_ms(),
+                "payload": payload,
+                "primary_endpoint": primary_name,
+                "primary": outcome_json(&primary),
+                "shadow_endpoint": endpoint.name,
+                "shadow": outcome_json(&shadow),
+                "shadow_latency_ms": shadow_latency_ms,
+            });
+            match log_path {
+                Some(path) => {
+                    if let Err(e) = append_line(&path, &record.to_string()).await {
+                        log::warn!("Failed to write shadow record to {:?}: {}", path, e);
+                    }
+                }
+                None => log::info!(target: "shadow", "{}", record),
+            }
+        });
+
+        primary_tx
+    }
+}
+
+fn outcome_json(outcome: &Result<String, String>) -> serde_json::Value {
+    match outcome {
+        Ok(content) => serde_json::json!({ "content": content }),
+        Err(error) => serde_json::json!({ "error": error }),
+    }
+}
+
+async fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{}\n", line).as_bytes()).await
+}
+
+#[derive(Debug, Deserialize)]
+struct LLMResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: Message,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    content: String,
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Send(reqwest::Error),
+    // Status, body, any Retry-After delay the endpoint asked for and its
+    // rate-limit headers
+    Status(reqwest::StatusCode, String, Option<Duration>, RateLimitHeaders),
+    // Reading the body or parsing it failed
+    Decode(Box<dyn std::error::Error + Send + Sync>),
+    NoChoices,
+    // A streamed reply went quiet for longer than the idle timeout
+    Stalled(Duration),
+    // A success status with a body that isn't JSON (or SSE), such as a
+    // proxy's HTML error page: the content type and the start of the body
+    NotJson(Option<String>, String),
+    // Not sent: the concurrency limiter's queue was full. Retry after this.
+    Shed(Duration),
+    // Not sent: the endpoint is cooling down after a 429 with Retry-After,
+    // for this much longer
+    CoolingDown(Duration),
+    // The body passed MAX_UPSTREAM_BYTES, this many bytes, and was abandoned
+    TooLarge(usize),
+}
+
+// Characters of a non-JSON body kept for the log
+const BODY_PREVIEW_CHARS: usize = 200;
+
+fn not_json(content_type: Option<String>, body: &[u8]) -> UpstreamError {
+    let body = String::from_utf8_lossy(body);
+    let preview: String = body.trim().chars().take(BODY_PREVIEW_CHARS).collect();
+    UpstreamError::NotJson(content_type, preview)
+}
+
+// MAX_UPSTREAM_BYTES (default 16 MiB) caps any one upstream body, buffered
+// or streamed, so a runaway or hostile backend can't exhaust memory.
+pub fn max_upstream_bytes() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+    *MAX.get_or_init(|| {
+        std::env::var("MAX_UPSTREAM_BYTES")
+            .ok()
+            .map(|v| match v.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("MAX_UPSTREAM_BYTES must be a positive integer"),
+            })
+            .unwrap_or(16 * 1024 * 1024)
+    })
+}
+
+// The whole body, or TooLarge as soon as it passes max_upstream_bytes.
+async fn read_body(mut response: reqwest::Response) -> Result<Bytes, UpstreamError> {
+    let limit = max_upstream_bytes();
+    if response.content_length().is_some_and(|length| length > limit as u64) {
+        return Err(UpstreamError::TooLarge(limit));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| UpstreamError::Decode(e.into()))? {
+        if body.len() + chunk.len() > limit {
+            return Err(UpstreamError::TooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(body))
+}
+
+fn content_type(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+impl UpstreamError {
+    // Short label for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UpstreamError::Send(_) => "send",
+            UpstreamError::Status(..) => "status",
+            UpstreamError::Decode(_) => "decode",
+            UpstreamError::NoChoices => "no_choices",
+            UpstreamError::Stalled(_) => "stalled",
+            UpstreamError::NotJson(..) => "not_json",
+            UpstreamError::Shed(_) => "shed",
+            UpstreamError::CoolingDown(_) => "cooling_down",
+            UpstreamError::TooLarge(_) => "too_large",
+        }
+    }
+
+    // Coarser grouping for llm_request_errors_total; every error has one.
+    // `local` calls were refused by this server and never reached the
+    // endpoint.
+    pub fn category(&self) -> &'static str {
+        match self {
+            UpstreamError::Send(e) if e.is_timeout() => "timeout",
+            UpstreamError::Send(e) if e.is_decode() => "decode",
+            UpstreamError::Send(_) => "connection",
+            UpstreamError::Stalled(_) => "timeout",
+            UpstreamError::Status(status, ..) if status.is_server_error() => "http_5xx",
+            UpstreamError::Status(..) => "http_4xx",
+            UpstreamError::Decode(_)
+            | UpstreamError::NoChoices
+            | UpstreamError::NotJson(..)
+            | UpstreamError::TooLarge(_) => "decode",
+            UpstreamError::Shed(_) | UpstreamError::CoolingDown(_) => "local",
+        }
+    }
+
+    // Why the call is worth repeating, or None when retrying can't help.
+    pub fn retry_reason(&self) -> Option<&'static str> {
+        match self {
+            UpstreamError::Send(e) if e.is_timeout() => Some("timeout"),
+            UpstreamError::Send(e) if e.is_connect() => Some("connection"),
+            UpstreamError::Stalled(_) => Some("timeout"),
+            UpstreamError::Status(status, ..) => match status.as_u16() {
+                429 => Some("429"),
+                502 => Some("502"),
+                503 => Some("503"),
+                504 => Some("504"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Delay requested by a 429 or 503 response's Retry-After header.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            UpstreamError::Status(status, _, retry_after, _)
+                if matches!(status.as_u16(), 429 | 503) =>
+            {
+                *retry_after
+            }
+            _ => None,
+        }
+    }
+}
+
+// Only the delay-seconds form; an HTTP date falls back to backoff.
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+// `x-ratelimit-*` and IETF `ratelimit*` response headers, passed on to the
+// client when the endpoint rate-limits us.
+pub type RateLimitHeaders = Vec<(String, String)>;
+
+fn rate_limit_headers(headers: &reqwest::header::HeaderMap) -> RateLimitHeaders {
+    headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ratelimit-") || name.as_str().starts_with("ratelimit"))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+async fn status_error(response: reqwest::Response) -> UpstreamError {
+    let status = response.status();
+    let retry_after = parse_retry_after(response.headers());
+    let rate_limit = rate_limit_headers(response.headers());
+    let error_body = read_body(response)
+        .await
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+        .unwrap_or_default();
+    UpstreamError::Status(status, error_body, retry_after, rate_limit)
+}
+
+// Endpoints that answered 429 with Retry-After. Until the delay passes,
+// chat calls to them fail fast instead of adding to the overload.
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    until: Mutex<HashMap<String, Instant>>,
+}
+
+impl Cooldowns {
+    // Refuses calls with CoolingDown until the endpoint's delay has passed.
+    pub fn check(&self, endpoint: &LlmEndpoint) -> Result<(), UpstreamError> {
+        match self.remaining(endpoint) {
+            Some(remaining) => Err(UpstreamError::CoolingDown(remaining)),
+            None => Ok(()),
+        }
+    }
+
+    fn remaining(&self, endpoint: &LlmEndpoint) -> Option<Duration> {
+        let mut until = self.until.lock().unwrap();
+        let remaining = until
+            .get(&endpoint.name)
+            .map(|at| at.saturating_duration_since(Instant::now()))?;
+        if remaining.is_zero() {
+            until.remove(&endpoint.name);
+            return None;
+        }
+        Some(remaining)
+    }
+
+    pub fn start(&self, endpoint: &LlmEndpoint, delay: Duration) {
+        log::warn!("Pausing calls to {} for {} s after a 429", endpoint.name, delay.as_secs());
+        self.until
+            .lock()
+            .unwrap()
+            .insert(endpoint.name.clone(), Instant::now() + delay);
+    }
+}
+
+// LLM_MAX_RETRIES extra attempts (default 0) for transient failures. A 429
+// or 503 with Retry-After waits as asked; otherwise the wait is drawn
+// uniformly from zero up to LLM_RETRY_BACKOFF_MS (default 500) doubled per
+// retry. Every wait is capped at LLM_RETRY_MAX_BACKOFF_MS (default 10000).
+#[derive(Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        let max_retries = std::env::var("LLM_MAX_RETRIES")
+            .ok()
+            .map(|v| v.parse().expect("LLM_MAX_RETRIES must be a non-negative integer"))
+            .unwrap_or(0);
+        let backoff_ms = std::env::var("LLM_RETRY_BACKOFF_MS")
+            .ok()
+            .map(|v| v.parse().expect("LLM_RETRY_BACKOFF_MS must be a number"))
+            .unwrap_or(500);
+        let max_backoff_ms = std::env::var("LLM_RETRY_MAX_BACKOFF_MS")
+            .ok()
+            .map(|v| v.parse().expect("LLM_RETRY_MAX_BACKOFF_MS must be a number"))
+            .unwrap_or(10_000);
+        Self {
+            max_retries,
+            initial_backoff: Duration::from_millis(backoff_ms),
+            max_backoff: Duration::from_millis(max_backoff_ms),
+        }
+    }
+
+    // Wait before retry number `retry` (starting at 1).
+    fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_backoff);
+        }
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Send(e) => write!(f, "failed to send request: {}", e),
+            UpstreamError::Status(status, body, ..) => write!(f, "status {}: {}", status, body),
+            UpstreamError::Decode(e) => write!(f, "failed to decode response: {}", e),
+            UpstreamError::NoChoices => write!(f, "response contained no choices"),
+            UpstreamError::Stalled(idle) => write!(f, "no data for {}s", idle.as_secs()),
+            UpstreamError::Shed(retry_after) => {
+                write!(f, "shed by the concurrency limiter, retry in {}s", retry_after.as_secs())
+            }
+            UpstreamError::CoolingDown(remaining) => {
+                write!(f, "cooling down after an upstream 429, retry in {}s", remaining.as_secs())
+            }
+            UpstreamError::NotJson(content_type, preview) => write!(
+                f,
+                "non-JSON response ({}): {:?}",
+                content_type.as_deref().unwrap_or("no content type"),
+                preview
+            ),
+            UpstreamError::TooLarge(limit) => write!(f, "response larger than {} bytes", limit),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    pub content: String,
+    pub usage: Option<Usage>,
+    pub finish_reason: Option<String>,
+    // The reply as received (the body, or each SSE data payload), kept only
+    // with DEBUG_ENDPOINT_ENABLED
+    pub raw: Vec<String>,
+}
+
+// Whether the endpoint withheld the reply for content-policy reasons, either
+// through the finish reason or by rejecting the request outright.
+pub fn content_filtered(result: &Result<Completion, UpstreamError>) -> bool {
+    match result {
+        Ok(completion) => completion.finish_reason.as_deref() == Some("content_filter"),
+        Err(UpstreamError::Status(status, body, ..)) => {
+            status.is_client_error() && body.contains("content_filter")
+        }
+        Err(_) => false,
+    }
+}
+
+// Sends a chat payload to a serving endpoint and returns the first choice's
+// content along with the reported token usage.
+pub async fn complete(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+    payload: &serde_json::Value,
+    trace: Option<&TraceHeaders>,
+) -> Result<Completion, UpstreamError> {
+    let mut request = client
+        .post(&endpoint.url)
+        .header("Authorization", format!("Bearer {}", api_key));
+    if let Some(trace) = trace {
+        request = trace.apply(request);
+    }
+    let response = request
+        .json(payload)
+        .send()
+        .await
+        .map_err(UpstreamError::Send)?;
+
+    // Get status before consuming the response
+    if !response.status().is_success() {
+        return Err(status_error(response).await);
+    }
+
+    let content_type = content_type(&response);
+    let body = read_body(response).await?;
+    completion_from_body(content_type, body).await
+}
+
+// A whole chat-completions body, from `complete` or a streamed call whose
+// backend ignored `stream`.
+async fn completion_from_body(content_type: Option<String>, body: Bytes) -> Result<Completion, UpstreamError> {
+    let llm_resp = match parse_response(body.clone()).await {
+        Ok(llm_resp) => llm_resp,
+        // Valid JSON of the wrong shape stays a decode error
+        Err(_) if serde_json::from_slice::<serde::de::IgnoredAny>(&body).is_err() => {
+            return Err(not_json(content_type, &body));
+        }
+        Err(e) => return Err(e),
+    };
+    let choice = llm_resp
+        .choices
+        .into_iter()
+        .next()
+        .ok_or(UpstreamError::NoChoices)?;
+    Ok(Completion {
+        content: choice.message.content,
+        usage: llm_resp.usage,
+        finish_reason: choice.finish_reason,
+        raw: if debug::enabled() { vec![String::from_utf8_lossy(&body).into_owned()] } else { Vec::new() },
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+    index: Option<usize>,
+}
+
+// One embeddings call for `inputs`, in the OpenAI-compatible shape Databricks
+// embedding endpoints serve. Vectors come back in input order.
+pub async fn embed(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+    inputs: &[String],
+    trace: Option<&TraceHeaders>,
+) -> Result<Vec<Vec<f32>>, UpstreamError> {
+    let mut request = client
+        .post(&endpoint.url)
+        .header("Authorization", format!("Bearer {}", api_key));
+    if let Some(trace) = trace {
+        request = trace.apply(request);
+    }
+    let response = request
+        .json(&serde_json::json!({ "input": inputs }))
+        .send()
+        .await
+        .map_err(UpstreamError::Send)?;
+    if !response.status().is_success() {
+        return Err(status_error(response).await);
+    }
+    let content_type = content_type(&response);
+    let body = read_body(response).await?;
+    if serde_json::from_slice::<serde::de::IgnoredAny>(&body).is_err() {
+        return Err(not_json(content_type, &body));
+    }
+    parse_embeddings(&body, inputs.len())
+}
+
+// Orders vectors by their `index` when the endpoint gives one, and insists
+// on exactly one per input so a short reply can't shift the rest.
+fn parse_embeddings(body: &[u8], expected: usize) -> Result<Vec<Vec<f32>>, UpstreamError> {
+    let response: EmbeddingsResponse = serde_json::from_slice(body).map_err(|e| UpstreamError::Decode(e.into()))?;
+    if response.data.len() != expected {
+        return Err(UpstreamError::Decode(
+            format!("expected {} embeddings, got {}", expected, response.data.len()).into(),
+        ));
+    }
+    let mut vectors: Vec<Option<Vec<f32>>> = vec![None; expected];
+    for (position, data) in response.data.into_iter().enumerate() {
+        let index = data.index.unwrap_or(position);
+        match vectors.get_mut(index) {
+            Some(slot @ None) => *slot = Some(data.embedding),
+            _ => return Err(UpstreamError::Decode(format!("bad or repeated embedding index {}", index).into())),
+        }
+    }
+    Ok(vectors.into_iter().flatten().collect())
+}
+
+// How /api/chat reads the reply. Streaming gets the first byte sooner and
+// notices a stalled endpoint; the client still receives one response.
+// CHAT_UPSTREAM_BUFFERED=true restores the single buffered request.
+#[derive(Debug, Clone, Copy)]
+pub enum Transport {
+    Buffered,
+    Streamed { idle_timeout: Duration },
+}
+
+impl Transport {
+    pub fn from_env(idle_timeout: Duration) -> Self {
+        match std::env::var("CHAT_UPSTREAM_BUFFERED").as_deref() {
+            Ok("true") => Transport::Buffered,
+            _ => Transport::Streamed { idle_timeout },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamUsage {
+    usage: Option<Usage>,
+}
+
+// Reads a streaming completion to the end and assembles it into the same
+// shape `complete` returns.
+pub async fn complete_streamed(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+    payload: &serde_json::Value,
+    trace: Option<&TraceHeaders>,
+    idle_timeout: Duration,
+) -> Result<Completion, UpstreamError> {
+    let mut response = open_stream(client, endpoint, api_key, payload, trace).await?;
+    let content_type = content_type(&response);
+
+    let mut decoder = SseDecoder::default();
+    // Kept until the first `data:` line, to parse or explain a body without one
+    let mut body = Vec::new();
+    let mut saw_data = false;
+    let mut content = String::new();
+    let mut usage = None;
+    let mut finish_reason = None;
+    let mut received = false;
+    let mut raw = Vec::new();
+    let mut received_bytes = 0;
+    loop {
+        let chunk = match tokio::time::timeout(idle_timeout, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => chunk,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(UpstreamError::Decode(e.into())),
+            Err(_) => return Err(UpstreamError::Stalled(idle_timeout)),
+        };
+        received_bytes += chunk.len();
+        if received_bytes > max_upstream_bytes() {
+            return Err(UpstreamError::TooLarge(max_upstream_bytes()));
+        }
+        if !saw_data {
+            body.extend_from_slice(&chunk);
+        }
+        let mut done = false;
+        for data in decoder.feed(&chunk) {
+            saw_data = true;
+            if debug::enabled() {
+                raw.push(data.clone());
+            }
+            if let Some(reported) = stream_usage(&data) {
+                usage = Some(reported);
+            }
+            match parse_stream_data(&data) {
+                Some(StreamEvent::Delta(delta)) => {
+                    received = true;
+                    content.push_str(&delta);
+                }
+                Some(StreamEvent::Finished(reason)) => {
+                    received = true;
+                    finish_reason = reason;
+                }
+                Some(StreamEvent::Done) => done = true,
+                None => {}
+            }
+        }
+        if saw_data && !body.is_empty() {
+            body = Vec::new();
+        }
+        if done {
+            break;
+        }
+    }
+    // A plain JSON body is a backend ignoring `stream`, not an error page
+    if !saw_data {
+        return match content_type.as_deref().is_some_and(|t| t.contains("json")) {
+            true => completion_from_body(content_type, body.into()).await,
+            false => Err(not_json(content_type, &body)),
+        };
+    }
+    if !received {
+        return Err(UpstreamError::NoChoices);
+    }
+    Ok(Completion {
+        content,
+        usage,
+        finish_reason,
+        raw,
+    })
+}
+
+// Bodies at least LLM_BLOCKING_PARSE_BYTES long (default 262144) are parsed
+// on the blocking pool so a huge reply doesn't stall the worker's other
+// requests.
+fn blocking_parse_threshold() -> usize {
+    static THRESHOLD: OnceLock<usize> = OnceLock::new();
+    *THRESHOLD.get_or_init(|| {
+        std::env::var("LLM_BLOCKING_PARSE_BYTES")
+            .ok()
+            .map(|v| v.parse().expect("LLM_BLOCKING_PARSE_BYTES must be a number of bytes"))
+            .unwrap_or(256 * 1024)
+    })
+}
+
+async fn parse_response(body: Bytes) -> Result<LLMResponse, UpstreamError> {
+    let started = Instant::now();
+    let size = body.len();
+    let offloaded = size >= blocking_parse_threshold();
+    let parsed = if offloaded {
+        tokio::task::spawn_blocking(move || serde_json::from_slice::<LLMResponse>(&body))
+            .await
+            .map_err(|e| UpstreamError::Decode(e.into()))?
+    } else {
+        serde_json::from_slice::<LLMResponse>(&body)
+    };
+    let elapsed = started.elapsed();
+    if offloaded {
+        log::info!("Parsed {} byte LLM response off the runtime in {} ms", size, elapsed.as_millis());
+    } else {
+        log::debug!("Parsed {} byte LLM response in {} us", size, elapsed.as_micros());
+    }
+    parsed.map_err(|e| UpstreamError::Decode(e.into()))
+}
+
+// `complete` or `complete_streamed`, depending on the transport, with
+// retries for transient failures. `on_retry` is called with the reason
+// before each retry.
+#[allow(clippy::too_many_arguments)]
+pub async fn complete_with_retries(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+    payload: &serde_json::Value,
+    trace: Option<&TraceHeaders>,
+    transport: Transport,
+    policy: &RetryPolicy,
+    on_retry: impl Fn(&'static str),
+) -> Result<Completion, UpstreamError> {
+    with_retries(endpoint, policy, on_retry, || async {
+        match transport {
+            Transport::Buffered => complete(client, endpoint, api_key, payload, trace).await,
+            Transport::Streamed { idle_timeout } => {
+                complete_streamed(client, endpoint, api_key, payload, trace, idle_timeout).await
+            }
+        }
+    })
+    .await
+}
+
+// `open_stream` with the same retries. Only connecting is retried: once the
+// response is handed back its deltas may already be on their way to a client.
+pub async fn open_stream_with_retries(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+    payload: &serde_json::Value,
+    trace: Option<&TraceHeaders>,
+    policy: &RetryPolicy,
+    on_retry: impl Fn(&'static str),
+) -> Result<reqwest::Response, UpstreamError> {
+    with_retries(endpoint, policy, on_retry, || open_stream(client, endpoint, api_key, payload, trace)).await
+}
+
+// Runs `attempt` until it succeeds or fails in a way that isn't worth
+// retrying, with FAULT_INJECT applied before each try.
+async fn with_retries<T, F, Fut>(
+    endpoint: &LlmEndpoint,
+    policy: &RetryPolicy,
+    on_retry: impl Fn(&'static str),
+    mut attempt: F,
+) -> Result<T, UpstreamError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, UpstreamError>>,
+{
+    let mut retries = 0;
+    loop {
+        let injected = match faults::settings() {
+            Some(faults) => faults.before_attempt().await,
+            None => Ok(()),
+        };
+        let attempt = match injected {
+            Ok(()) => attempt().await,
+            Err(e) => Err(e),
+        };
+        let error = match attempt {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let reason = match error.retry_reason() {
+            Some(reason) if retries < policy.max_retries => reason,
+            _ => return Err(error),
+        };
+        retries += 1;
+        let backoff = policy.delay(retries, error.retry_after());
+        log::warn!(
+            "LLM request to {} failed ({}), retry {}/{} in {} ms",
+            endpoint.name,
+            error,
+            retries,
+            policy.max_retries,
+            backoff.as_millis()
+        );
+        on_retry(reason);
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+// Sends a minimal chat request so a misconfigured endpoint is caught at
+// startup rather than on the first user request.
+pub async fn self_test(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+) -> Result<(), UpstreamError> {
+    let payload = serde_json::json!({
+        "messages": [{ "role": "user", "content": "ping" }],
+        "max_tokens": 1
+    });
+    let started = Instant::now();
+    complete(client, endpoint, api_key, &payload, None).await?;
+    // native-tls doesn't report the negotiated version, only that the
+    // handshake met the floor
+    let transport = if endpoint.url.starts_with("https://") {
+        format!("TLS {} or later", min_tls_version().1)
+    } else {
+        "plain HTTP".to_string()
+    };
+    log::info!(
+        "Startup self-test against {} succeeded in {} ms over {}",
+        endpoint.name,
+        started.elapsed().as_millis(),
+        transport
+    );
+    Ok(())
+}
+
+// Oldest TLS version upstream connections accept (MIN_TLS_VERSION; only
+// 1.2, the default, for now). reqwest's native-tls backend, the one this
+// build uses, refuses a 1.3 floor; that needs the rustls backend.
+pub fn min_tls_version() -> (reqwest::tls::Version, &'static str) {
+    match std::env::var("MIN_TLS_VERSION").as_deref() {
+        Err(_) | Ok("") | Ok("1.2") => (reqwest::tls::Version::TLS_1_2, "1.2"),
+        Ok("1.3") => panic!("MIN_TLS_VERSION=1.3 needs the rustls TLS backend, which this build doesn't include; use 1.2"),
+        Ok(other) => panic!("MIN_TLS_VERSION must be 1.2, not {:?}", other),
+    }
+}
+
+// Client for every upstream call. Certificates are always verified; there
+// is deliberately no setting to turn that off.
+pub fn build_client() -> reqwest::Client {
+    let (version, name) = min_tls_version();
+    log::info!("Upstream connections require TLS {} or later", name);
+    reqwest::Client::builder()
+        .min_tls_version(version)
+        .build()
+        .unwrap_or_else(|e| panic!("Can't build the HTTP client with MIN_TLS_VERSION {}: {}", name, e))
+}
+
+// Opens `count` connections to the endpoint in parallel so their TLS
+// handshakes are done before the first chat request. The status of the HEAD
+// requests is irrelevant; each finished one leaves an idle pooled connection.
+// Returns how many completed.
+pub async fn warm_pool(client: &reqwest::Client, endpoint: &LlmEndpoint, api_key: &str, count: usize) -> usize {
+    let started = Instant::now();
+    let requests = (0..count).map(|_| {
+        client
+            .head(&endpoint.url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .send()
+    });
+    let results = futures::future::join_all(requests).await;
+    let mut warmed = 0;
+    for result in results {
+        match result {
+            Ok(_) => warmed += 1,
+            Err(e) => log::warn!("Failed to warm connection to {}: {}", endpoint.name, e),
+        }
+    }
+    log::info!(
+        "Warmed {}/{} connections to {} in {} ms",
+        warmed,
+        count,
+        endpoint.name,
+        started.elapsed().as_millis()
+    );
+    warmed
+}
+
+// Opens a streaming chat completion. The caller reads the body with
+// `Response::chunk` and decodes it with `SseDecoder`.
+pub async fn open_stream(
+    client: &reqwest::Client,
+    endpoint: &LlmEndpoint,
+    api_key: &str,
+    payload: &serde_json::Value,
+    trace: Option<&TraceHeaders>,
+) -> Result<reqwest::Response, UpstreamError> {
+    let mut payload = payload.clone();
+    payload["stream"] = serde_json::Value::Bool(true);
+    // Ask for token counts in the final chunk so cost and budgets still work
+    payload["stream_options"] = serde_json::json!({ "include_usage": true });
+    let mut request = client
+        .post(&endpoint.url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Accept", "text/event-stream");
+    if let Some(trace) = trace {
+        request = trace.apply(request);
+    }
+    let response = request
+        .json(&payload)
+        .send()
+        .await
+        .map_err(UpstreamError::Send)?;
+
+    if !response.status().is_success() {
+        return Err(status_error(response).await);
+    }
+    // Caught here so the SSE routes don't relay an error page as an empty reply
+    let content_type = content_type(&response);
+    if content_type.as_deref().is_some_and(|t| t.starts_with("text/html")) {
+        let body = read_body(response).await.unwrap_or_default();
+        return Err(not_json(content_type, &body));
+    }
+    Ok(response)
+}
+
+// Splits an upstream server-sent event stream into `data:` payloads,
+// buffering partial lines across chunks.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut data = Vec::new();
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some(payload) = line.strip_prefix("data:") {
+                data.push(payload.trim_start().to_string());
+            }
+        }
+        data
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: Option<Delta>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamEvent {
+    Delta(String),
+    Finished(Option<String>),
+    Done,
+}
+
+// Token counts from a `data:` payload; with include_usage they arrive in a
+// final chunk of their own.
+pub fn stream_usage(data: &str) -> Option<Usage> {
+    serde_json::from_str::<StreamUsage>(data).ok()?.usage
+}
+
+// Interprets one `data:` payload of an OpenAI-style completion stream.
+pub fn parse_stream_data(data: &str) -> Option<StreamEvent> {
+    if data == "[DONE]" {
+        return Some(StreamEvent::Done);
+    }
+    let chunk: StreamChunk = serde_json::from_str(data).ok()?;
+    let choice = chunk.choices.into_iter().next()?;
+    if let Some(content) = choice.delta.and_then(|delta| delta.content) {
+        if !content.is_empty() {
+            return Some(StreamEvent::Delta(content));
+        }
+    }
+    choice.finish_reason.map(|reason| StreamEvent::Finished(Some(reason)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn retry_after_header_sets_the_delay() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "5".parse().unwrap());
+        let retry_after = parse_retry_after(&headers);
+        assert_eq!(retry_after, Some(Duration::from_secs(5)));
+
+        let error = UpstreamError::Status(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            String::new(),
+            retry_after,
+            Vec::new(),
+        );
+        assert_eq!(policy().delay(1, error.retry_after()), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn missing_retry_after_falls_back_to_jittered_backoff() {
+        let error =
+            UpstreamError::Status(reqwest::StatusCode::TOO_MANY_REQUESTS, String::new(), None, Vec::new());
+        let policy = policy();
+        let delays: Vec<Duration> = (0..50).map(|_| policy.delay(3, error.retry_after())).collect();
+        // Third retry: uniform in [0, 500ms * 4)
+        assert!(delays.iter().all(|d| *d < Duration::from_secs(2)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn every_error_falls_in_one_category() {
+        let status = |code: u16| {
+            let status = reqwest::StatusCode::from_u16(code).unwrap();
+            UpstreamError::Status(status, String::new(), None, Vec::new())
+        };
+        assert_eq!(status(400).category(), "http_4xx");
+        assert_eq!(status(429).category(), "http_4xx");
+        assert_eq!(status(503).category(), "http_5xx");
+        assert_eq!(UpstreamError::Stalled(Duration::from_secs(30)).category(), "timeout");
+        assert_eq!(UpstreamError::NoChoices.category(), "decode");
+        assert_eq!(UpstreamError::Shed(Duration::from_secs(1)).category(), "local");
+        assert_eq!(UpstreamError::CoolingDown(Duration::from_secs(1)).category(), "local");
+    }
+
+    // Sends a JSON-typed body of blank lines that never ends, without a
+    // Content-Length.
+    async fn endless_server() -> LlmEndpoint {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+                    let head = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\nconnection: close\r\n\r\n";
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    let block = [[b' '; 1023].as_slice(), b"\n"].concat().repeat(64);
+                    while socket.write_all(&block).await.is_ok() {}
+                });
+            }
+        });
+        LlmEndpoint {
+            name: "endless".to_string(),
+            url: format!("http://{}/invocations", addr),
+        }
+    }
+
+    #[actix_web::test]
+    async fn oversized_bodies_are_abandoned() {
+        let endpoint = endless_server().await;
+        let client = reqwest::Client::new();
+        let payload = serde_json::json!({ "messages": [] });
+        let buffered = complete(&client, &endpoint, "t", &payload, None).await;
+        let streamed = complete_streamed(&client, &endpoint, "t", &payload, None, Duration::from_secs(5)).await;
+        for result in [buffered, streamed] {
+            match result {
+                Err(UpstreamError::TooLarge(limit)) => assert_eq!(limit, max_upstream_bytes()),
+                other => panic!("expected TooLarge, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn embeddings_are_ordered_by_index() {
+        let body = br#"{"data": [{"embedding": [2.0], "index": 1}, {"embedding": [1.0], "index": 0}]}"#;
+        assert_eq!(parse_embeddings(body, 2).unwrap(), vec![vec![1.0], vec![2.0]]);
+        assert!(parse_embeddings(body, 3).is_err());
+        let repeated = br#"{"data": [{"embedding": [1.0], "index": 0}, {"embedding": [2.0], "index": 0}]}"#;
+        assert!(parse_embeddings(repeated, 2).is_err());
+    }
+
+    #[actix_web::test]
+    async fn html_success_body_is_not_json() {
+        let endpoint = crate::fixed_upstream("text/html", "<html><body>Gateway login required</body></html>").await;
+        let client = reqwest::Client::new();
+        let payload = serde_json::json!({ "messages": [] });
+        let buffered = complete(&client, &endpoint, "t", &payload, None).await;
+        let streamed = complete_streamed(&client, &endpoint, "t", &payload, None, Duration::from_secs(5)).await;
+        for result in [buffered, streamed] {
+            match result {
+                Err(UpstreamError::NotJson(content_type, preview)) => {
+                    assert_eq!(content_type.as_deref(), Some("text/html"));
+                    assert!(preview.contains("Gateway login required"));
+                }
+                other => panic!("expected NotJson, got {:?}", other),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn streamed_call_accepts_a_plain_json_reply() {
+        let body = r#"{"choices": [{"message": {"role": "assistant", "content": "Hello"}, "finish_reason": "stop"}], "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}}"#;
+        let endpoint = crate::fixed_upstream("application/json", body).await;
+        let client = reqwest::Client::new();
+        let payload = serde_json::json!({ "messages": [] });
+        let completion = complete_streamed(&client, &endpoint, "t", &payload, None, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(completion.content, "Hello");
+        assert_eq!(completion.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(completion.usage.map(|usage| usage.total_tokens), Some(4));
+    }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+// Token usage as reported by the serving endpoint.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    // Some endpoints leave total_tokens out, so fall back to the sum
+    pub fn tokens(&self) -> u64 {
+        if self.total_tokens > 0 {
+            self.total_tokens
+        } else {
+            self.prompt_tokens + self.completion_tokens
+        }
+    }
+}
+
+impl std::ops::Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+            total_tokens: self.tokens() + other.tokens(),
+        }
+    }
+}
+
+// Per-1K-token prices used to estimate the dollar cost of a request.
+#[derive(Debug, Default)]
+pub struct Pricing {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+impl Pricing {
+    pub fn from_env() -> Self {
+        let price = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .map(|v| v.parse::<f64>().unwrap_or_else(|_| panic!("{} must be a number", key)))
+                .unwrap_or(0.0)
+        };
+        Self {
+            prompt_per_1k: price("PRICE_PER_1K_PROMPT"),
+            completion_per_1k: price("PRICE_PER_1K_COMPLETION"),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.prompt_per_1k > 0.0 || self.completion_per_1k > 0.0
+    }
+
+    pub fn cost_usd(&self, usage: &Usage) -> f64 {
+        usage.prompt_tokens as f64 / 1000.0 * self.prompt_per_1k
+            + usage.completion_tokens as f64 / 1000.0 * self.completion_per_1k
+    }
+}
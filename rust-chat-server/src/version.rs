@@ -0,0 +1,40 @@
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::AppState;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set by build.rs
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+// Value of the X-Server-Version header on every response
+pub fn header_value() -> String {
+    format!("{} ({})", VERSION, GIT_COMMIT)
+}
+
+pub fn info() -> serde_json::Value {
+    serde_json::json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_timestamp": BUILD_TIMESTAMP,
+    })
+}
+
+#[get("/api/version")]
+async fn version() -> impl Responder {
+    HttpResponse::Ok().json(info())
+}
+
+// Still a liveness check: the server answers 200 even when every
+// endpoint is down, and `status` says whether chat can be served.
+#[get("/health")]
+async fn health(app_state: web::Data<AppState>, client: web::Data<reqwest::Client>) -> impl Responder {
+    let endpoints = app_state.endpoint_health.statuses(&app_state, &client).await;
+    let up = endpoints.iter().any(|endpoint| endpoint.healthy);
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": if up { "ok" } else { "unhealthy" },
+        "build": info(),
+        "ui_available": app_state.static_dir.is_some(),
+        "endpoints": endpoints,
+    }))
+}
@@ -0,0 +1,137 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::time::Instant;
+
+use crate::admin;
+use crate::audit::AuditEntry;
+use crate::chat::{self, ChatRequest};
+use crate::client_ip;
+use crate::request_id::RequestId;
+use crate::upstream;
+use crate::AppState;
+
+// WARMUP_PROMPTS_PATH names a file of common prompts, one per line (blank
+// lines and `#` comments are skipped), that POST /admin/warmup sends through
+// /api/chat so their replies are in the response cache before real traffic.
+pub fn prompts_from_env() -> Vec<String> {
+    let Some(path) = std::env::var("WARMUP_PROMPTS_PATH").ok().filter(|p| !p.is_empty()) else {
+        return Vec::new();
+    };
+    let prompts: Vec<String> = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Can't read WARMUP_PROMPTS_PATH {}: {}", path, e))
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    log::info!("Loaded {} warmup prompts from {}", prompts.len(), path);
+    prompts
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct WarmupRequest {
+    // Connections to open; WARM_CONNECTIONS by default
+    connections: Option<usize>,
+    // Run the startup self-test too (default off)
+    #[serde(default)]
+    self_test: bool,
+    // Prime the response cache with the warmup prompts (default on)
+    prompts: Option<bool>,
+}
+
+// For deploys: warms the instance before it is put in rotation. Answers 502
+// when the self-test was asked for and failed, so a deploy can stop there.
+#[post("/admin/warmup")]
+async fn warmup(
+    req: HttpRequest,
+    body: Option<web::Json<WarmupRequest>>,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = admin::authorize(&req, &app_state) {
+        return Ok(response);
+    }
+    let options = body.map(web::Json::into_inner).unwrap_or_default();
+    let started = Instant::now();
+    let endpoint = match &app_state.regions {
+        Some(regions) => regions.best().clone(),
+        None => app_state.primary_endpoint(),
+    };
+
+    let requested = options.connections.unwrap_or(app_state.warm_connections);
+    let warmed = match requested {
+        0 => 0,
+        count => upstream::warm_pool(&client, &endpoint, &app_state.api_key, count).await,
+    };
+
+    let mut self_test_failed = false;
+    let self_test = if options.self_test {
+        let test_started = Instant::now();
+        let result = upstream::self_test(&client, &endpoint, &app_state.api_key).await;
+        if let Err(e) = &result {
+            log::warn!("Warmup self-test against {} failed: {}", endpoint.url, e);
+            self_test_failed = true;
+        }
+        serde_json::json!({
+            "ok": result.is_ok(),
+            "error": result.err().map(|e| e.to_string()),
+            "elapsed_ms": test_started.elapsed().as_millis() as u64,
+        })
+    } else {
+        serde_json::Value::Null
+    };
+
+    // Sent one at a time at low priority, through the whole /api/chat
+    // pipeline so the cache keys match real requests
+    let prompts = if options.prompts == Some(false) || app_state.warmup_prompts.is_empty() {
+        serde_json::Value::Null
+    } else if app_state.response_cache.is_none() {
+        serde_json::json!({ "skipped": "response cache is disabled" })
+    } else {
+        let (mut cached, mut already_cached, mut failed) = (0, 0, Vec::new());
+        for (index, prompt) in app_state.warmup_prompts.iter().enumerate() {
+            let chat_request: ChatRequest =
+                serde_json::from_value(serde_json::json!({ "message": prompt, "priority": "low" }))
+                    .expect("a message and a priority make a valid chat request");
+            let warmup_id = RequestId(format!("{}-{}", request_id.0, index));
+            let response = chat::complete_chat(req.clone(), chat_request, warmup_id, client.clone(), app_state.clone()).await;
+            match response {
+                Ok(response) if response.headers().contains_key("X-Cache") => already_cached += 1,
+                Ok(response) if response.status().is_success() => cached += 1,
+                Ok(response) => failed.push(serde_json::json!({ "prompt": index, "status": response.status().as_u16() })),
+                Err(e) => failed.push(serde_json::json!({
+                    "prompt": index,
+                    "status": e.as_response_error().status_code().as_u16(),
+                })),
+            }
+        }
+        serde_json::json!({
+            "configured": app_state.warmup_prompts.len(),
+            "cached": cached,
+            "already_cached": already_cached,
+            "failed": failed,
+        })
+    };
+
+    let summary = serde_json::json!({
+        "endpoint": endpoint.name,
+        "connections": { "requested": requested, "warmed": warmed },
+        "self_test": self_test,
+        "prompts": prompts,
+        "elapsed_ms": started.elapsed().as_millis() as u64,
+    });
+    log::info!("Warmup finished in {} ms", started.elapsed().as_millis());
+    app_state.audit(AuditEntry {
+        event: "instance.warmed",
+        request_id: Some(request_id.0.clone()),
+        subject: None,
+        client_ip: client_ip::client_ip(&app_state, &req),
+        details: summary.clone(),
+    });
+    Ok(if self_test_failed {
+        HttpResponse::BadGateway().json(summary)
+    } else {
+        HttpResponse::Ok().json(summary)
+    })
+}
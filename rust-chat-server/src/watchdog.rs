@@ -0,0 +1,50 @@
+use actix_web::web;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::AppState;
+
+// Each actix worker runs its requests on one thread, so a CPU-bound handler
+// (a large JSON parse, say) delays everything else on that worker. One
+// watchdog task per worker sleeps for CHECK_INTERVAL and measures how late it
+// wakes up. Delays over EVENT_LOOP_STALL_MS (default 100, 0 disables) are
+// logged and counted; the worst delay seen is exported either way.
+pub struct StallWatchdog {
+    threshold: Duration,
+}
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+impl StallWatchdog {
+    pub fn from_env() -> Option<Self> {
+        let threshold_ms: u64 = std::env::var("EVENT_LOOP_STALL_MS")
+            .ok()
+            .map(|v| v.parse().expect("EVENT_LOOP_STALL_MS must be a number"))
+            .unwrap_or(100);
+        (threshold_ms > 0).then(|| Self {
+            threshold: Duration::from_millis(threshold_ms),
+        })
+    }
+}
+
+// Runs on the current worker until it shuts down.
+pub async fn run(app_state: web::Data<AppState>) {
+    let Some(watchdog) = &app_state.stall_watchdog else {
+        return;
+    };
+    let worker = std::thread::current().name().unwrap_or("worker").to_string();
+    loop {
+        let due = Instant::now() + CHECK_INTERVAL;
+        tokio::time::sleep_until(due).await;
+        let delay = Instant::now().saturating_duration_since(due);
+        app_state.metrics.event_loop_stall_max.set_max(delay.as_secs_f64());
+        if delay > watchdog.threshold {
+            app_state.metrics.event_loop_stalls.inc(&[]);
+            log::warn!(
+                "Event loop on {} stalled for {} ms; look for blocking work that belongs in spawn_blocking",
+                worker,
+                delay.as_millis()
+            );
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Posts a JSON event to COMPLETION_WEBHOOK_URL after each successful chat.
+// Delivery happens in the background and never affects the response.
+#[derive(Debug)]
+pub struct CompletionWebhook {
+    url: String,
+}
+
+impl CompletionWebhook {
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("COMPLETION_WEBHOOK_URL").ok().filter(|u| !u.is_empty())?;
+        log::info!("Completion webhook enabled: {}", url);
+        Some(Self { url })
+    }
+
+    pub fn notify(&self, client: &reqwest::Client, event: serde_json::Value) {
+        let client = client.clone();
+        let url = self.url.clone();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            for attempt in 1..=MAX_ATTEMPTS {
+                let result = client
+                    .post(&url)
+                    .timeout(DELIVERY_TIMEOUT)
+                    .json(&event)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match result {
+                    Ok(_) => {
+                        log::debug!("Delivered completion webhook on attempt {}", attempt);
+                        return;
+                    }
+                    Err(e) if attempt < MAX_ATTEMPTS => {
+                        log::warn!("Completion webhook attempt {} failed: {}", attempt, e);
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "Dropping completion webhook after {} attempts: {}",
+                            MAX_ATTEMPTS,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+    }
+}
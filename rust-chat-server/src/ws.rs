@@ -0,0 +1,395 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, Codec, Frame, Message};
+use actix_web::body::{BodyStream, MessageBody};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::cancel::Cancellations;
+use crate::chat::{self, ChatRequest};
+use crate::client_ip;
+use crate::request_id::RequestId;
+use crate::stream::{self, Event, Generation};
+use crate::trace::TraceHeaders;
+use crate::turns::ConversationTurns;
+use crate::AppState;
+
+// GET /api/chat/ws[?room=name] upgrades to a WebSocket. Each text frame
+// from a client is a ChatRequest; the reply is streamed to every member of
+// the room as JSON text frames:
+//
+//   {"type": "prompt", "message": "...", "from": n}   a member asked
+//   {"type": "generation", "request_id": "...",      to the sender only; the
+//    "cancel_token": "..."}                          token for /cancel
+//   {"type": "status", "value": "thinking"}          upstream call started
+//   {"type": "status", "value": "streaming"}         just before the first delta
+//   {"type": "delta", "delta": "..."}
+//   {"type": "done", ...}                            as the SSE done event
+//   {"type": "error", "error": "..."}
+//
+// A reply that fails before any token skips "streaming". Every reply ends
+// with exactly one `done` or `error`.
+//
+// Any member may prompt, one generation per room at a time. Without
+// `room` the connection gets a private room. Rooms are removed when their
+// last member leaves. With MAX_WS_CONNECTIONS set, upgrades beyond that
+// many open sockets are refused with 503.
+
+// Frames queued per member; a viewer that falls this far behind is dropped
+const MEMBER_BUFFER: usize = 256;
+
+#[derive(Default)]
+pub struct Rooms {
+    next_member: AtomicU64,
+    rooms: Mutex<HashMap<String, Room>>,
+    connections: AtomicUsize,
+    max_connections: Option<usize>,
+}
+
+#[derive(Default)]
+struct Room {
+    members: HashMap<u64, mpsc::Sender<Bytes>>,
+    generating: bool,
+}
+
+impl Rooms {
+    pub fn from_env() -> Self {
+        let max_connections = std::env::var("MAX_WS_CONNECTIONS").ok().map(|v| match v.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => panic!("MAX_WS_CONNECTIONS must be a positive integer"),
+        });
+        Self {
+            max_connections,
+            ..Self::default()
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    // Counts a new socket; false when MAX_WS_CONNECTIONS are already open.
+    fn open_connection(&self) -> bool {
+        let max = self.max_connections.unwrap_or(usize::MAX);
+        self.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+            .is_ok()
+    }
+
+    fn close_connection(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Without a room name the member gets a private room, named so no
+    // client can ask for it.
+    fn join(&self, room: Option<String>, tx: mpsc::Sender<Bytes>) -> (String, u64) {
+        let member = self.next_member.fetch_add(1, Ordering::Relaxed);
+        let room = room.unwrap_or_else(|| format!("#{}", member));
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.entry(room.clone()).or_default().members.insert(member, tx);
+        (room, member)
+    }
+
+    fn leave(&self, room: &str, member: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(entry) = rooms.get_mut(room) {
+            entry.members.remove(&member);
+            if entry.members.is_empty() {
+                rooms.remove(room);
+                log::debug!("Room {} is empty, removed", room);
+            }
+        }
+    }
+
+    // Marks the room busy; false if a generation is already running.
+    fn start_generation(&self, room: &str) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        match rooms.get_mut(room) {
+            Some(entry) if !entry.generating => {
+                entry.generating = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn finish_generation(&self, room: &str) {
+        if let Some(entry) = self.rooms.lock().unwrap().get_mut(room) {
+            entry.generating = false;
+        }
+    }
+
+    // Sends to every member, dropping those whose queue is full. Returns
+    // false once the room has nobody left to send to.
+    fn broadcast(&self, room: &str, data: &serde_json::Value) -> bool {
+        let frame = text_frame(data);
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(entry) = rooms.get_mut(room) else {
+            return false;
+        };
+        entry.members.retain(|member, tx| match tx.try_send(frame.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                log::warn!("Dropping member {} of room {}: too far behind", member, room);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+        !entry.members.is_empty()
+    }
+
+    fn send_to(&self, room: &str, member: u64, data: &serde_json::Value) {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(tx) = rooms.get(room).and_then(|entry| entry.members.get(&member)) {
+            let _ = tx.try_send(text_frame(data));
+        }
+    }
+}
+
+fn encode(message: Message) -> Bytes {
+    let mut buf = BytesMut::new();
+    // Server-side encoding of a complete message can't fail
+    let _ = Codec::new().encode(message, &mut buf);
+    buf.freeze()
+}
+
+fn text_frame(data: &serde_json::Value) -> Bytes {
+    encode(Message::Text(data.to_string().into()))
+}
+
+#[derive(Debug, Deserialize)]
+struct WsQuery {
+    room: Option<String>,
+}
+
+fn valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= 64
+        && room.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[get("/api/chat/ws")]
+async fn chat_ws(
+    http_req: HttpRequest,
+    query: web::Query<WsQuery>,
+    payload: web::Payload,
+    request_id: web::ReqData<RequestId>,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Some(room) = query.room.as_deref().filter(|room| !valid_room(room)) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("invalid room {:?}: use 1-64 letters, digits, '-' or '_'", room)
+        })));
+    }
+    let mut handshake = ws::handshake(http_req.head())?;
+    if !app_state.rooms.open_connection() {
+        log::warn!("Refusing WebSocket upgrade: {} connections open", app_state.rooms.connections());
+        return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "error": "too many WebSocket connections; try again later"
+        })));
+    }
+
+    let (tx, rx) = mpsc::channel::<Bytes>(MEMBER_BUFFER);
+    let (room, member) = app_state.rooms.join(query.into_inner().room, tx.clone());
+    log::info!("WebSocket member {} joined room {}", member, room);
+
+    actix_web::rt::spawn(read_frames(
+        payload,
+        tx,
+        Connection {
+            http_req,
+            request_id: request_id.0.clone(),
+            room,
+            member,
+        },
+        client,
+        app_state,
+    ));
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|frame| (Ok::<_, actix_web::Error>(frame), rx))
+    });
+    let response = handshake.message_body(BodyStream::new(body).boxed())?;
+    Ok(HttpResponse::from(response))
+}
+
+struct Connection {
+    http_req: HttpRequest,
+    request_id: String,
+    room: String,
+    member: u64,
+}
+
+// Decodes client frames until the client closes or disconnects, then
+// leaves the room.
+async fn read_frames(
+    mut payload: web::Payload,
+    tx: mpsc::Sender<Bytes>,
+    conn: Connection,
+    client: web::Data<reqwest::Client>,
+    app_state: web::Data<AppState>,
+) {
+    let mut codec = Codec::new();
+    let mut buf = BytesMut::new();
+    let mut prompts = 0;
+    'read: loop {
+        // The request payload doesn't always end when the client vanishes,
+        // but the response body is dropped, closing the frame channel
+        let chunk = tokio::select! {
+            chunk = payload.next() => chunk,
+            _ = tx.closed() => None,
+        };
+        let Some(Ok(chunk)) = chunk else {
+            break;
+        };
+        buf.extend_from_slice(&chunk);
+        loop {
+            let frame = match codec.decode(&mut buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("Bad WebSocket frame from member {}: {}", conn.member, e);
+                    break 'read;
+                }
+            };
+            match frame {
+                Frame::Text(text) => {
+                    prompts += 1;
+                    let request_id = format!("{}-{}", conn.request_id, prompts);
+                    prompt(&text, &conn, RequestId(request_id), &client, &app_state).await;
+                }
+                Frame::Ping(data) => {
+                    let _ = tx.try_send(encode(Message::Pong(data)));
+                }
+                Frame::Close(reason) => {
+                    let _ = tx.try_send(encode(Message::Close(reason)));
+                    break 'read;
+                }
+                Frame::Binary(_) | Frame::Continuation(_) | Frame::Pong(_) => {}
+            }
+        }
+    }
+    app_state.rooms.leave(&conn.room, conn.member);
+    app_state.rooms.close_connection();
+    log::info!("WebSocket member {} left room {}", conn.member, conn.room);
+}
+
+// Starts a generation in the member's room. Problems are reported to the
+// sender only.
+async fn prompt(
+    text: &[u8],
+    conn: &Connection,
+    request_id: RequestId,
+    client: &reqwest::Client,
+    app_state: &web::Data<AppState>,
+) {
+    let started = Instant::now();
+    let rooms = &app_state.rooms;
+    let reject = |error: String| {
+        rooms.send_to(&conn.room, conn.member, &serde_json::json!({ "type": "error", "error": error }));
+    };
+    let req: ChatRequest = match serde_json::from_slice(text) {
+        Ok(req) => req,
+        Err(e) => return reject(format!("invalid chat request: {}", e)),
+    };
+    let turn = match ConversationTurns::begin(app_state, req.conversation_id.as_deref()).await {
+        Ok(turn) => turn,
+        Err(response) => return reject(error_message(response).await),
+    };
+    let prepared = match chat::prepare_chat(req, app_state) {
+        Ok(prepared) => prepared,
+        Err(response) => return reject(error_message(response).await),
+    };
+    if prepared.req.schema.is_some() {
+        return reject("schema is only supported on /api/chat".to_string());
+    }
+    if !rooms.start_generation(&conn.room) {
+        return reject("a reply is already being generated in this room".to_string());
+    }
+
+    let payload = prepared.payload();
+    let (selected, route) = chat::select_endpoint(app_state, &prepared);
+    let endpoint = &*selected;
+    let client_ip = client_ip::client_ip(app_state, &conn.http_req);
+    chat::audit_request(app_state, &request_id, client_ip, endpoint, &prepared);
+    log::info!("Streaming to room {} from {} ({})", conn.room, endpoint.url, route);
+    rooms.broadcast(
+        &conn.room,
+        &serde_json::json!({ "type": "prompt", "message": prepared.req.message, "from": conn.member }),
+    );
+
+    rooms.broadcast(&conn.room, &status("thinking"));
+    let trace = TraceHeaders::for_request(&conn.http_req, &request_id, app_state.trace_context, &app_state.forward_headers);
+    let cancel = Cancellations::register(app_state, &request_id.0);
+    // Only the sender may stop its generation
+    rooms.send_to(
+        &conn.room,
+        conn.member,
+        &serde_json::json!({ "type": "generation", "request_id": request_id.0, "cancel_token": cancel.token }),
+    );
+    let opened = chat::open_llm_stream(app_state, client, endpoint, &payload, &trace, prepared.req.priority).await;
+    let (upstream_response, permit) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            chat::count_upstream_error(app_state, &endpoint.name, &e);
+            log::error!("Failed to open stream for room {}: {}", conn.room, e);
+            rooms.broadcast(&conn.room, &Event::Error("Failed to reach the LLM endpoint").to_json());
+            rooms.finish_generation(&conn.room);
+            return;
+        }
+    };
+
+    let (events_tx, mut events_rx) = mpsc::channel::<Event>(32);
+    let generation = Generation {
+        request_id: request_id.0,
+        endpoint_name: endpoint.name.clone(),
+        route,
+        client_ip,
+        started,
+        cancel,
+        permit,
+    };
+    let pumped = stream::pump(upstream_response, events_tx, app_state.clone(), prepared.req, generation);
+    actix_web::rt::spawn(async move {
+        pumped.await;
+        drop(turn);
+    });
+    let app_state = app_state.clone();
+    let room = conn.room.clone();
+    actix_web::rt::spawn(async move {
+        let mut streaming = false;
+        while let Some(event) = events_rx.recv().await {
+            if matches!(event, Event::Delta(_)) && !streaming {
+                streaming = true;
+                app_state.rooms.broadcast(&room, &status("streaming"));
+            }
+            // Dropping the receiver when everyone has left stops the pump
+            if !app_state.rooms.broadcast(&room, &event.to_json()) {
+                break;
+            }
+        }
+        app_state.rooms.finish_generation(&room);
+    });
+}
+
+fn status(value: &str) -> serde_json::Value {
+    serde_json::json!({ "type": "status", "value": value })
+}
+
+// The `error` field of a JSON error response from prepare_chat.
+async fn error_message(response: HttpResponse) -> String {
+    let status = response.status();
+    actix_web::body::to_bytes(response.into_body())
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok())
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or_else(|| format!("request rejected with status {}", status.as_u16()))
+}